@@ -0,0 +1,25 @@
+" Light text on a dark background
+highlight clear
+highlight Normal       guifg=#d0d0d0 guibg=#1c1c1c
+highlight Comment      guifg=#808080 gui=italic
+highlight Constant     guifg=#d7875f
+highlight String       guifg=#afd787
+highlight Number       guifg=#d7875f
+highlight Identifier   guifg=#87d7d7
+highlight Keyword      guifg=#87afd7 gui=bold
+highlight Type         guifg=#d7d787
+highlight PreProc      guifg=#d787d7
+highlight Special      guifg=#ffaf5f
+highlight Title        guifg=#ffd75f gui=bold
+highlight Visual       guibg=#3a3a5f
+highlight Search       guifg=#1c1c1c guibg=#ffd75f
+highlight MatchParen   guifg=#ffffff guibg=#5f8787 gui=bold
+highlight NonText      guifg=#4e4e4e
+highlight SpecialKey   guifg=#4e4e4e
+highlight LineNr       guifg=#626262
+highlight CursorLineNr guifg=#ffd75f gui=bold
+highlight CursorLine   guibg=#262626 gui=NONE
+highlight ColorColumn  guibg=#303030
+highlight SignColumn   guifg=#ffaf5f guibg=#1c1c1c
+highlight FoldColumn   guifg=#626262 guibg=#1c1c1c
+highlight StatusLine   guifg=#1c1c1c guibg=#a8a8a8 gui=bold
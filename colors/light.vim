@@ -0,0 +1,25 @@
+" Dark text on a light background
+highlight clear
+highlight Normal       guifg=#303030 guibg=#fafafa
+highlight Comment      guifg=#8a8a8a gui=italic
+highlight Constant     guifg=#af5f00
+highlight String       guifg=#5f8700
+highlight Number       guifg=#af5f00
+highlight Identifier   guifg=#005f87
+highlight Keyword      guifg=#0000af gui=bold
+highlight Type         guifg=#875f00
+highlight PreProc      guifg=#870087
+highlight Special      guifg=#d75f00
+highlight Title        guifg=#af0000 gui=bold
+highlight Visual       guibg=#d7d7ff
+highlight Search       guifg=#303030 guibg=#ffff87
+highlight MatchParen   guibg=#afd7d7 gui=bold
+highlight NonText      guifg=#bcbcbc
+highlight SpecialKey   guifg=#bcbcbc
+highlight LineNr       guifg=#a8a8a8
+highlight CursorLineNr guifg=#af5f00 gui=bold
+highlight CursorLine   guibg=#eeeeee gui=NONE
+highlight ColorColumn  guibg=#e4e4e4
+highlight SignColumn   guifg=#d75f00 guibg=#fafafa
+highlight FoldColumn   guifg=#a8a8a8 guibg=#fafafa
+highlight StatusLine   guifg=#fafafa guibg=#585858 gui=bold
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use crate::pos::CharIdx;
+
+/// Which way an anchor goes when text is inserted right where it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gravity {
+    Left,   // Stays before the new text
+    Right,  // Moves after it, staying with the char that follows
+}
+
+impl Gravity {
+    /// Where char index `pos` goes when `removed` chars at `idx` are replaced
+    /// by `inserted` chars: positions in the removed text collapse to `idx`,
+    /// and the inserted text goes on the side given by the gravity.
+    pub fn adjust(self, pos : usize, idx : usize, removed : usize, inserted : usize) -> usize {
+        if pos < idx {
+            pos
+        }
+        else if pos > idx + removed {
+            pos - removed + inserted
+        }
+        else {
+            match self {
+                Gravity::Left => idx,
+                Gravity::Right => idx + inserted,
+            }
+        }
+    }
+}
+
+/// Handle to a position kept by `Anchors`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Anchor(usize);
+
+/// Positions between chars that move along with the text around them.
+#[derive(Default)]
+pub struct Anchors {
+    positions : HashMap<Anchor, (CharIdx, Gravity)>,
+    next : usize,   // Handles are never reused, so stale ones find nothing
+}
+
+impl Anchors {
+    pub fn add(&mut self, idx : CharIdx, gravity : Gravity) -> Anchor {
+        let anchor = Anchor(self.next);
+        self.next += 1;
+        self.positions.insert(anchor, (idx, gravity));
+        anchor
+    }
+
+    pub fn get(&self, anchor : Anchor) -> Option<CharIdx> {
+        self.positions.get(&anchor).map(|(idx, _)| *idx)
+    }
+
+    pub fn remove(&mut self, anchor : Anchor) -> Option<CharIdx> {
+        self.positions.remove(&anchor).map(|(idx, _)| idx)
+    }
+
+    /// Follow an edit replacing `removed` chars at `idx` with `inserted` chars.
+    pub fn adjust(&mut self, idx : usize, removed : usize, inserted : usize) {
+        for (pos, gravity) in self.positions.values_mut() {
+            pos.0 = gravity.adjust(pos.0, idx, removed, inserted);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Small xorshift generator for repeatable random edits.
+    pub struct Rng(pub u64);
+
+    impl Rng {
+        pub fn below(&mut self, n : usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n.max(1) as u64) as usize
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Item {
+        Char,
+        Anchor(usize, Gravity),
+    }
+
+    /// Text as a sequence of chars with anchors placed between them, the
+    /// obvious way to get anchor positions right.
+    #[derive(Default)]
+    pub struct Model {
+        items : Vec<Item>,
+    }
+
+    impl Model {
+        pub fn new(chars : usize) -> Model {
+            Model { items : vec![Item::Char; chars] }
+        }
+
+        pub fn len(&self) -> usize {
+            self.items.iter().filter(|i| **i == Item::Char).count()
+        }
+
+        // Item index of the boundary before char `idx`, before any anchors
+        fn boundary(&self, idx : usize) -> usize {
+            let mut chars = 0;
+            for (n, item) in self.items.iter().enumerate() {
+                if chars == idx {
+                    return n;
+                }
+                if *item == Item::Char {
+                    chars += 1;
+                }
+            }
+            self.items.len()
+        }
+
+        pub fn add(&mut self, id : usize, idx : usize, gravity : Gravity) {
+            let at = self.boundary(idx);
+            self.items.insert(at, Item::Anchor(id, gravity));
+        }
+
+        pub fn get(&self, id : usize) -> Option<CharIdx> {
+            let at = self.items.iter().position(|i| matches!(i, Item::Anchor(a, _) if *a == id))?;
+            Some(CharIdx(self.items[..at].iter().filter(|i| **i == Item::Char).count()))
+        }
+
+        pub fn edit(&mut self, idx : usize, removed : usize, inserted : usize) {
+            let start = self.boundary(idx);
+            let mut end = start;
+            let mut left = removed;
+            while left > 0 {
+                if self.items[end] == Item::Char {
+                    left -= 1;
+                }
+                end += 1;
+            }
+            // Anchors in and around the edit end up together: those with left
+            // gravity before the new chars, the others after them
+            while end < self.items.len() && self.items[end] != Item::Char {
+                end += 1;
+            }
+            let anchors : Vec<Item> = self.items[start..end].iter().copied().filter(|i| *i != Item::Char).collect();
+            let lefts = anchors.iter().filter(|i| matches!(i, Item::Anchor(_, Gravity::Left)));
+            let rights = anchors.iter().filter(|i| matches!(i, Item::Anchor(_, Gravity::Right)));
+            let new : Vec<Item> = lefts.copied()
+                .chain(std::iter::repeat_n(Item::Char, inserted))
+                .chain(rights.copied())
+                .collect();
+            self.items.splice(start..end, new);
+        }
+    }
+
+    #[test]
+    fn test_gravity() {
+        // Insert at the anchor
+        assert_eq!(Gravity::Left.adjust(3, 3, 0, 2), 3);
+        assert_eq!(Gravity::Right.adjust(3, 3, 0, 2), 5);
+        // Delete around it
+        assert_eq!(Gravity::Left.adjust(4, 3, 2, 0), 3);
+        assert_eq!(Gravity::Right.adjust(5, 3, 2, 0), 3);
+        // Replace the text it is in
+        assert_eq!(Gravity::Left.adjust(4, 3, 2, 3), 3);
+        assert_eq!(Gravity::Right.adjust(4, 3, 2, 3), 6);
+        // Edits before and after it
+        assert_eq!(Gravity::Left.adjust(9, 3, 2, 1), 8);
+        assert_eq!(Gravity::Right.adjust(2, 3, 2, 1), 2);
+    }
+
+    #[test]
+    fn test_add_get_remove() {
+        let mut anchors = Anchors::default();
+        let a = anchors.add(CharIdx(2), Gravity::Left);
+        let b = anchors.add(CharIdx(2), Gravity::Right);
+        anchors.adjust(2, 0, 1);
+        assert_eq!((anchors.get(a), anchors.get(b)), (Some(CharIdx(2)), Some(CharIdx(3))));
+        assert_eq!(anchors.remove(a), Some(CharIdx(2)));
+        assert_eq!(anchors.get(a), None);
+        let c = anchors.add(CharIdx(0), Gravity::Left);
+        assert_ne!(a, c);
+        assert_eq!(anchors.get(a), None);
+    }
+
+    #[test]
+    fn test_matches_model() {
+        for seed in 1..=50 {
+            let mut rng = Rng(seed);
+            let mut anchors = Anchors::default();
+            let mut model = Model::new(20);
+            let mut handles = Vec::new();
+            for step in 0..200 {
+                let len = model.len();
+                if step % 10 == 0 {
+                    let idx = rng.below(len + 1);
+                    let gravity = if rng.below(2) == 0 { Gravity::Left } else { Gravity::Right };
+                    model.add(handles.len(), idx, gravity);
+                    handles.push(anchors.add(CharIdx(idx), gravity));
+                    continue;
+                }
+                let idx = rng.below(len + 1);
+                let removed = rng.below((len - idx).min(5) + 1);
+                let inserted = rng.below(5);
+                anchors.adjust(idx, removed, inserted);
+                model.edit(idx, removed, inserted);
+                for (id, anchor) in handles.iter().enumerate() {
+                    assert_eq!(anchors.get(*anchor), model.get(id),
+                        "seed {} step {}: anchor {} after edit at {} -{} +{}", seed, step, id, idx, removed, inserted);
+                }
+            }
+        }
+    }
+}
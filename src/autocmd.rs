@@ -0,0 +1,136 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    BufReadPost,
+    BufWritePre,
+    BufWritePost,
+    FileType,
+    FocusGained,
+    FocusLost,
+    InsertEnter,
+    InsertLeave,
+    VimEnter,
+    VimLeavePre,
+}
+
+const EVENT_NAMES : &[(&str, Event)] = &[
+    ("bufread", Event::BufReadPost),
+    ("bufreadpost", Event::BufReadPost),
+    ("bufwrite", Event::BufWritePre),
+    ("bufwritepre", Event::BufWritePre),
+    ("bufwritepost", Event::BufWritePost),
+    ("filetype", Event::FileType),
+    ("focusgained", Event::FocusGained),
+    ("focuslost", Event::FocusLost),
+    ("insertenter", Event::InsertEnter),
+    ("insertleave", Event::InsertLeave),
+    ("vimenter", Event::VimEnter),
+    ("vimleavepre", Event::VimLeavePre),
+];
+
+impl Event {
+    pub fn from_name(name : &str) -> Option<Event> {
+        let lower = name.to_ascii_lowercase();
+        EVENT_NAMES.iter().find(|(n, _)| *n == lower).map(|(_, e)| *e)
+    }
+}
+
+pub struct Autocmd {
+    pub event : Event,
+    pub pattern : String,
+    pub command : String,
+}
+
+#[derive(Default)]
+pub struct Autocmds {
+    list : Vec<Autocmd>,
+}
+
+impl Autocmds {
+    pub fn add(&mut self, event : Event, pattern : &str, command : &str) {
+        self.list.push(Autocmd {
+            event,
+            pattern : String::from(pattern),
+            command : String::from(command),
+        });
+    }
+
+    /// Remove autocommands, optionally limited to one event and pattern.
+    pub fn remove(&mut self, event : Option<Event>, pattern : Option<&str>) {
+        self.list.retain(|a| {
+            !(event.is_none_or(|e| e == a.event) && pattern.is_none_or(|p| p == a.pattern))
+        });
+    }
+
+    /// Commands to run for `event` on the file `name`, in definition order.
+    pub fn matching(&self, event : Event, name : &str) -> Vec<String> {
+        self.list.iter()
+            .filter(|a| a.event == event && pattern_matches(&a.pattern, name))
+            .map(|a| a.command.clone())
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Autocmd> {
+        self.list.iter()
+    }
+}
+
+/// Match a file name against an autocommand pattern. Patterns are a comma
+/// separated list of globs using `*` and `?`. A pattern without a slash is
+/// matched against the last path component only.
+pub fn pattern_matches(pattern : &str, name : &str) -> bool {
+    pattern.split(',').any(|p| {
+        let subject = if p.contains('/') {
+            name
+        } else {
+            name.rsplit('/').next().unwrap_or(name)
+        };
+        glob(p.as_bytes(), subject.as_bytes())
+    })
+}
+
+fn glob(p : &[u8], s : &[u8]) -> bool {
+    match (p.first(), s.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => glob(&p[1..], s) || (!s.is_empty() && glob(p, &s[1..])),
+        (Some(b'?'), Some(_)) => glob(&p[1..], &s[1..]),
+        (Some(a), Some(b)) if a == b => glob(&p[1..], &s[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_names() {
+        assert_eq!(Event::from_name("BufRead"), Some(Event::BufReadPost));
+        assert_eq!(Event::from_name("insertleave"), Some(Event::InsertLeave));
+        assert_eq!(Event::from_name("FileType"), Some(Event::FileType));
+        assert_eq!(Event::from_name("Nope"), None);
+    }
+
+    #[test]
+    fn test_pattern_matches() {
+        assert!(pattern_matches("*", "main.rs"));
+        assert!(pattern_matches("*.rs", "src/main.rs"));
+        assert!(pattern_matches("*.c,*.h", "x.h"));
+        assert!(pattern_matches("src/*.rs", "src/main.rs"));
+        assert!(pattern_matches("?akefile", "Makefile"));
+        assert!(!pattern_matches("*.rs", "main.rs.bak"));
+        assert!(!pattern_matches("src/*.rs", "main.rs"));
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let mut a = Autocmds::default();
+        a.add(Event::BufReadPost, "*.rs", "set ro");
+        a.add(Event::BufReadPost, "*.c", "set ut=100");
+        a.add(Event::InsertEnter, "*", "set noro");
+        assert_eq!(a.matching(Event::BufReadPost, "a.rs"), vec!["set ro"]);
+        a.remove(Some(Event::BufReadPost), Some("*.rs"));
+        assert!(a.matching(Event::BufReadPost, "a.rs").is_empty());
+        a.remove(None, None);
+        assert!(a.matching(Event::InsertEnter, "a.rs").is_empty());
+    }
+}
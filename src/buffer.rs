@@ -0,0 +1,70 @@
+use std::fs;
+use std::time::SystemTime;
+
+use crate::keymap::Keymap;
+use crate::options::{LocalOptions, Options};
+use crate::syntax::Highlighter;
+use crate::text::Text;
+
+pub struct Buffer {
+    pub text : Text,
+    pub name : Option<String>,
+    pub local : LocalOptions,   // Buffer-local option values
+    pub maps : Keymap,          // Buffer-local key mappings
+    pub highlight : Highlighter,
+    saved_tick : usize,         // Text::changedtick at the last write
+    saved_format : String,      // 'fileformat' at the last write
+    disk_time : Option<SystemTime>, // When the file was last changed, as far as we know
+}
+
+impl Buffer {
+    pub fn new(contents : &str, name : Option<&str>, options : &Options) -> Buffer {
+        let mut text = Text::new(contents);
+        let saved_tick = text.changedtick();
+        let local = options.new_local();
+        text.set_modifiable(options.get_bool(&local, "modifiable"));
+        let saved_format = String::from(local.get_str("fileformat"));
+        Buffer {
+            text,
+            name : name.map(String::from),
+            local,
+            maps : Keymap::default(),
+            highlight : Highlighter::default(),
+            saved_tick,
+            saved_format,
+            disk_time : None,
+        }
+    }
+
+    // Modification time of the buffer's file on disk
+    fn file_time(&self) -> Option<SystemTime> {
+        fs::metadata(self.name.as_ref()?).ok()?.modified().ok()
+    }
+
+    /// Take the file as it is on disk now as the one the buffer was read
+    /// from or written to.
+    pub fn mark_disk_time(&mut self) {
+        self.disk_time = self.file_time();
+    }
+
+    /// Whether the file changed on disk since it was last read or written,
+    /// or was removed. Each change is only reported once.
+    pub fn changed_on_disk(&mut self) -> bool {
+        let time = self.file_time();
+        if self.disk_time.is_none() || time == self.disk_time {
+            return false;
+        }
+        self.disk_time = time;
+        true
+    }
+
+    /// Whether the text or its line endings changed since the last write.
+    pub fn modified(&self) -> bool {
+        self.text.changedtick() != self.saved_tick || self.local.get_str("fileformat") != self.saved_format
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved_tick = self.text.changedtick();
+        self.saved_format = String::from(self.local.get_str("fileformat"));
+    }
+}
@@ -0,0 +1,55 @@
+use std::env;
+use std::path::PathBuf;
+
+use crate::editor::Editor;
+use crate::ex;
+
+// Directory of the user configuration: `$XDG_CONFIG_HOME/editor`, falling
+// back to `~/.config/editor`
+fn config_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("editor"))
+}
+
+/// Location of the user configuration file:
+/// `$XDG_CONFIG_HOME/editor/config`, falling back to `~/.config/editor/config`.
+pub fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config"))
+}
+
+// Colour schemes that come with the editor
+const SCHEMES : &[(&str, &str)] = &[
+    ("dark", include_str!("../colors/dark.vim")),
+    ("light", include_str!("../colors/light.vim")),
+    ("default", "highlight clear\n"),
+];
+
+/// Load colour scheme `name`: `colors/{name}.vim` in the configuration
+/// directory, or one that comes with the editor.
+pub fn load_scheme(editor : &mut Editor, name : &str) -> Result<(), String> {
+    let path = config_dir().map(|dir| dir.join("colors").join(format!("{}.vim", name)));
+    match (path, SCHEMES.iter().find(|(n, _)| *n == name)) {
+        (Some(path), _) if path.is_file() => ex::source(editor, &path.to_string_lossy())?,
+        (_, Some((_, contents))) => ex::source_str(editor, name, contents)?,
+        _ => return Err(format!("E185: Cannot find color scheme '{}'", name)),
+    }
+    editor.highlights.scheme = Some(String::from(name));
+    Ok(())
+}
+
+/// Load the configuration selected on the command line. `rc` is the argument
+/// given to `-u`: `None` loads the default file if it exists, `NONE` skips
+/// loading altogether.
+pub fn load(editor : &mut Editor, rc : Option<&str>) -> Result<(), String> {
+    match rc {
+        Some("NONE") => Ok(()),
+        Some(path) => ex::source(editor, path),
+        None => match default_path() {
+            Some(path) if path.is_file() => ex::source(editor, &path.to_string_lossy()),
+            _ => Ok(()),
+        },
+    }
+}
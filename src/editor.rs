@@ -0,0 +1,630 @@
+use std::fs;
+
+use crate::anchor::Anchor;
+use crate::autocmd::{Autocmds, Event};
+use crate::buffer::Buffer;
+use crate::ex;
+use crate::fileformat;
+use crate::filetype;
+use crate::format;
+use crate::gutter::{self, Gutter};
+use crate::highlight::{Depth, Highlights};
+use crate::indent;
+use crate::keymap::Keymap;
+use crate::listchars::{self, ListChars};
+use crate::matching;
+use crate::modeline;
+use crate::options::{Options, SetScope};
+use crate::pos::{CharIdx, LineNo, Position, Range};
+use crate::register::Registers;
+use crate::replace::Replaced;
+use crate::syntax::{self, Lang};
+use crate::width;
+use crate::wrap::Wrap;
+
+/// Where a Visual mode selection started, and whether it takes whole lines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Visual {
+    pub line : usize,
+    pub col : usize,
+    pub linewise : bool,
+}
+
+/// Keys insert mode is waiting for after Ctrl-R or Ctrl-V.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InsertPending {
+    None,
+    Register,   // Ctrl-R: the name of the register to insert
+    Literal,    // Ctrl-V: a key to insert as it is
+    Code { radix : u32, max : usize, lead : Option<char>, digits : String },
+}
+
+pub struct Editor {
+    pub buffer : Buffer,
+    pub line : usize,               // Cursor line
+    pub col : usize,                // Cursor position in the line, in chars
+    pub top : usize,                // First line shown on screen
+    pub left : usize,               // First cell shown of each line with 'nowrap'
+    pub options : Options,
+    pub autocmds : Autocmds,
+    pub maps : Keymap,
+    pub registers : Registers,
+    pub highlights : Highlights,
+    pub cmdline : String,           // Command line being typed after ':'
+    pub message : Option<String>,   // Shown on the last screen line
+    pub status : String,            // Text left on the last screen line
+    pub hit_enter : Option<String>, // Long message shown until a key is pressed
+    pub redraw : bool,              // Draw the whole screen again, for Ctrl-L
+    pub silent : bool,              // Executing a <silent> mapping
+    pub inserted : String,          // Text typed since entering insert mode
+    pub insert_start : Option<Anchor>,  // Where insert mode was entered
+    pub insert_pending : InsertPending,
+    pub insert_oneshot : Option<i8>,    // Mode to go back to after Ctrl-O
+    pub auto_indent : Option<usize>,    // Line indented by 'autoindent', until something is typed
+    pub replaced : Vec<Replaced>,       // Replace mode changes Backspace can undo
+    pub count : usize,                  // Count typed before a normal mode command
+    pub pending : String,               // Keys of an unfinished normal mode command
+    pub register : Option<char>,        // Register given with "x for the next command
+    pub visual : Option<Visual>,        // Selection in Visual mode
+    pub matched : Option<(CharIdx, CharIdx)>,   // Bracket pair shown at the cursor
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        let options = Options::new();
+        let buffer = Buffer::new("", None, &options);
+        Editor {
+            buffer,
+            line : 0,
+            col : 0,
+            top : 0,
+            left : 0,
+            options,
+            autocmds : Autocmds::default(),
+            maps : Keymap::default(),
+            registers : Registers::default(),
+            highlights : Highlights::new(Depth::detect()),
+            cmdline : String::new(),
+            message : None,
+            status : String::new(),
+            hit_enter : None,
+            redraw : false,
+            silent : false,
+            inserted : String::new(),
+            insert_start : None,
+            insert_pending : InsertPending::None,
+            insert_oneshot : None,
+            auto_indent : None,
+            replaced : Vec::new(),
+            count : 0,
+            pending : String::new(),
+            register : None,
+            visual : None,
+            matched : None,
+        }
+    }
+
+    pub fn opt_bool(&self, name : &str) -> bool {
+        self.options.get_bool(&self.buffer.local, name)
+    }
+
+    pub fn opt_number(&self, name : &str) -> i64 {
+        self.options.get_number(&self.buffer.local, name)
+    }
+
+    pub fn opt_str(&self, name : &str) -> &str {
+        self.options.get_str(&self.buffer.local, name)
+    }
+
+    /// Contents of register `name`, including the read-only `%` register
+    /// holding the file name.
+    pub fn register_text(&self, name : char) -> Option<String> {
+        match name {
+            '%' => self.buffer.name.clone(),
+            _ => self.registers.get(name).map(|r| r.text.clone()),
+        }
+    }
+
+    /// Chars selected in Visual mode, from the start of the first line to
+    /// the end of the last one when whole lines are selected.
+    pub fn selection(&self) -> Option<(Range, bool)> {
+        let v = self.visual?;
+        let text = &self.buffer.text;
+        let (from, to) = (Position::new(v.line, v.col).min(self.cursor()), Position::new(v.line, v.col).max(self.cursor()));
+        if v.linewise {
+            let start = text.line_start(from.line);
+            let end = text.line_start(to.line) + text.get_line_length(to.line);
+            return Some((Range::new(start, end), true));
+        }
+        // The whole grapheme at the end
+        let line = text.get_line(to.line);
+        let end = text.char_idx(to) + (width::next_col(line, to.col) - to.col).max(1);
+        Some((Range::new(text.char_idx(from), end.min(text.end())), false))
+    }
+
+    /// Where `%` goes from char index `idx`, matching 'matchpairs' and
+    /// skipping strings of the language highlighted.
+    pub fn match_pair(&self, idx : CharIdx) -> Option<CharIdx> {
+        let pairs = matching::parse_pairs(self.opt_str("matchpairs")).unwrap_or_default();
+        let quotes = self.buffer.highlight.lang().map_or(String::from("\""), Lang::quotes);
+        self.buffer.text.match_pair(idx, &pairs, &quotes)
+    }
+
+    /// The bracket at char index `idx` and its match, if `idx` is on one and
+    /// the match is in lines `first` to `last`.
+    pub fn bracket_pair(&self, idx : CharIdx, first : LineNo, last : LineNo) -> Option<(CharIdx, CharIdx)> {
+        let pairs = matching::parse_pairs(self.opt_str("matchpairs")).unwrap_or_default();
+        let text = &self.buffer.text;
+        let pos = text.position(idx);
+        let c = text.get_line(pos.line).chars().nth(pos.col)?;
+        if !pairs.iter().any(|(o, cl)| c == *o || c == *cl) {
+            return None;
+        }
+        let quotes = self.buffer.highlight.lang().map_or(String::from("\""), Lang::quotes);
+        text.match_pair_in(idx, &pairs, &quotes, first, last).map(|m| (idx, m))
+    }
+
+    pub fn cursor(&self) -> Position {
+        Position::new(self.line, self.col)
+    }
+
+    /// Char index of the cursor.
+    pub fn cursor_idx(&self) -> CharIdx {
+        self.buffer.text.char_idx(self.cursor())
+    }
+
+    /// Move the cursor to `pos`, keeping it on the start of a grapheme of
+    /// the line.
+    pub fn set_cursor(&mut self, pos : Position) {
+        let text = &self.buffer.text;
+        self.line = pos.line.min(text.last_line()).0;
+        let line = text.get_line(LineNo(self.line));
+        self.col = width::snap_col(line, pos.col.min(line.chars().count().saturating_sub(1)));
+    }
+
+    /// Set mark `name` at char index `idx`, as `m{name}` does at the cursor.
+    /// Uppercase marks are file marks, which only differ from lowercase ones
+    /// with more than one buffer.
+    pub fn set_mark(&mut self, name : char, idx : CharIdx) -> Result<(), String> {
+        let name = if name == '`' { '\'' } else { name };
+        if !name.is_ascii_alphabetic() && !"'[]<>".contains(name) {
+            return Err(String::from("E191: Argument must be a letter or forward/backward quote"));
+        }
+        self.buffer.text.marks_mut().set(name, idx);
+        Ok(())
+    }
+
+    /// Where mark `name` is, where `` ` `` is the same as `'`.
+    pub fn mark_position(&self, name : char) -> Result<Position, String> {
+        let name = if name == '`' { '\'' } else { name };
+        if !name.is_ascii_alphabetic() && !"'.^[]<>".contains(name) {
+            return Err(String::from("E78: Unknown mark"));
+        }
+        match self.buffer.text.marks().get(name) {
+            Some(idx) => Ok(self.buffer.text.position(idx.min(self.buffer.text.end()))),
+            None => Err(String::from("E20: Mark not set")),
+        }
+    }
+
+    /// Remember the cursor position in the jump list before a jump.
+    pub fn push_jump(&mut self) {
+        let idx = self.cursor_idx();
+        self.buffer.text.marks_mut().push_jump(idx);
+    }
+
+    /// Move the cursor to char index `idx`.
+    pub fn goto_idx(&mut self, idx : CharIdx) {
+        let text = &self.buffer.text;
+        self.set_cursor(text.position(idx.min(text.end())));
+    }
+
+    /// `:marks`: the marks with their line, column and text.
+    pub fn list_marks(&self) -> String {
+        let text = &self.buffer.text;
+        let mut lines = vec![String::from("mark line  col file/text")];
+        for (name, idx) in text.marks().list() {
+            let pos = text.position(idx.min(text.end()));
+            lines.push(format!(" {} {:>6} {:>4} {}", name, pos.line.one_based(), pos.col, text.get_line(pos.line).trim_start()));
+        }
+        lines.join("\n")
+    }
+
+    /// Cells of the line 'colorcolumn' highlights, from 0.
+    pub fn color_columns(&self) -> Vec<usize> {
+        self.opt_str("colorcolumn").split(',').filter_map(|c| c.parse::<usize>().ok()?.checked_sub(1)).collect()
+    }
+
+    /// What 'list' mode shows, or None when 'list' is off.
+    pub fn list_chars(&self) -> Option<ListChars> {
+        match self.opt_bool("list") {
+            true => listchars::parse(self.opt_str("listchars")),
+            false => None,
+        }
+    }
+
+    /// Cells from one tab stop to the next on screen: 'tabstop', or 0 when
+    /// 'list' shows tabs as `^I`.
+    pub fn screen_tabstop(&self) -> usize {
+        match self.list_chars() {
+            Some(lcs) if lcs.tab.is_none() => 0,
+            _ => self.opt_number("tabstop") as usize,
+        }
+    }
+
+    /// Widths of the fold column, the sign column and the line numbers.
+    /// With 'signcolumn' "auto" the sign column shows when a line has a
+    /// sign.
+    pub fn gutter(&self) -> Gutter {
+        let signs = match self.opt_str("signcolumn") {
+            "yes" => true,
+            "auto" => self.buffer.text.marks().list().iter().any(|(c, _)| c.is_ascii_alphabetic()),
+            _ => false,
+        };
+        let number = self.opt_bool("number") || self.opt_bool("relativenumber");
+        let count = self.buffer.text.line_count();
+        Gutter {
+            fold : self.opt_number("foldcolumn") as usize,
+            sign : if signs { gutter::SIGN_WIDTH } else { 0 },
+            number : if number { gutter::number_width(count, self.opt_number("numberwidth") as usize) } else { 0 },
+        }
+    }
+
+    /// Sign shown in the sign column for `line`: the letter marks on it.
+    pub fn sign(&self, line : LineNo) -> String {
+        let text = &self.buffer.text;
+        let letters : String = text.marks().list().into_iter()
+            .filter(|(c, idx)| c.is_ascii_alphabetic() && text.line_of((*idx).min(text.end())) == line)
+            .map(|(c, _)| c)
+            .collect();
+        letters.chars().take(gutter::SIGN_WIDTH).collect()
+    }
+
+    /// How lines are wrapped in screen rows `width` cells wide, or None
+    /// with 'nowrap'.
+    pub fn wrap(&self, width : usize) -> Option<Wrap<'_>> {
+        if !self.opt_bool("wrap") {
+            return None;
+        }
+        Some(Wrap {
+            width : width.max(1),
+            ts : self.screen_tabstop(),
+            linebreak : self.opt_bool("linebreak") && self.list_chars().is_none(),
+            breakat : self.opt_str("breakat"),
+            breakindent : self.opt_bool("breakindent"),
+            showbreak : self.opt_str("showbreak"),
+        })
+    }
+
+    /// Columns in one indent step: 'shiftwidth', or 'tabstop' when it is 0.
+    pub fn shiftwidth(&self) -> usize {
+        match self.opt_number("shiftwidth") {
+            0 => self.opt_number("tabstop") as usize,
+            n => n as usize,
+        }
+    }
+
+    /// Move the cursor to the first non-blank char of `line`.
+    pub fn goto_first_non_blank(&mut self, line : LineNo) {
+        self.line = line.min(self.buffer.text.last_line()).0;
+        let text = self.buffer.text.get_line(LineNo(self.line));
+        let blanks = text.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+        self.col = blanks.min(text.chars().count().saturating_sub(1));
+    }
+
+    /// Join lines `first` to `last` like `J`, or like `gJ` without
+    /// `spaces`, and put the cursor where the last line was joined.
+    pub fn join_lines(&mut self, first : LineNo, last : LineNo, spaces : bool) -> Result<(), String> {
+        let text = &self.buffer.text;
+        let (joined, col) = format::join(&text.get_lines(first, last), spaces, self.opt_bool("joinspaces"));
+        self.buffer.text.replace_lines(first, last, &[joined])?;
+        self.line = first.0;
+        self.col = col;
+        Ok(())
+    }
+
+    /// Shift lines `first` to `last` by `amount` indents, to the right when
+    /// positive. Empty lines are left alone.
+    pub fn shift_lines(&mut self, first : LineNo, last : LineNo, amount : i64) -> Result<(), String> {
+        let (sw, ts) = (self.shiftwidth(), self.opt_number("tabstop") as usize);
+        let (round, expandtab) = (self.opt_bool("shiftround"), self.opt_bool("expandtab"));
+        let lines : Vec<String> = self.buffer.text.get_lines(first, last).iter()
+            .map(|l| match l.is_empty() {
+                true => String::new(),
+                false => indent::set_indent(l, indent::shifted_width(l, amount, sw, ts, round), ts, expandtab),
+            })
+            .collect();
+        self.buffer.text.replace_lines(first, last, &lines)?;
+        self.goto_first_non_blank(first);
+        let dir = if amount > 0 { '>' } else { '<' };
+        let times = amount.unsigned_abs();
+        let plural = if times == 1 { "" } else { "s" };
+        let n = last - first + 1;
+        self.report(n, &format!("{} lines {}ed {} time{}", n, dir, times, plural));
+        Ok(())
+    }
+
+    // Width of the indent 'autoindent' and 'smartindent' give a new line
+    // holding `line` after `prev`, or None when both are off
+    fn auto_indent_width(&self, prev : &str, line : &str) -> Option<usize> {
+        let ts = self.opt_number("tabstop") as usize;
+        match (self.opt_bool("smartindent"), self.opt_bool("autoindent")) {
+            (true, _) => {
+                let openers = syntax::lang(self.opt_str("filetype")).map_or("{", |l| l.indent_after);
+                Some(indent::smart_indent_width(prev, line, self.shiftwidth(), ts, openers))
+            }
+            (false, true) => Some(indent::indent_width(prev, ts)),
+            (false, false) => None,
+        }
+    }
+
+    /// Indent for a new line holding `line` after the line `prev`, from
+    /// 'autoindent' and 'smartindent'. None when both are off.
+    pub fn new_line_indent(&self, prev : &str, line : &str) -> Option<String> {
+        let ts = self.opt_number("tabstop") as usize;
+        self.auto_indent_width(prev, line).map(|w| indent::indent_text(w, ts, self.opt_bool("expandtab")))
+    }
+
+    /// Open a new line below the cursor line, or above it with `above`, and
+    /// put the cursor on it after the indent it gets.
+    pub fn open_line(&mut self, above : bool) -> Result<(), String> {
+        let y = LineNo(self.line);
+        let line = String::from(self.buffer.text.get_line(y));
+        let ts = self.opt_number("tabstop") as usize;
+        let width = match above {
+            // As deep as the line it goes above, one more above a closing brace
+            true => self.auto_indent_width(&line, "").map(|_| {
+                let closes = self.opt_bool("smartindent") && line.trim_start().starts_with('}');
+                indent::indent_width(&line, ts) + if closes { self.shiftwidth() } else { 0 }
+            }),
+            false => self.auto_indent_width(&line, ""),
+        };
+        let indent = width.map_or(String::new(), |w| indent::indent_text(w, ts, self.opt_bool("expandtab")));
+        let text = &mut self.buffer.text;
+        let idx = match above {
+            true => text.line_start(y),
+            false => text.line_start(y) + text.get_line_length(y),
+        };
+        text.write_char("\n", idx)?;
+        if !above {
+            self.line += 1;
+        }
+        text.insert_str(text.line_start(LineNo(self.line)), &indent)?;
+        self.col = indent.chars().count();
+        self.auto_indent = (!indent.is_empty()).then_some(self.line);
+        Ok(())
+    }
+
+    /// Redo the blanks holding tabs in lines `first` to `last` for tabstop
+    /// `new_ts`, and all blanks with `all`, like `:retab`. The new tabstop
+    /// becomes 'tabstop'.
+    pub fn retab(&mut self, first : LineNo, last : LineNo, new_ts : Option<usize>, all : bool) -> Result<(), String> {
+        let ts = self.opt_number("tabstop") as usize;
+        let new_ts = new_ts.filter(|n| *n > 0).unwrap_or(ts);
+        let expandtab = self.opt_bool("expandtab");
+        let old = self.buffer.text.get_lines(first, last);
+        let lines : Vec<String> = old.iter().map(|l| indent::retab(l, ts, new_ts, expandtab, all)).collect();
+        if lines != old {
+            self.buffer.text.replace_lines(first, last, &lines)?;
+        }
+        if new_ts != ts {
+            self.options.set(&mut self.buffer.local, &format!("tabstop={}", new_ts), SetScope::Both)?;
+        }
+        Ok(())
+    }
+
+    /// Indent lines `first` to `last` from the line above them, like `=`.
+    pub fn reindent_lines(&mut self, first : LineNo, last : LineNo) -> Result<(), String> {
+        let (sw, ts) = (self.shiftwidth(), self.opt_number("tabstop") as usize);
+        let expandtab = self.opt_bool("expandtab");
+        let text = &self.buffer.text;
+        let mut prev = (0..first.0).rev().map(|n| text.get_line(LineNo(n)))
+            .find(|l| !l.trim().is_empty()).map(String::from).unwrap_or_default();
+        let mut lines = Vec::new();
+        for line in text.get_lines(first, last) {
+            let new = indent::set_indent(&line, indent::auto_indent_width(&prev, &line, sw, ts), ts, expandtab);
+            if !new.is_empty() {
+                prev = new.clone();
+            }
+            lines.push(new);
+        }
+        self.buffer.text.replace_lines(first, last, &lines)?;
+        self.goto_first_non_blank(first);
+        let n = last - first + 1;
+        self.report(n, &format!("{} lines indented ", n));
+        Ok(())
+    }
+
+    /// Show `msg` about a command changing `lines` lines when there are more
+    /// than 'report'.
+    pub fn report(&mut self, lines : usize, msg : &str) {
+        if lines as i64 > self.opt_number("report") {
+            self.add_message(msg);
+        }
+    }
+
+    /// Undo the last change, or redo the last undone one, and put the cursor
+    /// where it was made. Returns false when there was nothing to do.
+    pub fn undo(&mut self, redo : bool) -> bool {
+        let text = &mut self.buffer.text;
+        let start = if redo { text.redo() } else { text.undo() };
+        match start {
+            Ok(Some(idx)) => {
+                self.goto_idx(idx);
+                true
+            }
+            Ok(None) => {
+                self.add_message(if redo { "Already at newest change" } else { "Already at oldest change" });
+                false
+            }
+            Err(e) => {
+                self.add_message(&e.to_string());
+                false
+            }
+        }
+    }
+
+    /// Append to the message shown on the status line.
+    pub fn add_message(&mut self, msg : &str) {
+        match &mut self.message {
+            Some(m) => {
+                m.push('\n');
+                m.push_str(msg);
+            }
+            None => self.message = Some(String::from(msg)),
+        }
+    }
+
+    /// Run the autocommands registered for `event` on the current buffer.
+    /// FileType ones match the file type rather than the file name.
+    pub fn fire(&mut self, event : Event) {
+        let name = match event {
+            Event::FileType => String::from(self.opt_str("filetype")),
+            _ => self.buffer.name.clone().unwrap_or_default(),
+        };
+        for cmd in self.autocmds.matching(event, &name) {
+            if let Err(e) = ex::execute(self, &cmd) {
+                self.add_message(&e);
+            }
+        }
+    }
+
+    /// Bring the buffer up to date after options were set, when 'filetype'
+    /// was `filetype` before. A new file type sets 'syntax' to it and runs
+    /// the FileType autocommands, and the text is highlighted as 'syntax'.
+    pub fn options_set(&mut self, filetype : &str) {
+        self.buffer.text.set_modifiable(self.opt_bool("modifiable"));
+        if self.opt_str("filetype") != filetype {
+            let arg = format!("syntax={}", self.opt_str("filetype"));
+            // A valid file type is a valid syntax name
+            let _ = self.options.set(&mut self.buffer.local, &arg, SetScope::Local);
+            self.fire(Event::FileType);
+        }
+        self.buffer.highlight.set_lang(syntax::lang(self.opt_str("syntax")));
+    }
+
+    /// Set 'filetype' by the name of the buffer's file or its first line.
+    pub fn detect_filetype(&mut self) {
+        let first = self.buffer.text.get_line(LineNo(0));
+        if let Some(ft) = filetype::detect(self.buffer.name.as_deref(), first) {
+            let old = String::from(self.opt_str("filetype"));
+            let _ = self.options.set(&mut self.buffer.local, &format!("filetype={}", ft), SetScope::Local);
+            self.options_set(&old);
+        }
+    }
+
+    /// Set the options of modelines in the first and last 'modelines'
+    /// lines, when 'modeline' is set. Only buffer-local options can be set.
+    pub fn apply_modelines(&mut self) {
+        let count = self.buffer.text.line_count();
+        let n = (self.opt_number("modelines") as usize).min(count);
+        if !self.opt_bool("modeline") || n == 0 {
+            return;
+        }
+        let lines : Vec<usize> = (0..n).chain((count - n).max(n)..count).collect();
+        let old = String::from(self.opt_str("filetype"));
+        for line in lines {
+            let args = match modeline::parse(self.buffer.text.get_line(LineNo(line))) {
+                Some(args) => args,
+                None => continue,
+            };
+            for arg in args {
+                let result = match Options::is_buffer_local(&arg) {
+                    true => self.options.set(&mut self.buffer.local, &arg, SetScope::Local).map(|_| ()),
+                    false => Err(format!("E520: Not allowed in a modeline: {}", arg)),
+                };
+                if let Err(e) = result {
+                    self.add_message(&e);
+                }
+            }
+        }
+        self.options_set(&old);
+    }
+
+    /// Make the buffer hold `contents` read from file `name`, with the line
+    /// ending its lines have as 'fileformat'.
+    pub fn read_buffer(&mut self, contents : &str, name : Option<&str>) {
+        self.buffer = Buffer::new(&fileformat::to_buffer(contents), name, &self.options);
+        self.take_format(contents);
+        self.buffer.mark_saved();
+    }
+
+    // Set 'fileformat' from the line endings of file contents `contents`.
+    // Mixed endings are all written as the one most lines have.
+    fn take_format(&mut self, contents : &str) {
+        let (format, mixed) = match fileformat::detect(contents) {
+            Some(detected) => detected,
+            None => return,
+        };
+        let _ = self.options.set(&mut self.buffer.local, &format!("fileformat={}", format), SetScope::Local);
+        if mixed {
+            let name = self.buffer.name.as_deref().unwrap_or("[No Name]");
+            self.add_message(&format!("\"{}\" has mixed line endings, written as {}", name, format));
+        }
+    }
+
+    /// See if the buffer's file changed on disk since it was read or
+    /// written, like `:checktime`. It is read again when 'autoread' is set
+    /// and the buffer has no changes, else there is a warning.
+    pub fn check_time(&mut self) {
+        if !self.buffer.changed_on_disk() {
+            return;
+        }
+        let name = self.buffer.name.clone().unwrap_or_default();
+        let contents = match fs::read_to_string(&name) {
+            Ok(c) => c,
+            Err(_) => {
+                self.add_message(&format!("E211: File \"{}\" no longer available", name));
+                return;
+            }
+        };
+        if !self.opt_bool("autoread") || self.buffer.modified() {
+            self.add_message(&format!("W11: Warning: File \"{}\" has changed since editing started", name));
+            return;
+        }
+        // Reading it again is a change that can be undone
+        let text = &mut self.buffer.text;
+        let all = Range::new(CharIdx(0), text.end());
+        if let Err(e) = text.replace_range(all, &fileformat::to_buffer(&contents)) {
+            self.add_message(&e.to_string());
+            return;
+        }
+        text.close_change();
+        self.take_format(&contents);
+        self.buffer.mark_saved();
+        self.set_cursor(self.cursor());
+        self.add_message(&format!("\"{}\" {}L, {}B", name, self.buffer.text.line_count(), contents.len()));
+    }
+
+    /// Write the buffer to `name`, or to the buffer's own file.
+    pub fn write(&mut self, name : Option<&str>, force : bool) -> Result<(), String> {
+        let target = match name.or(self.buffer.name.as_deref()) {
+            Some(n) => String::from(n),
+            None => return Err(String::from("E32: No file name")),
+        };
+        let own_file = self.buffer.name.as_deref() == Some(target.as_str());
+        if own_file && self.opt_bool("readonly") && !force {
+            return Err(String::from("E45: 'readonly' option is set (add ! to override)"));
+        }
+
+        self.fire(Event::BufWritePre);
+        let contents = fileformat::to_file(self.buffer.text.get_text(), self.opt_str("fileformat"));
+        if let Err(e) = fs::write(&target, &contents) {
+            return Err(format!("E212: Can't open file for writing: {}: {}", target, e));
+        }
+        let msg = format!("\"{}\" {}L, {}B written",
+            target, self.buffer.text.line_count(), contents.len());
+        if own_file {
+            self.buffer.mark_saved();
+            self.buffer.mark_disk_time();
+        }
+        self.fire(Event::BufWritePost);
+        self.add_message(&msg);
+        Ok(())
+    }
+}
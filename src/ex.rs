@@ -0,0 +1,900 @@
+use std::fs;
+
+use crate::autocmd::Event;
+use crate::config;
+use crate::editor::Editor;
+use crate::keymap::{self, MAP_CMDLINE, MAP_INSERT, MAP_NORMAL, MAP_OP_PENDING, MAP_VISUAL};
+use crate::options::SetScope;
+use crate::pos::{ByteIdx, LineNo};
+use crate::{NORMAL, QUIT};
+
+// Full command names with the shortest accepted abbreviation
+const COMMANDS : &[(&str, usize)] = &[
+    ("autocmd", 2),
+    ("checktime", 6),
+    ("cmap", 2),
+    ("cmapclear", 5),
+    ("cnoremap", 3),
+    ("colorscheme", 4),
+    ("copy", 2),
+    ("cunmap", 2),
+    ("delete", 1),
+    ("goto", 2),
+    ("highlight", 2),
+    ("imap", 2),
+    ("imapclear", 5),
+    ("inoremap", 3),
+    ("iunmap", 2),
+    ("join", 1),
+    ("k", 1),
+    ("map", 3),
+    ("mapclear", 4),
+    ("mark", 2),
+    ("marks", 5),
+    ("move", 1),
+    ("nmap", 2),
+    ("nmapclear", 5),
+    ("nnoremap", 2),
+    ("noremap", 2),
+    ("nunmap", 3),
+    ("omap", 2),
+    ("omapclear", 5),
+    ("onoremap", 3),
+    ("ounmap", 2),
+    ("put", 2),
+    ("quit", 1),
+    ("redo", 3),
+    ("retab", 3),
+    ("set", 2),
+    ("setglobal", 4),
+    ("setlocal", 4),
+    ("source", 2),
+    ("t", 1),
+    ("undo", 1),
+    ("unmap", 3),
+    ("vmap", 2),
+    ("vmapclear", 5),
+    ("vnoremap", 2),
+    ("vunmap", 2),
+    ("write", 1),
+    ("wq", 2),
+    ("xit", 1),
+    ("yank", 1),
+];
+
+// Mapping commands: modes they apply to and whether they define, remove
+// or clear mappings
+const MAP_ALL : u8 = MAP_NORMAL | MAP_VISUAL | MAP_OP_PENDING;
+const MAP_COMMANDS : &[(&str, u8)] = &[
+    ("map", MAP_ALL), ("noremap", MAP_ALL), ("unmap", MAP_ALL), ("mapclear", MAP_ALL),
+    ("nmap", MAP_NORMAL), ("nnoremap", MAP_NORMAL), ("nunmap", MAP_NORMAL), ("nmapclear", MAP_NORMAL),
+    ("vmap", MAP_VISUAL), ("vnoremap", MAP_VISUAL), ("vunmap", MAP_VISUAL), ("vmapclear", MAP_VISUAL),
+    ("omap", MAP_OP_PENDING), ("onoremap", MAP_OP_PENDING), ("ounmap", MAP_OP_PENDING),
+    ("omapclear", MAP_OP_PENDING),
+    ("imap", MAP_INSERT), ("inoremap", MAP_INSERT), ("iunmap", MAP_INSERT), ("imapclear", MAP_INSERT),
+    ("cmap", MAP_CMDLINE), ("cnoremap", MAP_CMDLINE), ("cunmap", MAP_CMDLINE), ("cmapclear", MAP_CMDLINE),
+];
+
+fn resolve(name : &str) -> Option<&'static str> {
+    COMMANDS.iter()
+        .find(|(full, min)| name.len() >= *min && full.starts_with(name))
+        .map(|(full, _)| *full)
+}
+
+/// Split a command line into command name, bang and argument string.
+fn parse(line : &str) -> (&str, bool, &str) {
+    let name_len = line.find(|c : char| !c.is_ascii_alphabetic()).unwrap_or(line.len());
+    let (name, rest) = line.split_at(name_len);
+    match rest.strip_prefix('!') {
+        Some(r) => (name, true, r.trim()),
+        None => (name, false, rest.trim()),
+    }
+}
+
+// One line address: `.`, `$`, a number or a mark like `'a`, followed by any number of
+// `+N` and `-N` offsets, taking `current` as the 1-based current line.
+// Returns the 1-based line, where 0 is before the first line, and what
+// follows the address.
+fn parse_address<'a>(editor : &Editor, s : &'a str, current : usize) -> Result<(Option<usize>, &'a str), String> {
+    let last = editor.buffer.text.line_count() as i64;
+    let number_len = |s : &str| s.find(|c : char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (mut line, mut rest) = match s.chars().next() {
+        Some('.') => (Some(current as i64), &s[1..]),
+        Some('$') => (Some(last), &s[1..]),
+        Some('\'') => {
+            let name = s[1..].chars().next().ok_or_else(|| String::from("E78: Unknown mark"))?;
+            let pos = editor.mark_position(name)?;
+            (Some(pos.line.one_based() as i64), &s[1 + name.len_utf8()..])
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let n = number_len(s);
+            (s[..n].parse::<i64>().ok(), &s[n..])
+        }
+        _ => (None, s),
+    };
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        let n = number_len(&rest[1..]);
+        let offset = rest[1..=n].parse::<i64>().unwrap_or(1);
+        let base = line.unwrap_or(current as i64);
+        line = Some(if sign == '+' { base + offset } else { base - offset });
+        rest = &rest[n + 1..];
+    }
+    match line {
+        Some(l) if l < 0 || l > last => Err(String::from("E16: Invalid range")),
+        l => Ok((l.map(|l| l as usize), rest)),
+    }
+}
+
+// First and last line of a range, 1-based
+type LineRange = (usize, usize);
+
+// The line of a 1-based address, where 0 is taken as the first line
+fn line_no(address : usize) -> LineNo {
+    LineNo::from_one_based(address).unwrap_or_default()
+}
+
+// The line range before a command: `%`, or one or two addresses separated
+// by `,` or `;`. After `;` the second address counts from the first one
+// instead of the cursor line, and the cursor is to go to the first one once
+// the command runs, so that line is returned too. A backwards range is
+// swapped, as Vim does in Ex mode.
+fn parse_range<'a>(editor : &Editor, s : &'a str) -> Result<(Option<LineRange>, Option<usize>, &'a str), String> {
+    if let Some(rest) = s.strip_prefix('%') {
+        return Ok((Some((1, editor.buffer.text.line_count())), None, rest));
+    }
+    let (first, rest) = parse_address(editor, s, editor.line + 1)?;
+    let first = match first {
+        Some(f) => f,
+        None => return Ok((None, None, rest)),
+    };
+    let (rest, cursor) = match (rest.strip_prefix(','), rest.strip_prefix(';')) {
+        (Some(r), _) => (r, None),
+        (_, Some(r)) => (r, Some(first)),
+        _ => return Ok((Some((first, first)), None, rest)),
+    };
+    let range = match parse_address(editor, rest, cursor.unwrap_or(editor.line + 1))? {
+        (Some(second), rest) if second < first => (Some((second, first)), rest),
+        (Some(second), rest) => (Some((first, second)), rest),
+        (None, rest) => (Some((first, first)), rest),
+    };
+    Ok((range.0, cursor, range.1))
+}
+
+/// Execute one ex command line. Returns the mode to continue in.
+pub fn execute(editor : &mut Editor, line : &str) -> Result<i8, String> {
+    let line = line.trim_start_matches([' ', '\t', ':']);
+    if line.is_empty() || line.starts_with('"') {
+        return Ok(NORMAL);
+    }
+    let (range, cursor, cmd_line) = parse_range(editor, line)?;
+    let (name, bang, args) = parse(cmd_line.trim_start());
+    if name.is_empty() && args.is_empty() {
+        // A range alone goes to its last line
+        if let Some((_, last)) = range {
+            editor.push_jump();
+            editor.goto_first_non_blank(line_no(last));
+        }
+        return Ok(NORMAL);
+    }
+    let cmd = match resolve(name) {
+        Some(c) => c,
+        None => return Err(format!("E492: Not an editor command: {}", line)),
+    };
+    // The command parsed, so `;` can move the cursor now
+    if let Some(l) = cursor {
+        editor.line = line_no(l).0;
+    }
+    let current = editor.line + 1;
+    let (first, last) = range.unwrap_or((current, current));
+
+    match cmd {
+        "delete" | "yank" => {
+            let (reg, count) = register_and_count(args)?;
+            let (first, last) = match count {
+                Some(n) => (last, (last + n - 1).min(editor.buffer.text.line_count())),
+                None => (first, last),
+            };
+            let (first, last) = (line_no(first), line_no(last));
+            let n = last - first + 1;
+            let lines = editor.buffer.text.get_lines(first, last);
+            editor.registers.set(reg, &(lines.join("\n") + "\n"), true)?;
+            if cmd == "delete" {
+                editor.buffer.text.delete_lines(first, last)?;
+                editor.goto_first_non_blank(first);
+                editor.report(n, &format!("{} fewer lines", n));
+            } else {
+                editor.report(n, &format!("{} lines yanked", n));
+            }
+            Ok(NORMAL)
+        }
+        "put" => {
+            let reg = args.chars().next().unwrap_or('"');
+            let text = match editor.register_text(reg) {
+                Some(t) => t,
+                None => return Err(format!("E353: Nothing in register {}", reg)),
+            };
+            let lines : Vec<String> = text.strip_suffix('\n').unwrap_or(&text)
+                .split('\n').map(String::from).collect();
+            let at = LineNo(if bang { last.saturating_sub(1) } else { last });
+            editor.buffer.text.insert_lines(at, &lines)?;
+            editor.goto_first_non_blank(at + lines.len() - 1);
+            editor.report(lines.len(), &format!("{} more lines", lines.len()));
+            Ok(NORMAL)
+        }
+        "move" | "copy" | "t" => {
+            let dest = match parse_address(editor, args, editor.line + 1)? {
+                (Some(d), "") => d,
+                _ => return Err(String::from("E14: Invalid address")),
+            };
+            let (first, last) = (line_no(first), line_no(last));
+            let lines = editor.buffer.text.get_lines(first, last);
+            let n = lines.len();
+            // Lines go after line `dest`, so before `LineNo(dest)`
+            let dest = LineNo(dest);
+            if cmd != "move" {
+                editor.buffer.text.insert_lines(dest, &lines)?;
+                editor.goto_first_non_blank(dest + n - 1);
+                editor.report(n, &format!("{} more lines", n));
+                return Ok(NORMAL);
+            }
+            if dest > first && dest <= last {
+                return Err(String::from("E134: Cannot move a range of lines into itself"));
+            }
+            // Insert first, so moving every line never leaves the empty line
+            // that deleting them all does
+            editor.buffer.text.insert_lines(dest, &lines)?;
+            if dest > last {
+                editor.buffer.text.delete_lines(first, last)?;
+                editor.goto_first_non_blank(dest - 1);
+            } else {
+                editor.buffer.text.delete_lines(first + n, last + n)?;
+                editor.goto_first_non_blank(dest + n - 1);
+            }
+            editor.report(n, &format!("{} lines moved", n));
+            Ok(NORMAL)
+        }
+        "join" => {
+            let (_, count) = register_and_count(args)?;
+            let (first, last) = match (count, range) {
+                (Some(n), _) => (last, last + n.max(2) - 1),
+                (None, Some((f, l))) if f != l => (f, l),
+                _ => (first, first + 1),
+            };
+            let end = editor.buffer.text.line_count();
+            if first.max(1) < last.min(end) {
+                editor.join_lines(line_no(first), line_no(last.min(end)), !bang)?;
+            }
+            Ok(NORMAL)
+        }
+        "goto" => {
+            // Byte count from 1, in the argument or the range
+            let count = match args {
+                "" => range.map_or(1, |(_, l)| l),
+                _ => args.parse::<usize>().map_err(|_| format!("E488: Trailing characters: {}", args))?,
+            };
+            let text = &editor.buffer.text;
+            let byte = count.max(1) - 1;
+            let byte = byte.min(text.get_text().len().saturating_sub(1));
+            // Inside a multibyte char, go to its start
+            let idx = (0..=byte).rev().find_map(|b| text.char_idx_at(ByteIdx(b)).ok()).unwrap_or_default();
+            editor.push_jump();
+            editor.goto_idx(idx);
+            Ok(NORMAL)
+        }
+        "mark" | "k" => {
+            let mut chars = args.chars();
+            let name = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                (None, _) => return Err(String::from("E471: Argument required")),
+                _ => return Err(format!("E488: Trailing characters: {}", args)),
+            };
+            let idx = editor.buffer.text.line_start(line_no(last));
+            editor.set_mark(name, idx)?;
+            Ok(NORMAL)
+        }
+        "marks" => {
+            let list = editor.list_marks();
+            editor.add_message(&list);
+            Ok(NORMAL)
+        }
+        "checktime" => {
+            editor.check_time();
+            Ok(NORMAL)
+        }
+        "retab" => {
+            // The whole buffer unless a range is given
+            let (first, last) = range.unwrap_or((1, editor.buffer.text.line_count()));
+            let new_ts = match args {
+                "" => None,
+                _ => Some(args.parse::<usize>().map_err(|_| format!("E475: Invalid argument: {}", args))?),
+            };
+            editor.retab(line_no(first), line_no(last), new_ts, bang)?;
+            Ok(NORMAL)
+        }
+        "undo" | "redo" => {
+            editor.undo(cmd == "redo");
+            Ok(NORMAL)
+        }
+        "set" | "setlocal" | "setglobal" => {
+            let scope = match cmd {
+                "setlocal" => SetScope::Local,
+                "setglobal" => SetScope::Global,
+                _ => SetScope::Both,
+            };
+            let filetype = String::from(editor.opt_str("filetype"));
+            let shown = if args.is_empty() || args == "all" {
+                Some(editor.options.describe_all(&editor.buffer.local).join("\n"))
+            } else {
+                let shown = editor.options.set_line(&mut editor.buffer.local, args, scope);
+                editor.options_set(&filetype);
+                shown?
+            };
+            if let Some(s) = shown {
+                editor.add_message(&s);
+            }
+            Ok(NORMAL)
+        }
+        "autocmd" => {
+            autocmd(editor, bang, args)?;
+            Ok(NORMAL)
+        }
+        "highlight" => {
+            highlight(editor, args)?;
+            Ok(NORMAL)
+        }
+        "colorscheme" => {
+            if args.is_empty() {
+                let name = editor.highlights.scheme.clone().unwrap_or(String::from("default"));
+                editor.add_message(&name);
+                return Ok(NORMAL);
+            }
+            config::load_scheme(editor, args)?;
+            Ok(NORMAL)
+        }
+        c if MAP_COMMANDS.iter().any(|(m, _)| *m == c) => {
+            map_command(editor, cmd, bang, args)?;
+            Ok(NORMAL)
+        }
+        "source" => {
+            if args.is_empty() {
+                return Err(String::from("E471: Argument required"));
+            }
+            source(editor, args)?;
+            Ok(NORMAL)
+        }
+        "write" => {
+            editor.write(Some(args).filter(|a| !a.is_empty()), bang)?;
+            Ok(NORMAL)
+        }
+        "quit" => {
+            if editor.buffer.modified() && !bang {
+                return Err(String::from("E37: No write since last change (add ! to override)"));
+            }
+            Ok(QUIT)
+        }
+        "wq" | "xit" => {
+            if cmd == "wq" || editor.buffer.modified() {
+                editor.write(Some(args).filter(|a| !a.is_empty()), bang)?;
+            }
+            Ok(QUIT)
+        }
+        _ => Err(format!("E492: Not an editor command: {}", line)),
+    }
+}
+
+// Optional register name and count after :delete, :yank and :join
+fn register_and_count(args : &str) -> Result<(char, Option<usize>), String> {
+    let mut reg = '"';
+    let mut rest = args;
+    if let Some(c) = args.chars().next().filter(|c| !c.is_ascii_digit()) {
+        reg = c;
+        rest = args[c.len_utf8()..].trim_start();
+    }
+    if rest.is_empty() {
+        return Ok((reg, None));
+    }
+    match rest.parse::<usize>() {
+        Ok(n) if n > 0 => Ok((reg, Some(n))),
+        Ok(_) => Err(String::from("E939: Positive count required")),
+        Err(_) => Err(format!("E488: Trailing characters: {}", rest)),
+    }
+}
+
+// :autocmd[!] [{event}[,{event}] [{pattern} [{command}]]]
+fn autocmd(editor : &mut Editor, bang : bool, args : &str) -> Result<(), String> {
+    let mut parts = args.splitn(3, [' ', '\t']);
+    let events_arg = parts.next().filter(|s| !s.is_empty());
+    let pattern = parts.next().map(str::trim).filter(|s| !s.is_empty());
+    let command = parts.next().map(str::trim).filter(|s| !s.is_empty());
+
+    let mut events : Vec<Event> = Vec::new();
+    if let Some(names) = events_arg.filter(|n| *n != "*") {
+        for name in names.split(',') {
+            match Event::from_name(name) {
+                Some(e) => events.push(e),
+                None => return Err(format!("E216: No such event: {}", name)),
+            }
+        }
+    }
+
+    if bang {
+        if events.is_empty() {
+            editor.autocmds.remove(None, pattern);
+        }
+        for e in &events {
+            editor.autocmds.remove(Some(*e), pattern);
+        }
+    }
+
+    match (pattern, command) {
+        (Some(p), Some(c)) => {
+            if events.is_empty() {
+                return Err(format!("E216: No such event: {}", events_arg.unwrap_or("")));
+            }
+            for e in events {
+                editor.autocmds.add(e, p, c);
+            }
+        }
+        _ if !bang => {
+            let lines : Vec<String> = editor.autocmds.iter()
+                .filter(|a| events.is_empty() || events.contains(&a.event))
+                .filter(|a| pattern.is_none_or(|p| p == a.pattern))
+                .map(|a| format!("{:?}  {}  {}", a.event, a.pattern, a.command))
+                .collect();
+            editor.add_message(&format!("--- Autocommands ---\n{}", lines.join("\n")));
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+// :highlight [clear] [{group} [{key}={value} ...]] and :highlight [default]
+// link {from} {to}
+fn highlight(editor : &mut Editor, args : &str) -> Result<(), String> {
+    let words : Vec<&str> = args.split_whitespace().collect();
+    let words = match words.as_slice() {
+        ["default", rest @ ..] => rest,
+        words => words,
+    };
+    let hl = &mut editor.highlights;
+    match words {
+        [] => {
+            let list = hl.describe_all().join("\n");
+            editor.add_message(&list);
+        }
+        ["clear"] => hl.reset(),
+        ["clear", group] => hl.clear(group),
+        ["link", from, to] => hl.link(from, to),
+        ["link", ..] | ["clear", ..] => return Err(format!("E412: Not enough arguments: :highlight {}", args)),
+        [group] => match hl.describe(group) {
+            Some(s) => editor.add_message(&s),
+            None => return Err(format!("E411: Highlight group not found: {}", group)),
+        },
+        [group, rest @ ..] => hl.set(group, &rest.join(" "))?,
+    }
+    Ok(())
+}
+
+// :map, :noremap, :unmap and :mapclear in all their mode variants
+fn map_command(editor : &mut Editor, cmd : &str, bang : bool, args : &str) -> Result<(), String> {
+    let mut modes = MAP_COMMANDS.iter().find(|(c, _)| *c == cmd).map_or(0, |(_, m)| *m);
+    if bang && modes == MAP_ALL {
+        modes = MAP_INSERT | MAP_CMDLINE;
+    }
+
+    // Leading <buffer> and <silent> modifiers, in any order
+    let mut local = false;
+    let mut silent = false;
+    let mut rest = args;
+    loop {
+        let lower = rest.get(..8).map(str::to_ascii_lowercase);
+        match lower.as_deref() {
+            Some("<buffer>") => local = true,
+            Some("<silent>") => silent = true,
+            _ => break,
+        }
+        rest = rest[8..].trim_start();
+    }
+    let (lhs_text, rhs_text) = match rest.find([' ', '\t']) {
+        Some(i) => (&rest[..i], rest[i..].trim_start()),
+        None => (rest, ""),
+    };
+    let leader = String::from(editor.opt_str("mapleader"));
+    let lhs = keymap::parse_keys(lhs_text, &leader);
+    let maps = if local { &mut editor.buffer.maps } else { &mut editor.maps };
+
+    if cmd.ends_with("mapclear") {
+        maps.clear(modes);
+        return Ok(());
+    }
+    if cmd.ends_with("unmap") {
+        if lhs.is_empty() {
+            return Err(String::from("E474: Invalid argument"));
+        }
+        if !maps.remove(modes, &lhs) {
+            return Err(String::from("E31: No such mapping"));
+        }
+        return Ok(());
+    }
+    if rhs_text.is_empty() {
+        let mut lines = editor.buffer.maps.list(modes, &lhs, true);
+        lines.extend(editor.maps.list(modes, &lhs, false));
+        if lines.is_empty() {
+            editor.add_message("No mapping found");
+        } else {
+            editor.add_message(&lines.join("\n"));
+        }
+        return Ok(());
+    }
+    let noremap = cmd.contains("noremap");
+    let rhs = keymap::parse_keys(rhs_text, &leader);
+    maps.add(modes, &lhs, &rhs, rhs_text, noremap, silent);
+    Ok(())
+}
+
+/// Execute every line of `path` as an ex command.
+pub fn source(editor : &mut Editor, path : &str) -> Result<(), String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(_) => return Err(format!("E484: Can't open file {}", path)),
+    };
+    source_str(editor, path, &contents)
+}
+
+/// Execute every line of `contents`, read from `name`, as an ex command.
+pub fn source_str(editor : &mut Editor, name : &str, contents : &str) -> Result<(), String> {
+    // Join continuation lines starting with a backslash
+    let mut lines : Vec<(usize, String)> = Vec::new();
+    for (n, raw) in contents.lines().enumerate() {
+        let trimmed = raw.trim_start();
+        match (trimmed.strip_prefix('\\'), lines.last_mut()) {
+            (Some(rest), Some((_, prev))) => prev.push_str(rest),
+            _ => lines.push((n + 1, String::from(trimmed))),
+        }
+    }
+
+    let mut errors : Vec<String> = Vec::new();
+    for (n, line) in lines {
+        if let Err(e) = execute(editor, &line) {
+            errors.push(format!("line {}:\n{}", n, e));
+        }
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(format!("Error detected while processing {}:\n{}", name, errors.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlight::TermColor;
+    use crate::pos::{CharIdx, Position};
+
+    #[test]
+    fn test_resolve_abbreviations() {
+        assert_eq!(resolve("w"), Some("write"));
+        assert_eq!(resolve("wq"), Some("wq"));
+        assert_eq!(resolve("se"), Some("set"));
+        assert_eq!(resolve("setl"), Some("setlocal"));
+        assert_eq!(resolve("au"), Some("autocmd"));
+        assert_eq!(resolve("s"), None);
+        assert_eq!(resolve("a"), None);
+    }
+
+    #[test]
+    fn test_parse_bang_and_args() {
+        assert_eq!(parse("q!"), ("q", true, ""));
+        assert_eq!(parse("set ts=4 "), ("set", false, "ts=4"));
+        assert_eq!(parse("autocmd! BufRead"), ("autocmd", true, "BufRead"));
+    }
+
+    #[test]
+    fn test_execute_set_and_autocmd() {
+        let mut e = Editor::new();
+        assert_eq!(execute(&mut e, ":set ut=200"), Ok(NORMAL));
+        assert_eq!(e.opt_number("updatetime"), 200);
+        assert!(execute(&mut e, "bogus").unwrap_err().starts_with("E492"));
+
+        execute(&mut e, "autocmd InsertEnter * set ro").unwrap();
+        e.fire(Event::InsertEnter);
+        assert!(e.opt_bool("readonly"));
+        execute(&mut e, "autocmd! InsertEnter").unwrap();
+        assert_eq!(e.autocmds.iter().count(), 0);
+        assert!(execute(&mut e, "au Nope * set ro").unwrap_err().starts_with("E216"));
+    }
+
+    #[test]
+    fn test_highlight_and_colorscheme() {
+        let mut e = Editor::new();
+        execute(&mut e, "hi Comment guifg=#ff0000 gui=bold").unwrap();
+        let comment = e.highlights.get("Comment").unwrap();
+        assert_eq!(comment.fg, Some(TermColor::Rgb(255, 0, 0)));
+        assert!(comment.bold);
+        execute(&mut e, "highlight default link Note Comment").unwrap();
+        assert_eq!(e.highlights.style("Note"), e.highlights.style("Comment"));
+        assert!(execute(&mut e, "hi Nope").unwrap_err().starts_with("E411"));
+        assert!(execute(&mut e, "hi link Note").unwrap_err().starts_with("E412"));
+
+        execute(&mut e, "colorscheme light").unwrap();
+        assert_eq!(e.highlights.scheme.as_deref(), Some("light"));
+        assert!(e.highlights.get("Normal").unwrap().bg.is_some());
+        assert!(e.highlights.get("Note").is_none());
+        assert!(execute(&mut e, "colo nosuch").unwrap_err().starts_with("E185"));
+        execute(&mut e, "colo default").unwrap();
+        assert_eq!(e.highlights.get("Normal").unwrap().bg, None);
+    }
+
+    #[test]
+    fn test_filetype_and_modelines() {
+        let mut e = Editor::new();
+        execute(&mut e, "autocmd FileType python setlocal ts=4 cms=#\\ %s").unwrap();
+        execute(&mut e, "set ft=python").unwrap();
+        assert_eq!(e.opt_number("tabstop"), 4);
+        assert_eq!(e.opt_str("commentstring"), "# %s");
+        assert_eq!(e.opt_str("syntax"), "python");
+        assert!(e.buffer.highlight.lang().is_some());
+
+        // Modelines set buffer options only
+        e.buffer.text = crate::text::Text::new("x = 1\n# vim: set ft=sh ut=1:\n");
+        e.apply_modelines();
+        assert_eq!(e.opt_str("filetype"), "sh");
+        assert_eq!(e.opt_number("updatetime"), 500);
+        assert!(e.message.as_deref().is_some_and(|m| m.starts_with("E520")));
+        execute(&mut e, "setl syntax=").unwrap();
+        assert!(e.buffer.highlight.lang().is_none());
+    }
+
+    #[test]
+    fn test_map_commands() {
+        let mut e = Editor::new();
+        execute(&mut e, "set mapleader=,").unwrap();
+        execute(&mut e, "nnoremap <silent> <leader>w :w<CR>").unwrap();
+        let lhs = keymap::parse_keys(",w", "");
+        let (m, _) = e.maps.lookup(MAP_NORMAL, &lhs);
+        let m = m.unwrap();
+        assert!(m.noremap && m.silent);
+        assert_eq!(m.rhs.len(), 3);
+        assert!(e.maps.lookup(MAP_INSERT, &lhs).0.is_none());
+
+        execute(&mut e, "map! jk <Esc>").unwrap();
+        assert!(e.maps.lookup(MAP_INSERT, &keymap::parse_keys("jk", "")).0.is_some());
+        assert!(e.maps.lookup(MAP_NORMAL, &keymap::parse_keys("jk", "")).0.is_none());
+
+        execute(&mut e, "imap <buffer> jj <Esc>").unwrap();
+        assert!(e.buffer.maps.lookup(MAP_INSERT, &keymap::parse_keys("jj", "")).0.is_some());
+
+        execute(&mut e, "nunmap ,w").unwrap();
+        assert!(execute(&mut e, "nunmap ,w").unwrap_err().starts_with("E31"));
+        execute(&mut e, "mapclear!").unwrap();
+        assert!(e.maps.lookup(MAP_INSERT, &keymap::parse_keys("jk", "")).0.is_none());
+    }
+
+    #[test]
+    fn test_quit_refuses_modified_buffer() {
+        let mut e = Editor::new();
+        assert_eq!(execute(&mut e, "q"), Ok(QUIT));
+        let _ = e.buffer.text.write_char("x", CharIdx(0));
+        assert!(execute(&mut e, "q").unwrap_err().starts_with("E37"));
+        assert_eq!(execute(&mut e, "q!"), Ok(QUIT));
+    }
+
+    fn editor_with(text : &str) -> Editor {
+        let mut e = Editor::new();
+        e.buffer.text = crate::text::Text::new(text);
+        e
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut e = editor_with("a\nb\nc\nd");
+        e.line = 1;
+        assert_eq!(parse_range(&e, "%d"), Ok((Some((1, 4)), None, "d")));
+        assert_eq!(parse_range(&e, ".,$y"), Ok((Some((2, 4)), None, "y")));
+        assert_eq!(parse_range(&e, "-,+"), Ok((Some((1, 3)), None, "")));
+        assert_eq!(parse_range(&e, "3,1"), Ok((Some((1, 3)), None, "")));
+        assert_eq!(parse_range(&e, "$-2m0"), Ok((Some((2, 2)), None, "m0")));
+        assert_eq!(parse_range(&e, "d"), Ok((None, None, "d")));
+        assert!(parse_range(&e, "9d").is_err());
+        // After `;` the second address counts from the first
+        assert_eq!(parse_range(&e, "1;+2d"), Ok((Some((1, 3)), Some(1), "d")));
+    }
+
+    #[test]
+    fn test_semicolon_range_moves_cursor_only_when_run() {
+        let mut e = editor_with("a\nb\nc\nd\ne");
+        assert!(execute(&mut e, "3;5zz").is_err());
+        assert!(execute(&mut e, "3;bogus").is_err());
+        assert_eq!(e.line, 0);
+        execute(&mut e, "3;+1y").unwrap();
+        assert_eq!(e.line, 2);
+        assert_eq!(e.registers.get('"').unwrap().text, "c\nd\n");
+    }
+
+    #[test]
+    fn test_backwards_range_is_swapped() {
+        // Like Vim's Ex mode, a backwards range runs without asking
+        let mut e = editor_with("a\nb\nc\nd");
+        execute(&mut e, "3,1d").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "d");
+    }
+
+    #[test]
+    fn test_goto() {
+        let mut e = editor_with("aø\nb");
+        execute(&mut e, "goto 5").unwrap();
+        assert_eq!((e.line, e.col), (1, 0));
+        // The second byte of ø
+        execute(&mut e, "goto 3").unwrap();
+        assert_eq!((e.line, e.col), (0, 1));
+        execute(&mut e, "go").unwrap();
+        assert_eq!((e.line, e.col), (0, 0));
+        execute(&mut e, "2go").unwrap();
+        assert_eq!((e.line, e.col), (0, 1));
+        execute(&mut e, "1go").unwrap();
+        assert_eq!((e.line, e.col), (0, 0));
+    }
+
+    #[test]
+    fn test_marks() {
+        let mut e = editor_with("a\nb\nc\nd");
+        execute(&mut e, "2mark x").unwrap();
+        execute(&mut e, "4k y").unwrap();
+        assert_eq!(parse_range(&e, "'x,'yd"), Ok((Some((2, 4)), None, "d")));
+        assert!(parse_range(&e, "'z").unwrap_err().starts_with("E20"));
+        assert!(execute(&mut e, "mark 1").unwrap_err().starts_with("E191"));
+        // Marks move with their lines
+        execute(&mut e, "1d").unwrap();
+        assert_eq!(parse_range(&e, "'x"), Ok((Some((1, 1)), None, "")));
+        execute(&mut e, "'y").unwrap();
+        assert_eq!(e.line, 2);
+        assert_eq!(e.mark_position('\''), Ok(Position::new(0, 0)));
+    }
+
+    #[test]
+    fn test_delete_yank_put() {
+        let mut e = editor_with("a\nb\nc\nd");
+        execute(&mut e, "2,3d x").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "a\nd");
+        assert_eq!(e.registers.get('x').unwrap().text, "b\nc\n");
+        execute(&mut e, "$pu x").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "a\nd\nb\nc");
+        assert_eq!(e.line, 3);
+        execute(&mut e, "1y").unwrap();
+        execute(&mut e, "0put").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "a\na\nd\nb\nc");
+        execute(&mut e, "1d 2").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "d\nb\nc");
+        assert!(execute(&mut e, "pu q").unwrap_err().starts_with("E353"));
+    }
+
+    #[test]
+    fn test_move_and_copy() {
+        let mut e = editor_with("1\n2\n3\n4");
+        execute(&mut e, "1,2m$").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "3\n4\n1\n2");
+        assert_eq!(e.line, 3);
+        execute(&mut e, "4m0").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "2\n3\n4\n1");
+        assert!(execute(&mut e, "1,3m2").unwrap_err().starts_with("E134"));
+        execute(&mut e, "1t.").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "2\n2\n3\n4\n1");
+        execute(&mut e, "%m0").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "2\n2\n3\n4\n1");
+        execute(&mut e, "1m0").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "2\n2\n3\n4\n1");
+        execute(&mut e, "%co0").unwrap();
+        assert_eq!(e.buffer.text.line_count(), 10);
+        let mut e = editor_with("a");
+        execute(&mut e, "%m0").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "a");
+    }
+
+    #[test]
+    fn test_join_and_undo() {
+        let mut e = editor_with("a\n  b\nc");
+        execute(&mut e, "%j").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "a b c");
+        execute(&mut e, "undo").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "a\n  b\nc");
+        execute(&mut e, "j!").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "a  b\nc");
+        execute(&mut e, "u").unwrap();
+        execute(&mut e, "red").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "a  b\nc");
+    }
+
+    #[test]
+    fn test_retab() {
+        let mut e = editor_with("\tif a {\n\t\tb  c;\n        d\n}");
+        execute(&mut e, "set et").unwrap();
+        execute(&mut e, "1,2retab 4").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "        if a {\n                b  c;\n        d\n}");
+        assert_eq!(e.opt_number("tabstop"), 4);
+        // With ! runs of spaces become tabs too
+        execute(&mut e, "set noet").unwrap();
+        execute(&mut e, "ret!").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "\t\tif a {\n\t\t\t\tb  c;\n\t\td\n}");
+        assert!(execute(&mut e, "retab x").is_err());
+    }
+
+    #[test]
+    fn test_checktime() {
+        use std::time::{Duration, SystemTime};
+
+        let path = std::env::temp_dir().join(format!("checktime-{}.txt", std::process::id()));
+        let name = path.to_str().unwrap();
+        fs::write(&path, "one\n").unwrap();
+        let mut e = Editor::new();
+        e.buffer = crate::buffer::Buffer::new("one\n", Some(name), &e.options);
+        e.buffer.mark_disk_time();
+        execute(&mut e, "checkt").unwrap();
+        assert_eq!(e.message, None);
+        let touch = |text : &str, secs : u64| {
+            fs::write(&path, text).unwrap();
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::now() + Duration::from_secs(secs)).unwrap();
+        };
+
+        touch("two\n", 10);
+        execute(&mut e, "checktime").unwrap();
+        assert!(e.message.take().unwrap().starts_with("W11"));
+        assert_eq!(e.buffer.text.get_text(), "one\n");
+        // Only reported once
+        execute(&mut e, "checktime").unwrap();
+        assert_eq!(e.message, None);
+
+        touch("three\n", 20);
+        execute(&mut e, "set autoread").unwrap();
+        execute(&mut e, "checktime").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "three\n");
+        assert!(!e.buffer.modified());
+        assert!(e.message.take().unwrap().ends_with("2L, 6B"));
+        execute(&mut e, "undo").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "one\n");
+
+        fs::remove_file(&path).unwrap();
+        execute(&mut e, "checktime").unwrap();
+        assert!(e.message.take().unwrap().starts_with("E211"));
+    }
+
+    #[test]
+    fn test_fileformat() {
+        let path = std::env::temp_dir().join(format!("fileformat-{}.txt", std::process::id()));
+        let name = path.to_str().unwrap();
+        let mut e = Editor::new();
+        e.read_buffer("a\r\nb\r\n", Some(name));
+        assert_eq!(e.buffer.text.get_text(), "a\nb\n");
+        assert_eq!(e.opt_str("fileformat"), "dos");
+        assert!(!e.buffer.modified());
+        execute(&mut e, "w").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\r\nb\r\n");
+        // Changing the format is a change to write
+        execute(&mut e, "set ff=mac").unwrap();
+        assert!(e.buffer.modified());
+        execute(&mut e, "w").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\rb\r");
+        assert!(execute(&mut e, "set ff=vms").is_err());
+        // Mixed endings are all written the way most lines end
+        e.read_buffer("a\r\nb\nc\r\n", Some(name));
+        assert_eq!(e.opt_str("fileformat"), "dos");
+        assert!(e.message.take().unwrap().contains("mixed line endings"));
+        execute(&mut e, "w").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\r\nb\r\nc\r\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_nomodifiable() {
+        let mut e = editor_with("a\nb");
+        execute(&mut e, "set noma").unwrap();
+        assert!(execute(&mut e, "d").unwrap_err().starts_with("E21"));
+        assert!(execute(&mut e, "t0").unwrap_err().starts_with("E21"));
+        execute(&mut e, "u").unwrap();
+        assert!(e.message.take().unwrap().starts_with("E21"));
+        assert_eq!(e.buffer.text.get_text(), "a\nb");
+        execute(&mut e, "set ma").unwrap();
+        execute(&mut e, "d").unwrap();
+        assert_eq!(e.buffer.text.get_text(), "b");
+    }
+}
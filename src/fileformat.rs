@@ -0,0 +1,61 @@
+/// Line endings written for each 'fileformat'.
+pub const FORMATS : &[(&str, &str)] = &[("unix", "\n"), ("dos", "\r\n"), ("mac", "\r")];
+
+/// The format of `contents` by how its lines end, and whether some end
+/// otherwise: both CR LF and a bare LF. A CR alone only ends lines in a
+/// file without any LF; elsewhere it is text. None without line breaks.
+pub fn detect(contents : &str) -> Option<(&'static str, bool)> {
+    let lf = contents.matches('\n').count();
+    if lf == 0 {
+        return contents.contains('\r').then_some(("mac", false));
+    }
+    let crlf = contents.matches("\r\n").count();
+    // Most lines decide, and a tie goes to unix
+    let format = if crlf > lf - crlf { "dos" } else { "unix" };
+    Some((format, crlf > 0 && crlf < lf))
+}
+
+/// `contents` with each line ending in a single `\n`, the way the buffer
+/// keeps it.
+pub fn to_buffer(contents : &str) -> String {
+    match contents.contains('\n') {
+        true => contents.replace("\r\n", "\n"),
+        false => contents.replace('\r', "\n"),
+    }
+}
+
+/// Buffer text `text` with its line breaks written for `format`.
+pub fn to_file(text : &str, format : &str) -> String {
+    match FORMATS.iter().find(|(f, _)| *f == format) {
+        Some((_, ending)) if *ending != "\n" => text.replace('\n', ending),
+        _ => String::from(text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect("a\nb\n"), Some(("unix", false)));
+        assert_eq!(detect("a\r\nb\r\n"), Some(("dos", false)));
+        assert_eq!(detect("a\rb\r"), Some(("mac", false)));
+        assert_eq!(detect("no break"), None);
+        // Mixed: the most common ending wins, unix on a tie
+        assert_eq!(detect("a\r\nb\r\nc\n"), Some(("dos", true)));
+        assert_eq!(detect("a\r\nb\n"), Some(("unix", true)));
+        // A CR inside a line is text when LF ends the lines
+        assert_eq!(detect("a\rb\n"), Some(("unix", false)));
+    }
+
+    #[test]
+    fn test_convert() {
+        assert_eq!(to_buffer("a\r\nb\nc\r\n"), "a\nb\nc\n");
+        assert_eq!(to_buffer("a\rb\r"), "a\nb\n");
+        assert_eq!(to_buffer("a\rb\n"), "a\rb\n");
+        assert_eq!(to_file("a\nb\n", "dos"), "a\r\nb\r\n");
+        assert_eq!(to_file("a\nb\n", "mac"), "a\rb\r");
+        assert_eq!(to_file("a\nb\n", "unix"), "a\nb\n");
+    }
+}
@@ -0,0 +1,78 @@
+// File types by the extension of the file name
+const EXTENSIONS : &[(&str, &str)] = &[
+    ("bash", "sh"), ("c", "c"), ("h", "c"), ("js", "javascript"), ("json", "json"),
+    ("markdown", "markdown"), ("md", "markdown"), ("mk", "make"), ("pl", "perl"),
+    ("py", "python"), ("pyw", "python"), ("rb", "ruby"), ("rs", "rust"), ("sh", "sh"),
+    ("toml", "toml"), ("vim", "vim"), ("yaml", "yaml"), ("yml", "yaml"), ("zsh", "sh"),
+];
+
+// File types by the whole file name
+const NAMES : &[(&str, &str)] = &[
+    (".bash_profile", "sh"), (".bashrc", "sh"), (".profile", "sh"), (".zshrc", "sh"),
+    ("Cargo.lock", "toml"), ("Containerfile", "dockerfile"), ("Dockerfile", "dockerfile"),
+    ("GNUmakefile", "make"), ("Makefile", "make"), ("makefile", "make"),
+];
+
+// File types by the program a `#!` line runs, without a version
+const INTERPRETERS : &[(&str, &str)] = &[
+    ("bash", "sh"), ("dash", "sh"), ("ksh", "sh"), ("node", "javascript"), ("perl", "perl"),
+    ("python", "python"), ("ruby", "ruby"), ("sh", "sh"), ("zsh", "sh"),
+];
+
+fn lookup(table : &[(&str, &'static str)], key : &str) -> Option<&'static str> {
+    table.iter().find(|(k, _)| *k == key).map(|(_, ft)| *ft)
+}
+
+/// The file type of a file named `name` starting with `first_line`: by
+/// its name, its extension, or the program its `#!` line runs.
+pub fn detect(name : Option<&str>, first_line : &str) -> Option<&'static str> {
+    let base = name.map(|n| n.rsplit('/').next().unwrap_or(n));
+    let by_name = base.and_then(|base| {
+        let ext = base.rsplit_once('.').map(|(_, ext)| ext);
+        match lookup(NAMES, base) {
+            _ if base.starts_with("Dockerfile.") => Some("dockerfile"),
+            Some(ft) => Some(ft),
+            None => ext.and_then(|ext| lookup(EXTENSIONS, ext)),
+        }
+    });
+    by_name.or_else(|| shebang(first_line))
+}
+
+// The file type for the program run by a `#!` line, going past `env` and
+// its flags
+fn shebang(line : &str) -> Option<&'static str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|w| !w.starts_with('-'))?;
+    }
+    lookup(INTERPRETERS, program.trim_end_matches(|c : char| c.is_ascii_digit() || c == '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_by_name() {
+        assert_eq!(detect(Some("src/main.rs"), ""), Some("rust"));
+        assert_eq!(detect(Some("notes.md"), ""), Some("markdown"));
+        assert_eq!(detect(Some("/home/me/Makefile"), ""), Some("make"));
+        assert_eq!(detect(Some("Dockerfile"), ""), Some("dockerfile"));
+        assert_eq!(detect(Some("Dockerfile.dev"), ""), Some("dockerfile"));
+        assert_eq!(detect(Some(".bashrc"), ""), Some("sh"));
+        assert_eq!(detect(Some("README"), ""), None);
+        assert_eq!(detect(None, ""), None);
+    }
+
+    #[test]
+    fn test_detect_by_shebang() {
+        assert_eq!(detect(Some("run"), "#!/bin/sh"), Some("sh"));
+        assert_eq!(detect(Some("run"), "#!/usr/bin/env python3"), Some("python"));
+        assert_eq!(detect(Some("run"), "#!/usr/bin/env -S python3.11 -u"), Some("python"));
+        assert_eq!(detect(None, "#! /usr/bin/perl -w"), Some("perl"));
+        assert_eq!(detect(Some("run"), "#!/usr/bin/awk -f"), None);
+        // The name goes first
+        assert_eq!(detect(Some("x.rs"), "#!/bin/sh"), Some("rust"));
+    }
+}
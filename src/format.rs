@@ -0,0 +1,77 @@
+/// Join `lines` into one. With `spaces`, leading whitespace of the joined
+/// lines is removed and a space put between them, like vi's `J`: none when
+/// the line so far ends in white space or the next line starts with `)`,
+/// and two after a sentence end with `joinspaces`. Without `spaces` they
+/// are joined as they are, like `gJ`. Also returns the column where the
+/// last line was joined.
+pub fn join(lines : &[String], spaces : bool, joinspaces : bool) -> (String, usize) {
+    let mut joined = lines.first().cloned().unwrap_or_default();
+    let mut col = 0;
+    for line in lines.iter().skip(1) {
+        if !spaces {
+            col = joined.chars().count();
+            joined.push_str(line);
+            continue;
+        }
+        let line = line.trim_start_matches([' ', '\t']);
+        col = joined.chars().count();
+        let gap = match joined.chars().last() {
+            _ if line.is_empty() || line.starts_with(')') => "",
+            None | Some(' ') | Some('\t') => "",
+            Some('.' | '!' | '?') if joinspaces => "  ",
+            _ => " ",
+        };
+        joined.push_str(gap);
+        joined.push_str(line);
+    }
+    (joined, col)
+}
+
+/// Text pasted from the terminal as it goes in the buffer, where the
+/// terminal sends line breaks as the Enter key does: CR LF and a lone CR
+/// both end a line. Also returns the offset of its last char, where the
+/// cursor goes after pasting in normal mode.
+pub fn pasted(s : &str) -> (String, usize) {
+    let text = s.replace("\r\n", "\n").replace('\r', "\n");
+    let last = text.chars().count().saturating_sub(1);
+    (text, last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(v : &[&str]) -> Vec<String> {
+        v.iter().map(|s| String::from(*s)).collect()
+    }
+
+    #[test]
+    fn test_join_with_spaces() {
+        assert_eq!(join(&lines(&["foo", "   bar"]), true, false), (String::from("foo bar"), 3));
+        assert_eq!(join(&lines(&["a", "b", "c"]), true, false), (String::from("a b c"), 3));
+        assert_eq!(join(&lines(&["foo ", "bar"]), true, false), (String::from("foo bar"), 4));
+        assert_eq!(join(&lines(&["f(x", "  )"]), true, false), (String::from("f(x)"), 3));
+        assert_eq!(join(&lines(&["foo", ""]), true, false), (String::from("foo"), 3));
+        assert_eq!(join(&lines(&["", "\tbar"]), true, false), (String::from("bar"), 0));
+    }
+
+    #[test]
+    fn test_join_sentence_end() {
+        assert_eq!(join(&lines(&["End.", "Next"]), true, false).0, "End. Next");
+        assert_eq!(join(&lines(&["End.", "Next"]), true, true).0, "End.  Next");
+    }
+
+    #[test]
+    fn test_join_as_is() {
+        assert_eq!(join(&lines(&["foo", "  bar"]), false, false), (String::from("foo  bar"), 3));
+    }
+
+    #[test]
+    fn test_pasted() {
+        assert_eq!(pasted("a\r\nb\rc\n"), (String::from("a\nb\nc\n"), 5));
+        // Commands are text like any other
+        assert_eq!(pasted("dd:q\r"), (String::from("dd:q\n"), 4));
+        assert_eq!(pasted("x"), (String::from("x"), 0));
+        assert_eq!(pasted(""), (String::new(), 0));
+    }
+}
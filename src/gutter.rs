@@ -0,0 +1,58 @@
+/// Widths of the columns left of the text: fold column, sign column and
+/// line numbers, in that order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Gutter {
+    pub fold : usize,
+    pub sign : usize,
+    pub number : usize,
+}
+
+/// Cells a sign takes in the sign column.
+pub const SIGN_WIDTH : usize = 2;
+
+impl Gutter {
+    pub fn width(&self) -> usize {
+        self.fold + self.sign + self.number
+    }
+}
+
+/// Cells for line numbers of a buffer with `line_count` lines: at least
+/// `numberwidth`, and room for the number and a space after it.
+pub fn number_width(line_count : usize, numberwidth : usize) -> usize {
+    (line_count.max(1).ilog10() as usize + 2).max(numberwidth)
+}
+
+/// Text in the number column for 0-based `line` with the cursor on
+/// `cursor`. With `relative` the distance to the cursor line is shown, and
+/// with `number` as well the cursor line shows its own number on the left.
+pub fn number_text(line : usize, cursor : usize, number : bool, relative : bool, width : usize) -> String {
+    let w = width.saturating_sub(1);
+    match (number, relative) {
+        (true, true) if line == cursor => format!("{:<w$} ", line + 1),
+        (_, true) => format!("{:>w$} ", line.abs_diff(cursor)),
+        _ => format!("{:>w$} ", line + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_width() {
+        assert_eq!(number_width(1, 4), 4);
+        assert_eq!(number_width(999, 4), 4);
+        assert_eq!(number_width(1000, 4), 5);
+        assert_eq!(number_width(5, 1), 2);
+    }
+
+    #[test]
+    fn test_number_text() {
+        assert_eq!(number_text(4, 2, true, false, 4), "  5 ");
+        assert_eq!(number_text(4, 2, false, true, 4), "  2 ");
+        assert_eq!(number_text(2, 2, false, true, 4), "  0 ");
+        // Hybrid: the cursor line shows its number on the left
+        assert_eq!(number_text(2, 2, true, true, 4), "3   ");
+        assert_eq!(number_text(0, 2, true, true, 4), "  2 ");
+    }
+}
@@ -0,0 +1,392 @@
+use std::env;
+
+use crossterm::style::Color;
+
+use crate::screen::Style;
+
+/// A colour given to `:highlight`: one of the 256 terminal colours, or red,
+/// green and blue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TermColor {
+    Index(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// How many colours the terminal shows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Depth {
+    Colors16,
+    Colors256,
+    TrueColor,
+}
+
+impl Depth {
+    /// Go by `$COLORTERM` for truecolor, and `$TERM` for 256 colours.
+    pub fn detect() -> Depth {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+        match (colorterm.as_str(), term.contains("256color")) {
+            ("truecolor" | "24bit", _) => Depth::TrueColor,
+            (_, true) => Depth::Colors256,
+            _ => Depth::Colors16,
+        }
+    }
+}
+
+// Colour names `:highlight` knows, with their terminal colour
+const COLOR_NAMES : &[(&str, u8)] = &[
+    ("black", 0), ("darkred", 1), ("darkgreen", 2), ("darkyellow", 3), ("brown", 3),
+    ("darkblue", 4), ("darkmagenta", 5), ("darkcyan", 6), ("gray", 7), ("grey", 7),
+    ("lightgray", 7), ("lightgrey", 7), ("darkgray", 8), ("darkgrey", 8),
+    ("red", 9), ("lightred", 9), ("green", 10), ("lightgreen", 10),
+    ("yellow", 11), ("lightyellow", 11), ("blue", 12), ("lightblue", 12),
+    ("magenta", 13), ("lightmagenta", 13), ("cyan", 14), ("lightcyan", 14), ("white", 15),
+];
+
+// The first 16 terminal colours as xterm shows them
+const BASIC_RGB : [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0), (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0), (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+];
+
+// Levels of each of red, green and blue in the 6x6x6 colour cube
+const CUBE : [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// Parse a colour: a name, a number up to 255 or `#rrggbb`. NONE gives
+/// None.
+pub fn parse_color(s : &str) -> Result<Option<TermColor>, String> {
+    let bad = || format!("E421: Color name or number not recognized: {}", s);
+    if s.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    if let Some(hex) = s.strip_prefix('#') {
+        let n = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6).ok_or_else(bad)?;
+        return Ok(Some(TermColor::Rgb((n >> 16) as u8, (n >> 8) as u8, n as u8)));
+    }
+    if let Ok(n) = s.parse::<u8>() {
+        return Ok(Some(TermColor::Index(n)));
+    }
+    let lower = s.to_ascii_lowercase();
+    match COLOR_NAMES.iter().find(|(name, _)| *name == lower) {
+        Some((_, n)) => Ok(Some(TermColor::Index(*n))),
+        None => Err(bad()),
+    }
+}
+
+// Red, green and blue of terminal colour `n`
+fn index_rgb(n : u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => BASIC_RGB[n as usize],
+        16..=231 => {
+            let n = n - 16;
+            (CUBE[n as usize / 36], CUBE[n as usize / 6 % 6], CUBE[n as usize % 6])
+        }
+        _ => {
+            let level = 8 + 10 * (n - 232);
+            (level, level, level)
+        }
+    }
+}
+
+fn distance((r1, g1, b1) : (u8, u8, u8), (r2, g2, b2) : (u8, u8, u8)) -> u32 {
+    let d = |a : u8, b : u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+// The terminal colour closest to `rgb` among colours `from` to 255
+fn nearest(rgb : (u8, u8, u8), from : u8, to : u8) -> u8 {
+    (from..=to).min_by_key(|n| distance(index_rgb(*n), rgb)).unwrap_or(from)
+}
+
+impl TermColor {
+    /// The colour to draw with on a terminal showing `depth` colours,
+    /// degraded to the closest one it has.
+    pub fn to_color(self, depth : Depth) -> Color {
+        let n = match (self, depth) {
+            (TermColor::Rgb(r, g, b), Depth::TrueColor) => return Color::Rgb { r, g, b },
+            (TermColor::Rgb(r, g, b), Depth::Colors256) => nearest((r, g, b), 16, 255),
+            (TermColor::Rgb(r, g, b), Depth::Colors16) => nearest((r, g, b), 0, 15),
+            (TermColor::Index(n), Depth::Colors16) if n > 15 => nearest(index_rgb(n), 0, 15),
+            (TermColor::Index(n), _) => n,
+        };
+        match n {
+            0 => Color::Black,
+            1 => Color::DarkRed,
+            2 => Color::DarkGreen,
+            3 => Color::DarkYellow,
+            4 => Color::DarkBlue,
+            5 => Color::DarkMagenta,
+            6 => Color::DarkCyan,
+            7 => Color::Grey,
+            8 => Color::DarkGrey,
+            9 => Color::Red,
+            10 => Color::Green,
+            11 => Color::Yellow,
+            12 => Color::Blue,
+            13 => Color::Magenta,
+            14 => Color::Cyan,
+            15 => Color::White,
+            n => Color::AnsiValue(n),
+        }
+    }
+}
+
+fn describe_color(c : TermColor) -> String {
+    match c {
+        TermColor::Index(n) => n.to_string(),
+        TermColor::Rgb(r, g, b) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+    }
+}
+
+/// How a highlight group is drawn, or the group it links to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Highlight {
+    pub fg : Option<TermColor>,
+    pub bg : Option<TermColor>,
+    pub bold : bool,
+    pub italic : bool,
+    pub underline : bool,
+    pub reverse : bool,
+    pub link : Option<String>,
+}
+
+impl Highlight {
+    fn describe(&self) -> String {
+        if let Some(to) = &self.link {
+            return format!("links to {}", to);
+        }
+        let mut parts = Vec::new();
+        let attrs : Vec<&str> = [(self.bold, "bold"), (self.italic, "italic"), (self.underline, "underline"), (self.reverse, "reverse")]
+            .iter().filter(|(on, _)| *on).map(|(_, name)| *name).collect();
+        if !attrs.is_empty() {
+            parts.push(format!("gui={}", attrs.join(",")));
+        }
+        if let Some(fg) = self.fg {
+            parts.push(format!("guifg={}", describe_color(fg)));
+        }
+        if let Some(bg) = self.bg {
+            parts.push(format!("guibg={}", describe_color(bg)));
+        }
+        match parts.is_empty() {
+            true => String::from("cleared"),
+            false => parts.join(" "),
+        }
+    }
+}
+
+// Groups as they are before a colour scheme changes them: the name,
+// terminal colours for the text and background, attributes, and the group
+// it links to
+type DefaultHighlight = (&'static str, Option<u8>, Option<u8>, &'static str, Option<&'static str>);
+const DEFAULTS : &[DefaultHighlight] = &[
+    ("Normal", None, None, "", None),
+    ("Comment", Some(6), None, "", None),
+    ("Constant", Some(5), None, "", None),
+    ("String", None, None, "", Some("Constant")),
+    ("Number", None, None, "", Some("Constant")),
+    ("Identifier", Some(14), None, "", None),
+    ("Keyword", Some(3), None, "", None),
+    ("Type", Some(2), None, "", None),
+    ("PreProc", Some(13), None, "", None),
+    ("Special", Some(9), None, "", None),
+    ("Title", Some(13), None, "bold", None),
+    ("Visual", None, None, "reverse", None),
+    ("Search", Some(0), Some(11), "", None),
+    ("MatchParen", None, Some(6), "", None),
+    ("NonText", Some(12), None, "", None),
+    ("SpecialKey", Some(12), None, "", None),
+    ("LineNr", Some(3), None, "", None),
+    ("CursorLineNr", Some(11), None, "bold", None),
+    ("CursorLine", None, None, "underline", None),
+    ("ColorColumn", None, Some(1), "", None),
+    ("SignColumn", None, None, "", None),
+    ("FoldColumn", Some(4), None, "", None),
+    ("StatusLine", None, None, "bold,reverse", None),
+];
+
+/// The highlight groups, and how many colours they are drawn with.
+pub struct Highlights {
+    groups : Vec<(String, Highlight)>,
+    pub depth : Depth,
+    pub scheme : Option<String>,    // Colour scheme last loaded
+}
+
+impl Highlights {
+    pub fn new(depth : Depth) -> Highlights {
+        let mut h = Highlights { groups : Vec::new(), depth, scheme : None };
+        h.reset();
+        h
+    }
+
+    /// Set every group back to how it is before any colour scheme.
+    pub fn reset(&mut self) {
+        self.groups = DEFAULTS.iter().map(|(name, fg, bg, attrs, link)| {
+            let mut h = Highlight {
+                fg : fg.map(TermColor::Index),
+                bg : bg.map(TermColor::Index),
+                link : link.map(String::from),
+                ..Highlight::default()
+            };
+            attrs.split(',').filter(|a| !a.is_empty()).for_each(|a| { set_attr(&mut h, a); });
+            (String::from(*name), h)
+        }).collect();
+        self.scheme = None;
+    }
+
+    /// Group `name`, in any case.
+    pub fn get(&self, name : &str) -> Option<&Highlight> {
+        self.groups.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, h)| h)
+    }
+
+    // Group `name`, added when new
+    fn get_mut(&mut self, name : &str) -> &mut Highlight {
+        let n = match self.groups.iter().position(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some(n) => n,
+            None => {
+                self.groups.push((String::from(name), Highlight::default()));
+                self.groups.len() - 1
+            }
+        };
+        &mut self.groups[n].1
+    }
+
+    /// Change group `name` by arguments like `guifg=#ffffff gui=bold`. The
+    /// group no longer links to another.
+    pub fn set(&mut self, name : &str, args : &str) -> Result<(), String> {
+        let mut h = self.get(name).cloned().unwrap_or_default();
+        h.link = None;
+        for arg in args.split_whitespace() {
+            let illegal = || format!("E423: Illegal argument: {}", arg);
+            let (key, value) = arg.split_once('=').ok_or_else(illegal)?;
+            match key.to_ascii_lowercase().as_str() {
+                "fg" | "guifg" | "ctermfg" => h.fg = parse_color(value)?,
+                "bg" | "guibg" | "ctermbg" => h.bg = parse_color(value)?,
+                "gui" | "cterm" | "term" => {
+                    h = Highlight { bold : false, italic : false, underline : false, reverse : false, ..h };
+                    for attr in value.split(',') {
+                        if !attr.eq_ignore_ascii_case("none") && !set_attr(&mut h, &attr.to_ascii_lowercase()) {
+                            return Err(illegal());
+                        }
+                    }
+                }
+                _ => return Err(illegal()),
+            }
+        }
+        *self.get_mut(name) = h;
+        Ok(())
+    }
+
+    /// Make group `from` drawn like group `to`, or like nothing when `to`
+    /// is NONE.
+    pub fn link(&mut self, from : &str, to : &str) {
+        let link = Some(String::from(to)).filter(|to| !to.eq_ignore_ascii_case("none"));
+        *self.get_mut(from) = Highlight { link, ..Highlight::default() };
+    }
+
+    /// Remove all highlighting of group `name`.
+    pub fn clear(&mut self, name : &str) {
+        *self.get_mut(name) = Highlight::default();
+    }
+
+    /// How group `name` is shown by `:highlight`.
+    pub fn describe(&self, name : &str) -> Option<String> {
+        let (name, h) = self.groups.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))?;
+        Some(format!("{:<15}xxx {}", name, h.describe()))
+    }
+
+    /// All groups as shown by `:highlight`.
+    pub fn describe_all(&self) -> Vec<String> {
+        self.groups.iter().filter_map(|(name, _)| self.describe(name)).collect()
+    }
+
+    /// How to draw group `name`, following links. Unknown groups are drawn
+    /// like the text around them.
+    pub fn style(&self, name : &str) -> Style {
+        let mut h = self.get(name);
+        // Links going round in a circle end somewhere
+        for _ in 0..self.groups.len() {
+            match h.and_then(|h| h.link.as_deref()) {
+                Some(to) => h = self.get(to),
+                None => break,
+            }
+        }
+        let h = match h {
+            Some(h) if h.link.is_none() => h,
+            _ => return Style::default(),
+        };
+        Style {
+            fg : h.fg.map(|c| c.to_color(self.depth)),
+            bg : h.bg.map(|c| c.to_color(self.depth)),
+            bold : h.bold,
+            italic : h.italic,
+            underline : h.underline,
+            reverse : h.reverse,
+        }
+    }
+}
+
+// Turn on attribute `attr`. Returns false for one that is not known.
+fn set_attr(h : &mut Highlight, attr : &str) -> bool {
+    match attr {
+        "bold" => h.bold = true,
+        "italic" => h.italic = true,
+        "underline" => h.underline = true,
+        "reverse" | "inverse" => h.reverse = true,
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("#1a2B3c"), Ok(Some(TermColor::Rgb(0x1a, 0x2b, 0x3c))));
+        assert_eq!(parse_color("208"), Ok(Some(TermColor::Index(208))));
+        assert_eq!(parse_color("DarkBlue"), Ok(Some(TermColor::Index(4))));
+        assert_eq!(parse_color("NONE"), Ok(None));
+        assert!(parse_color("#12345").unwrap_err().starts_with("E421"));
+        assert!(parse_color("mauve").is_err());
+        assert!(parse_color("256").is_err());
+    }
+
+    #[test]
+    fn test_degrade() {
+        let orange = TermColor::Rgb(255, 135, 0);
+        assert_eq!(orange.to_color(Depth::TrueColor), Color::Rgb { r : 255, g : 135, b : 0 });
+        assert_eq!(orange.to_color(Depth::Colors256), Color::AnsiValue(208));
+        assert_eq!(TermColor::Rgb(10, 10, 10).to_color(Depth::Colors256), Color::AnsiValue(232));
+        assert_eq!(TermColor::Rgb(250, 10, 10).to_color(Depth::Colors16), Color::Red);
+        assert_eq!(TermColor::Index(208).to_color(Depth::Colors256), Color::AnsiValue(208));
+        assert_eq!(TermColor::Index(21).to_color(Depth::Colors16), Color::DarkBlue);
+        assert_eq!(TermColor::Index(4).to_color(Depth::Colors16), Color::DarkBlue);
+    }
+
+    #[test]
+    fn test_set_and_link() {
+        let mut h = Highlights::new(Depth::TrueColor);
+        assert_eq!(h.style("String"), h.style("Constant"));
+        h.set("string", "guifg=#00ff00 gui=bold,italic").unwrap();
+        let style = h.style("String");
+        assert_eq!(style.fg, Some(Color::Rgb { r : 0, g : 255, b : 0 }));
+        assert!(style.bold && style.italic && !style.underline);
+        assert_eq!(h.describe("String").unwrap(), "String         xxx gui=bold,italic guifg=#00ff00");
+        assert!(h.set("String", "guifg").unwrap_err().starts_with("E423"));
+        assert!(h.set("String", "gui=blinking").unwrap_err().starts_with("E423"));
+
+        // New groups, links between them, and links going round
+        h.link("MyGroup", "Comment");
+        assert_eq!(h.style("mygroup"), h.style("Comment"));
+        assert_eq!(h.describe("MyGroup").unwrap(), "MyGroup        xxx links to Comment");
+        h.link("A", "B");
+        h.link("B", "A");
+        assert_eq!(h.style("A"), Style::default());
+        h.clear("Comment");
+        assert_eq!(h.describe("Comment").unwrap(), "Comment        xxx cleared");
+        h.reset();
+        assert!(h.get("MyGroup").is_none());
+        assert_eq!(h.style("Comment").fg, Some(Color::DarkCyan));
+    }
+}
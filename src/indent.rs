@@ -0,0 +1,262 @@
+use crate::width;
+
+/// Screen column of char `col` in `line`, with tabs expanded to `ts` columns
+/// and wide chars taking two.
+pub fn virtual_col(line : &str, col : usize, ts : usize) -> usize {
+    width::cell_of_col(line, col, ts.max(1))
+}
+
+// Whitespace filling the screen columns from `from` to `to`, using tabs
+// where they fit unless `expandtab` is set
+fn fill(from : usize, to : usize, ts : usize, expandtab : bool) -> String {
+    let ts = ts.max(1);
+    let mut s = String::new();
+    let mut vcol = from;
+    if !expandtab {
+        while (vcol / ts + 1) * ts <= to {
+            s.push('\t');
+            vcol = (vcol / ts + 1) * ts;
+        }
+    }
+    s.extend(std::iter::repeat_n(' ', to.saturating_sub(vcol)));
+    s
+}
+
+// Step used by Tab and Backspace: 'softtabstop', or 'shiftwidth' when it
+// is negative, or 'tabstop' when it is zero
+fn soft_step(ts : usize, sts : i64, sw : usize) -> usize {
+    match sts {
+        0 => ts.max(1),
+        n if n < 0 => sw.max(1),
+        n => n as usize,
+    }
+}
+
+/// Edit made by Tab at char `col` of `line`: the number of chars to delete
+/// before the cursor and the text to insert in their place.
+pub fn soft_tab(line : &str, col : usize, ts : usize, sts : i64, sw : usize, expandtab : bool) -> (usize, String) {
+    if sts == 0 && !expandtab {
+        return (0, String::from("\t"));
+    }
+    let vcol = virtual_col(line, col, ts);
+    let step = soft_step(ts, sts, sw);
+    let target = (vcol / step + 1) * step;
+    if expandtab {
+        return (0, fill(vcol, target, ts, true));
+    }
+
+    // Rebuild the whitespace before the cursor with as many tabs as fit
+    let chars : Vec<char> = line.chars().take(col).collect();
+    let ws_start = chars.iter().rposition(|c| *c != ' ' && *c != '\t').map_or(0, |i| i + 1);
+    let from = virtual_col(line, ws_start, ts);
+    (col - ws_start, fill(from, target, ts, false))
+}
+
+/// Edit made by Backspace at char `col` of `line` when 'softtabstop' is set
+/// and the cursor follows spaces: chars to delete, and spaces to put back
+/// when a deleted tab overshoots the previous stop.
+pub fn soft_backspace(line : &str, col : usize, ts : usize, sts : i64, sw : usize) -> Option<(usize, String)> {
+    let chars : Vec<char> = line.chars().take(col).collect();
+    if sts == 0 || chars.last() != Some(&' ') {
+        return None;
+    }
+    let vcol = virtual_col(line, col, ts);
+    let step = soft_step(ts, sts, sw);
+    let target = (vcol - 1) / step * step;
+    let mut start = col;
+    while start > 0 && (chars[start - 1] == ' ' || chars[start - 1] == '\t')
+        && virtual_col(line, start, ts) > target {
+        start -= 1;
+    }
+    let left = virtual_col(line, start, ts);
+    Some((col - start, fill(left, target, ts, true)))
+}
+
+/// Screen columns taken by the indent of `line`.
+pub fn indent_width(line : &str, ts : usize) -> usize {
+    let lead = line.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+    virtual_col(line, lead, ts)
+}
+
+/// `line` with its indent replaced by one `width` columns wide.
+pub fn set_indent(line : &str, width : usize, ts : usize, expandtab : bool) -> String {
+    let body = line.trim_start_matches([' ', '\t']);
+    if body.is_empty() {
+        return String::new();
+    }
+    fill(0, width, ts, expandtab) + body
+}
+
+/// Indent of `line` after shifting it by `amount` times `sw` columns, to the
+/// right when positive. With `round`, the indent goes to a multiple of `sw`.
+pub fn shifted_width(line : &str, amount : i64, sw : usize, ts : usize, round : bool) -> usize {
+    let sw = sw.max(1) as i64;
+    let width = indent_width(line, ts) as i64;
+    let new = match round {
+        true if amount < 0 => ((width + sw - 1) / sw + amount) * sw,
+        true => (width / sw + amount) * sw,
+        false => width + amount * sw,
+    };
+    new.max(0) as usize
+}
+
+/// Indent for `line` when the line before it is `prev`: the same as `prev`,
+/// one `sw` deeper after an opening bracket and one less before a closing
+/// one.
+pub fn auto_indent_width(prev : &str, line : &str, sw : usize, ts : usize) -> usize {
+    let mut width = indent_width(prev, ts);
+    if prev.trim_end().ends_with(['{', '(', '[']) {
+        width += sw;
+    }
+    if line.trim_start().starts_with(['}', ')', ']']) {
+        width = width.saturating_sub(sw);
+    }
+    width
+}
+
+/// Indent 'smartindent' gives a new line holding `line` after `prev`: one
+/// `sw` deeper when `prev` ends in one of `openers`, and one less when
+/// `line` starts with a closing brace.
+pub fn smart_indent_width(prev : &str, line : &str, sw : usize, ts : usize, openers : &str) -> usize {
+    let mut width = indent_width(prev, ts);
+    if prev.trim_end().ends_with(|c| openers.contains(c)) {
+        width += sw;
+    }
+    if line.trim_start().starts_with('}') {
+        width = width.saturating_sub(sw);
+    }
+    width
+}
+
+/// Whitespace for an indent `width` columns wide.
+pub fn indent_text(width : usize, ts : usize, expandtab : bool) -> String {
+    fill(0, width, ts, expandtab)
+}
+
+/// `line` with each run of blanks holding a tab redone for tabstop
+/// `new_ts`, keeping the screen columns it covers with tabstop `ts`. With
+/// `all`, runs of spaces alone are redone too.
+pub fn retab(line : &str, ts : usize, new_ts : usize, expandtab : bool, all : bool) -> String {
+    let ts = ts.max(1);
+    let mut s = String::new();
+    let mut run = String::new();
+    let mut vcol = 0;
+    let mut start = 0;
+    for c in line.chars().chain(std::iter::once('\n')) {
+        if c == ' ' || c == '\t' {
+            if run.is_empty() {
+                start = vcol;
+            }
+            run.push(c);
+            vcol = if c == '\t' { (vcol / ts + 1) * ts } else { vcol + 1 };
+            continue;
+        }
+        match all || run.contains('\t') {
+            true if !run.is_empty() => s.push_str(&fill(start, vcol, new_ts, expandtab)),
+            _ => s.push_str(&run),
+        }
+        run.clear();
+        if c != '\n' {
+            s.push(c);
+            vcol += width::grapheme_width(c.encode_utf8(&mut [0; 4]));
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_col() {
+        assert_eq!(virtual_col("abc", 2, 8), 2);
+        assert_eq!(virtual_col("\tx", 1, 8), 8);
+        assert_eq!(virtual_col("ab\tx", 3, 4), 4);
+        assert_eq!(virtual_col("ab\tx", 4, 4), 5);
+        assert_eq!(virtual_col("日本", 2, 8), 4);
+    }
+
+    #[test]
+    fn test_soft_tab_plain_tab() {
+        assert_eq!(soft_tab("ab", 2, 8, 0, 8, false), (0, String::from("\t")));
+    }
+
+    #[test]
+    fn test_soft_tab_expandtab() {
+        assert_eq!(soft_tab("ab", 2, 4, 0, 4, true), (0, String::from("  ")));
+        assert_eq!(soft_tab("abcd", 4, 8, 4, 4, true), (0, String::from("    ")));
+        assert_eq!(soft_tab("", 0, 8, -1, 2, true), (0, String::from("  ")));
+        // Wide chars take two columns each
+        assert_eq!(soft_tab("日本", 2, 8, 0, 8, true), (0, String::from("    ")));
+    }
+
+    #[test]
+    fn test_soft_tab_mixes_tabs_and_spaces() {
+        // Two 4-column soft tabs make one real tab with tabstop 8
+        assert_eq!(soft_tab("", 0, 8, 4, 4, false), (0, String::from("    ")));
+        assert_eq!(soft_tab("    ", 4, 8, 4, 4, false), (4, String::from("\t")));
+        assert_eq!(soft_tab("\t", 1, 8, 4, 4, false), (1, String::from("\t    ")));
+    }
+
+    #[test]
+    fn test_soft_backspace() {
+        assert_eq!(soft_backspace("        ", 8, 8, 4, 4), Some((4, String::new())));
+        assert_eq!(soft_backspace("      ", 6, 8, 4, 4), Some((2, String::new())));
+        assert_eq!(soft_backspace("\t  ", 3, 8, 4, 4), Some((2, String::new())));
+        assert_eq!(soft_backspace("\t    ", 5, 8, 4, 4), Some((4, String::new())));
+        assert_eq!(soft_backspace("ab", 2, 8, 4, 4), None);
+        assert_eq!(soft_backspace("    ", 4, 8, 0, 4), None);
+    }
+
+    #[test]
+    fn test_set_indent() {
+        assert_eq!(set_indent("  x", 8, 8, false), "\tx");
+        assert_eq!(set_indent("\tx", 4, 8, false), "    x");
+        assert_eq!(set_indent("\tx", 0, 8, false), "x");
+        assert_eq!(set_indent("x", 10, 8, true), "          x");
+        assert_eq!(set_indent("   ", 4, 8, true), "");
+    }
+
+    #[test]
+    fn test_shifted_width() {
+        assert_eq!(shifted_width("x", 1, 4, 8, false), 4);
+        assert_eq!(shifted_width("\tx", -1, 4, 8, false), 4);
+        assert_eq!(shifted_width("  x", -1, 4, 8, false), 0);
+        assert_eq!(shifted_width("   x", 2, 4, 8, false), 11);
+        // 'shiftround' first goes to the nearest multiple
+        assert_eq!(shifted_width("   x", 1, 4, 8, true), 4);
+        assert_eq!(shifted_width("     x", -1, 4, 8, true), 4);
+        assert_eq!(shifted_width("    x", -1, 4, 8, true), 0);
+    }
+
+    #[test]
+    fn test_auto_indent_width() {
+        assert_eq!(auto_indent_width("    foo();", "bar();", 4, 8), 4);
+        assert_eq!(auto_indent_width("fn main() {", "x", 4, 8), 4);
+        assert_eq!(auto_indent_width("    x", "}", 4, 8), 0);
+        assert_eq!(auto_indent_width("\tif (a) {", "}", 4, 8), 8);
+    }
+
+    #[test]
+    fn test_smart_indent_width() {
+        assert_eq!(smart_indent_width("    foo();", "", 4, 8, "{"), 4);
+        assert_eq!(smart_indent_width("if (a) {", "", 4, 8, "{"), 4);
+        assert_eq!(smart_indent_width("if a:", "", 4, 8, "{"), 0);
+        assert_eq!(smart_indent_width("if a:", "", 4, 8, "{:"), 4);
+        // Splitting `{}` puts the brace back on the outer indent
+        assert_eq!(smart_indent_width("  f() {", "}", 2, 8, "{"), 2);
+    }
+
+    #[test]
+    fn test_retab() {
+        assert_eq!(retab("\tx", 8, 4, true, false), "        x");
+        assert_eq!(retab("\tx", 8, 4, false, false), "\t\tx");
+        assert_eq!(retab("a\tb", 4, 4, true, false), "a   b");
+        // Runs of spaces only change with `all`
+        assert_eq!(retab("        x  y", 8, 8, false, false), "        x  y");
+        assert_eq!(retab("        x  y", 8, 8, false, true), "\tx  y");
+        assert_eq!(retab("  \t x\t", 8, 8, true, false), "         x      ");
+        assert_eq!(retab("日\tx", 8, 4, true, false), "日      x");
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+// Modes a mapping applies to, combined as bit flags
+pub const MAP_NORMAL : u8 = 1;
+pub const MAP_VISUAL : u8 = 2;
+pub const MAP_OP_PENDING : u8 = 4;
+pub const MAP_INSERT : u8 = 8;
+pub const MAP_CMDLINE : u8 = 16;
+
+const MAP_MODES : &[(u8, char)] = &[
+    (MAP_NORMAL, 'n'),
+    (MAP_VISUAL, 'v'),
+    (MAP_OP_PENDING, 'o'),
+    (MAP_INSERT, 'i'),
+    (MAP_CMDLINE, 'c'),
+];
+
+/// A key press with the modifiers that matter for mappings. Shift is folded
+/// into the character for printable keys, and control characters are stored
+/// lower case, so `A` and `<C-a>` compare the way they are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub code : KeyCode,
+    pub mods : KeyModifiers,
+}
+
+impl Key {
+    pub fn new(code : KeyCode, mods : KeyModifiers) -> Key {
+        let mut mods = mods & (KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT);
+        let code = match code {
+            KeyCode::Char(c) => {
+                mods.remove(KeyModifiers::SHIFT);
+                if mods.contains(KeyModifiers::CONTROL) {
+                    KeyCode::Char(c.to_ascii_lowercase())
+                } else {
+                    KeyCode::Char(c)
+                }
+            }
+            KeyCode::BackTab => {
+                mods.remove(KeyModifiers::SHIFT);
+                KeyCode::BackTab
+            }
+            c => c,
+        };
+        Key { code, mods }
+    }
+
+    pub fn char(c : char) -> Key {
+        Key::new(KeyCode::Char(c), KeyModifiers::NONE)
+    }
+
+    pub fn event(&self) -> KeyEvent {
+        KeyEvent::new(self.code, self.mods)
+    }
+}
+
+impl From<KeyEvent> for Key {
+    fn from(e : KeyEvent) -> Key {
+        Key::new(e.code, e.modifiers)
+    }
+}
+
+const KEY_NAMES : &[(&str, KeyCode)] = &[
+    ("cr", KeyCode::Enter),
+    ("enter", KeyCode::Enter),
+    ("return", KeyCode::Enter),
+    ("esc", KeyCode::Esc),
+    ("tab", KeyCode::Tab),
+    ("bs", KeyCode::Backspace),
+    ("backspace", KeyCode::Backspace),
+    ("del", KeyCode::Delete),
+    ("delete", KeyCode::Delete),
+    ("insert", KeyCode::Insert),
+    ("space", KeyCode::Char(' ')),
+    ("lt", KeyCode::Char('<')),
+    ("bar", KeyCode::Char('|')),
+    ("bslash", KeyCode::Char('\\')),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+];
+
+// Parse the inside of a `<...>` key name
+fn parse_special(name : &str) -> Option<Key> {
+    let mut mods = KeyModifiers::NONE;
+    let mut rest = name;
+    while rest.len() > 2 && rest.as_bytes()[1] == b'-' {
+        match rest.as_bytes()[0].to_ascii_lowercase() {
+            b'c' => mods |= KeyModifiers::CONTROL,
+            b's' => mods |= KeyModifiers::SHIFT,
+            b'a' | b'm' => mods |= KeyModifiers::ALT,
+            _ => return None,
+        }
+        rest = &rest[2..];
+    }
+
+    let mut chars = rest.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if mods.is_empty() {
+            return None;
+        }
+        return Some(Key::new(KeyCode::Char(c), mods));
+    }
+
+    let lower = rest.to_ascii_lowercase();
+    if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        return Some(Key::new(KeyCode::F(n), mods));
+    }
+    if lower == "tab" && mods.contains(KeyModifiers::SHIFT) {
+        return Some(Key::new(KeyCode::BackTab, mods));
+    }
+    KEY_NAMES.iter()
+        .find(|(n, _)| *n == lower)
+        .map(|(_, code)| Key::new(*code, mods))
+}
+
+/// Parse key notation such as `<C-w>j` or `<leader>w`. Unknown `<...>` names
+/// are taken literally.
+pub fn parse_keys(s : &str, leader : &str) -> Vec<Key> {
+    let mut keys : Vec<Key> = Vec::new();
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        if c == '<'
+            && let Some(end) = rest.find('>') {
+            let name = &rest[1..end];
+            if name.eq_ignore_ascii_case("leader") {
+                keys.extend(parse_keys(if leader.is_empty() { "\\" } else { leader }, ""));
+                rest = &rest[end + 1..];
+                continue;
+            }
+            if name.eq_ignore_ascii_case("nop") {
+                rest = &rest[end + 1..];
+                continue;
+            }
+            if let Some(k) = parse_special(name) {
+                keys.push(k);
+                rest = &rest[end + 1..];
+                continue;
+            }
+        }
+        keys.push(Key::char(c));
+        rest = &rest[c.len_utf8()..];
+    }
+    keys
+}
+
+/// Key notation for showing a key in mapping listings.
+pub fn key_name(key : &Key) -> String {
+    let mut prefix = String::new();
+    if key.mods.contains(KeyModifiers::CONTROL) {
+        prefix.push_str("C-");
+    }
+    if key.mods.contains(KeyModifiers::ALT) {
+        prefix.push_str("M-");
+    }
+    if key.mods.contains(KeyModifiers::SHIFT) {
+        prefix.push_str("S-");
+    }
+    let name = match key.code {
+        KeyCode::Char(' ') => String::from("Space"),
+        KeyCode::Char('<') => String::from("lt"),
+        KeyCode::Char(c) if prefix.is_empty() => return c.to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::BackTab => String::from("S-Tab"),
+        KeyCode::F(n) => format!("F{}", n),
+        KeyCode::Enter => String::from("CR"),
+        KeyCode::Backspace => String::from("BS"),
+        KeyCode::Delete => String::from("Del"),
+        KeyCode::PageUp => String::from("PageUp"),
+        KeyCode::PageDown => String::from("PageDown"),
+        code => format!("{:?}", code),
+    };
+    format!("<{}{}>", prefix, name)
+}
+
+pub fn keys_name(keys : &[Key]) -> String {
+    keys.iter().map(key_name).collect()
+}
+
+pub struct Mapping {
+    pub lhs : Vec<Key>,
+    pub rhs : Vec<Key>,
+    pub rhs_text : String,  // Right-hand side as typed, for listings
+    pub noremap : bool,
+    pub silent : bool,
+}
+
+#[derive(Default)]
+struct Node {
+    children : HashMap<Key, Node>,
+    mapping : Option<Mapping>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.mapping.is_none()
+    }
+
+    fn collect<'a>(&'a self, out : &mut Vec<&'a Mapping>) {
+        if let Some(m) = &self.mapping {
+            out.push(m);
+        }
+        for child in self.children.values() {
+            child.collect(out);
+        }
+    }
+}
+
+/// Key mappings for every mode, stored as one key trie per mode.
+#[derive(Default)]
+pub struct Keymap {
+    tries : [Node; 5],
+}
+
+fn trie_index(mode : u8) -> usize {
+    mode.trailing_zeros() as usize
+}
+
+impl Keymap {
+    pub fn add(&mut self, modes : u8, lhs : &[Key], rhs : &[Key], rhs_text : &str, noremap : bool, silent : bool) {
+        for (bit, _) in MAP_MODES.iter().filter(|(bit, _)| modes & bit != 0) {
+            let mut node = &mut self.tries[trie_index(*bit)];
+            for key in lhs {
+                node = node.children.entry(*key).or_default();
+            }
+            node.mapping = Some(Mapping {
+                lhs : lhs.to_vec(),
+                rhs : rhs.to_vec(),
+                rhs_text : String::from(rhs_text),
+                noremap,
+                silent,
+            });
+        }
+    }
+
+    /// Remove the mapping for `lhs` in `modes`. Returns false when there was
+    /// no such mapping in any of them.
+    pub fn remove(&mut self, modes : u8, lhs : &[Key]) -> bool {
+        fn remove_from(node : &mut Node, lhs : &[Key]) -> bool {
+            match lhs.split_first() {
+                None => node.mapping.take().is_some(),
+                Some((key, rest)) => {
+                    let Some(child) = node.children.get_mut(key) else { return false };
+                    let removed = remove_from(child, rest);
+                    if child.is_empty() {
+                        node.children.remove(key);
+                    }
+                    removed
+                }
+            }
+        }
+        let mut removed = false;
+        for (bit, _) in MAP_MODES.iter().filter(|(bit, _)| modes & bit != 0) {
+            removed |= remove_from(&mut self.tries[trie_index(*bit)], lhs);
+        }
+        removed
+    }
+
+    pub fn clear(&mut self, modes : u8) {
+        for (bit, _) in MAP_MODES.iter().filter(|(bit, _)| modes & bit != 0) {
+            self.tries[trie_index(*bit)] = Node::default();
+        }
+    }
+
+    /// Look up `keys` in the trie for `mode`. Returns the mapping for exactly
+    /// `keys`, if any, and whether a longer mapping starts with `keys`.
+    pub fn lookup(&self, mode : u8, keys : &[Key]) -> (Option<&Mapping>, bool) {
+        let mut node = &self.tries[trie_index(mode)];
+        for key in keys {
+            match node.children.get(key) {
+                Some(n) => node = n,
+                None => return (None, false),
+            }
+        }
+        (node.mapping.as_ref(), !node.children.is_empty())
+    }
+
+    /// Mappings in `modes` whose left-hand side starts with `prefix`, as
+    /// listing lines.
+    pub fn list(&self, modes : u8, prefix : &[Key], local : bool) -> Vec<String> {
+        let mut lines : Vec<String> = Vec::new();
+        for (bit, name) in MAP_MODES.iter().filter(|(bit, _)| modes & bit != 0) {
+            let mut found : Vec<&Mapping> = Vec::new();
+            self.tries[trie_index(*bit)].collect(&mut found);
+            found.sort_by_key(|m| keys_name(&m.lhs));
+            for m in found.iter().filter(|m| m.lhs.starts_with(prefix)) {
+                lines.push(format!("{}  {:<12} {}{}{}",
+                    name,
+                    keys_name(&m.lhs),
+                    if m.noremap { '*' } else { ' ' },
+                    if local { '@' } else { ' ' },
+                    m.rhs_text));
+            }
+        }
+        lines
+    }
+}
+
+pub enum Resolved<'a> {
+    Wait,                       // Typed keys are the start of a longer mapping
+    Mapped(&'a Mapping, usize), // Mapping and the number of keys it consumes
+    Unmapped,                   // First key is not mapped
+}
+
+/// Decide what to do with the keys typed so far. Buffer-local maps are
+/// listed first in `maps` and take precedence.
+pub fn resolve<'a>(maps : &[&'a Keymap], mode : u8, keys : &[Key], timed_out : bool) -> Resolved<'a> {
+    if !timed_out && maps.iter().any(|m| m.lookup(mode, keys).1) {
+        return Resolved::Wait;
+    }
+    for len in (1..=keys.len()).rev() {
+        for map in maps {
+            if let (Some(m), _) = map.lookup(mode, &keys[..len]) {
+                return Resolved::Mapped(m, len);
+            }
+        }
+    }
+    Resolved::Unmapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(s : &str) -> Vec<Key> {
+        parse_keys(s, "\\")
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(keys("ab"), vec![Key::char('a'), Key::char('b')]);
+        assert_eq!(keys("<C-w>j"), vec![
+            Key::new(KeyCode::Char('w'), KeyModifiers::CONTROL), Key::char('j')]);
+        assert_eq!(keys("<C-W>"), vec![Key::new(KeyCode::Char('w'), KeyModifiers::CONTROL)]);
+        assert_eq!(keys(":w<CR>"), vec![
+            Key::char(':'), Key::char('w'), Key::new(KeyCode::Enter, KeyModifiers::NONE)]);
+        assert_eq!(keys("<lt><Space>"), vec![Key::char('<'), Key::char(' ')]);
+        assert_eq!(keys("<F5><S-Tab>"), vec![
+            Key::new(KeyCode::F(5), KeyModifiers::NONE),
+            Key::new(KeyCode::BackTab, KeyModifiers::NONE)]);
+        assert_eq!(keys("<nope>").len(), 6);
+        assert_eq!(parse_keys("<leader>w", ","), vec![Key::char(','), Key::char('w')]);
+        assert_eq!(keys("<Nop>"), vec![]);
+    }
+
+    #[test]
+    fn test_key_from_event_folds_shift() {
+        let k : Key = KeyEvent::new(KeyCode::Char('A'), KeyModifiers::SHIFT).into();
+        assert_eq!(k, Key::char('A'));
+    }
+
+    #[test]
+    fn test_keys_name_round_trip() {
+        for s in ["<C-w>j", ":w<CR>", "<lt>x<Space>", "<F5>", "<M-a>"] {
+            assert_eq!(keys(&keys_name(&keys(s))), keys(s));
+        }
+    }
+
+    #[test]
+    fn test_add_lookup_remove() {
+        let mut m = Keymap::default();
+        m.add(MAP_NORMAL | MAP_VISUAL, &keys("gx"), &keys("x"), "x", false, false);
+        assert!(m.lookup(MAP_NORMAL, &keys("g")).1);
+        assert!(m.lookup(MAP_NORMAL, &keys("gx")).0.is_some());
+        assert!(m.lookup(MAP_VISUAL, &keys("gx")).0.is_some());
+        assert!(m.lookup(MAP_INSERT, &keys("gx")).0.is_none());
+
+        assert!(m.remove(MAP_NORMAL, &keys("gx")));
+        assert!(!m.remove(MAP_NORMAL, &keys("gx")));
+        assert!(!m.lookup(MAP_NORMAL, &keys("g")).1);
+        assert!(m.lookup(MAP_VISUAL, &keys("gx")).0.is_some());
+        m.clear(MAP_VISUAL);
+        assert!(m.lookup(MAP_VISUAL, &keys("gx")).0.is_none());
+    }
+
+    #[test]
+    fn test_resolve_prefix_and_timeout() {
+        let mut m = Keymap::default();
+        m.add(MAP_NORMAL, &keys("a"), &keys("x"), "x", false, false);
+        m.add(MAP_NORMAL, &keys("abc"), &keys("y"), "y", false, false);
+        let maps = [&m];
+
+        assert!(matches!(resolve(&maps, MAP_NORMAL, &keys("a"), false), Resolved::Wait));
+        assert!(matches!(resolve(&maps, MAP_NORMAL, &keys("ab"), false), Resolved::Wait));
+        assert!(matches!(resolve(&maps, MAP_NORMAL, &keys("abc"), false), Resolved::Mapped(_, 3)));
+        assert!(matches!(resolve(&maps, MAP_NORMAL, &keys("abx"), false), Resolved::Mapped(_, 1)));
+        assert!(matches!(resolve(&maps, MAP_NORMAL, &keys("a"), true), Resolved::Mapped(_, 1)));
+        assert!(matches!(resolve(&maps, MAP_NORMAL, &keys("x"), false), Resolved::Unmapped));
+        assert!(matches!(resolve(&maps, MAP_INSERT, &keys("a"), false), Resolved::Unmapped));
+    }
+
+    #[test]
+    fn test_resolve_buffer_local_first() {
+        let mut global = Keymap::default();
+        let mut local = Keymap::default();
+        global.add(MAP_NORMAL, &keys("x"), &keys("g"), "g", false, false);
+        local.add(MAP_NORMAL, &keys("x"), &keys("l"), "l", false, false);
+        match resolve(&[&local, &global], MAP_NORMAL, &keys("x"), false) {
+            Resolved::Mapped(m, 1) => assert_eq!(m.rhs_text, "l"),
+            _ => panic!("expected local mapping"),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+/// What 'list' mode shows for the chars named in 'listchars'. Those left
+/// out are shown as usual, except a tab, which is then shown as `^I`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListChars {
+    pub tab : Option<(char, char, Option<char>)>,   // First cell, the rest, and the last one
+    pub trail : Option<char>,                       // Trailing spaces
+    pub eol : Option<char>,                         // After the end of the line
+    pub nbsp : Option<char>,                        // Non-breaking spaces
+}
+
+/// Parse a 'listchars' value such as `tab:>-,trail:-,eol:$`. Returns None
+/// when it is not valid.
+pub fn parse(s : &str) -> Option<ListChars> {
+    let mut lcs = ListChars::default();
+    for item in s.split(',').filter(|item| !item.is_empty()) {
+        let (name, value) = item.split_once(':')?;
+        let chars : Vec<char> = value.chars().collect();
+        match (name, chars.as_slice()) {
+            ("tab", &[first, rest]) => lcs.tab = Some((first, rest, None)),
+            ("tab", &[first, rest, last]) => lcs.tab = Some((first, rest, Some(last))),
+            ("trail", &[c]) => lcs.trail = Some(c),
+            ("eol", &[c]) => lcs.eol = Some(c),
+            ("nbsp", &[c]) => lcs.nbsp = Some(c),
+            _ => return None,
+        }
+    }
+    Some(lcs)
+}
+
+impl ListChars {
+    /// The cells of a tab `cells` wide.
+    pub fn tab_cells(&self, cells : usize) -> Option<String> {
+        let (first, rest, last) = self.tab?;
+        Some((0..cells).map(|n| match last {
+            Some(last) if n + 1 == cells => last,
+            _ if n == 0 && (last.is_none() || cells > 1) => first,
+            _ => rest,
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let lcs = parse("tab:>-,trail:~,eol:$,nbsp:+").unwrap();
+        assert_eq!(lcs.tab, Some(('>', '-', None)));
+        assert_eq!((lcs.trail, lcs.eol, lcs.nbsp), (Some('~'), Some('$'), Some('+')));
+        assert_eq!(parse(""), Some(ListChars::default()));
+        assert_eq!(parse("eol:¶").unwrap().eol, Some('¶'));
+        assert_eq!(parse("tab:>"), None);
+        assert_eq!(parse("eol:ab"), None);
+        assert_eq!(parse("space:."), None);
+        assert_eq!(parse("eol"), None);
+    }
+
+    #[test]
+    fn test_tab_cells() {
+        assert_eq!(parse("tab:>-").unwrap().tab_cells(4).as_deref(), Some(">---"));
+        assert_eq!(parse("tab:<->").unwrap().tab_cells(4).as_deref(), Some("<-->"));
+        assert_eq!(parse("tab:<->").unwrap().tab_cells(1).as_deref(), Some(">"));
+        assert_eq!(parse("eol:$").unwrap().tab_cells(4), None);
+    }
+}
@@ -1,342 +1,2085 @@
 use crossterm::{
-    event::{self, KeyCode, KeyEvent},
+    event::{
+        self, DisableBracketedPaste, DisableFocusChange, DisableMouseCapture,
+        EnableBracketedPaste, EnableFocusChange, EnableMouseCapture,
+        KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+    },
     terminal::{self, ClearType},
-    ExecutableCommand,
-    cursor::{MoveTo, RestorePosition, SavePosition, MoveLeft, MoveDown, MoveUp, MoveRight, SetCursorStyle},
+    ExecutableCommand, queue,
+    cursor::{MoveTo, SetCursorStyle},
 };
-use std::io::{stdout, Write};
+use std::collections::VecDeque;
+use std::io::stdout;
 use std::fs;
+use std::time::{Duration, Instant};
 use std::io::prelude::*;
 use std::io;
+mod anchor;
+mod autocmd;
+mod buffer;
+mod config;
+mod editor;
+mod format;
+mod ex;
+mod fileformat;
+mod filetype;
+mod gutter;
+mod highlight;
+mod indent;
+mod keymap;
+mod listchars;
+mod mark;
+mod modeline;
+mod matching;
+mod mouse;
+mod screen;
+mod options;
+mod pos;
+mod register;
+mod replace;
+mod syntax;
 mod text;
-use text::Text;
+mod textobject;
+mod width;
+mod wrap;
+use anchor::Gravity;
+use autocmd::Event;
+use editor::{Editor, InsertPending, Visual};
+use replace::Replaced;
+use pos::{CharIdx, LineNo, Position, Range};
+use text::TextError;
+use mouse::Clicks;
+use gutter::Gutter;
+use screen::{Grid, Screen, Style};
+use listchars::ListChars;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+use wrap::{Row, Wrap};
+use keymap::{Key, Resolved, MAP_CMDLINE, MAP_INSERT, MAP_NORMAL, MAP_OP_PENDING, MAP_VISUAL};
 use std::env;
 
 const QUIT: i8 = -1;
 const NORMAL: i8 = 0;
 const INSERT: i8 = 1;
+const COMMAND: i8 = 2;
+const REPLACE: i8 = 3;
+const VREPLACE: i8 = 4;
+const VISUAL: i8 = 5;
+
+// Modes where typed characters go into the buffer
+fn is_insert(mode : i8) -> bool {
+    mode == INSERT || mode == REPLACE || mode == VREPLACE
+}
 
 struct RawModeGuard;
 impl RawModeGuard {
     fn new() -> io::Result<Self> {
         terminal::enable_raw_mode()?;
+        stdout().execute(EnableBracketedPaste)?;
+        stdout().execute(EnableFocusChange)?;
         Ok(Self)
     }
 }
 impl Drop for RawModeGuard {
     fn drop(&mut self) {
+        let _ = stdout().execute(DisableMouseCapture);
+        let _ = stdout().execute(DisableFocusChange);
+        let _ = stdout().execute(DisableBracketedPaste);
         let _ = terminal::disable_raw_mode();
     }
 }
 
-fn refresh_text(buffer : &Text) {
-    stdout().execute(SavePosition).unwrap();
-    stdout().execute(terminal::Clear(ClearType::All)).unwrap();
-    stdout().execute(MoveTo(0,0)).unwrap();
-    for line in buffer.get_text().lines() {
-        print!("{}", line);
-        stdout().execute(MoveDown(1)).unwrap();
-        stdout().execute(MoveLeft(line.chars().count() as u16)).unwrap();
+// Whether the status line is shown above the last screen line. There is
+// one window, so only 'laststatus' 2 shows it.
+fn has_status_line(editor : &Editor) -> bool {
+    editor.opt_number("laststatus") == 2
+}
+
+// Number of screen lines available for text
+fn text_rows(editor : &Editor) -> usize {
+    let (_, height) = terminal::size().unwrap_or((80, 24));
+    // The last screen line is reserved for messages and the command line
+    let reserved = 1 + has_status_line(editor) as usize;
+    (height as usize).saturating_sub(reserved).max(1)
+}
+
+// Number of screen columns available for text, right of the gutter
+fn text_width(editor : &Editor) -> usize {
+    let (width, _) = terminal::size().unwrap_or((80, 24));
+    (width as usize).saturating_sub(editor.gutter().width()).max(1)
+}
+
+// Screen rows of buffer line `line_no`: as wrapped by `wrap`, or one row
+// with 'nowrap'
+fn line_rows(editor : &Editor, line_no : usize, wrap : Option<&Wrap>) -> Vec<Row> {
+    let line = editor.buffer.text.get_line(LineNo(line_no));
+    match wrap {
+        Some(wrap) => wrap.rows(line),
+        None => vec![Row { start : 0, end : line.chars().count(), lead : 0 }],
+    }
+}
+
+// Draw buffer line `line_no` in `rows` from screen row `row`, in the
+// highlight groups of its syntax, the Visual selection and the bracket pair
+// at the cursor. Tabs go to the next tab stop, 'list' mode shows
+// 'listchars', rows after the first start with 'showbreak' and the cells
+// of 'colorcolumn' are drawn over ColorColumn. The text starts at cell
+// `x`, and cells of the line before `left` are scrolled out of view.
+fn draw_line(editor : &Editor, grid : &mut Grid, (row, x) : (usize, usize), line_no : usize, rows : &[Row], left : usize) {
+    let line = editor.buffer.text.get_line(LineNo(line_no));
+    let start = editor.buffer.text.line_start(LineNo(line_no));
+    let end = start + line.chars().count();
+    let matched = editor.matched.filter(|(a, b)| (start..end).contains(a) || (start..end).contains(b));
+    let (selected, linewise) = editor.selection().unwrap_or_default();
+    let (ts, lcs) = (editor.screen_tabstop(), editor.list_chars());
+    let trail = line.trim_end_matches(' ').chars().count();
+    let hl = &editor.highlights;
+    let (visual, paren) = (hl.style("Visual"), hl.style("MatchParen"));
+    let (special_key, non_text) = (hl.style("SpecialKey"), hl.style("NonText"));
+    let showbreak = editor.opt_str("showbreak");
+    let spans = editor.buffer.highlight.spans(line_no);
+    let span_styles : Vec<Style> = spans.iter().map(|s| hl.style(s.group.name())).collect();
+    let mut span = 0;
+    let (mut r, mut row_cell) = (0, 0);
+    let mut row_cells = vec![0];    // Cell of the line each row starts at
+    let mut cell = 0;
+    let mut n = 0;
+    for g in line.graphemes(true) {
+        if n >= rows[r].end && r + 1 < rows.len() {
+            r += 1;
+            row_cell = cell;
+            row_cells.push(cell);
+            let at = rows[r].lead.saturating_sub(UnicodeWidthStr::width(showbreak));
+            grid.put_str(row + r, x + at, showbreak, non_text);
+        }
+        let idx = start + n;
+        while span < spans.len() && spans[span].end <= n {
+            span += 1;
+        }
+        let mut style = match spans.get(span) {
+            Some(s) if s.start <= n => span_styles[span],
+            _ => Style::default(),
+        };
+        if matched.is_some_and(|(a, b)| idx == a || idx == b) {
+            style = paren.over(style);
+        }
+        if selected.contains(idx) {
+            style = visual.over(style);
+        }
+        let next = width::next_cell(g, cell, ts);
+        let listed = match (g, lcs) {
+            ("\t", Some(lcs)) => Some(lcs.tab_cells(next - cell).unwrap_or(String::from("^I"))),
+            (" ", Some(ListChars { trail : Some(c), .. })) if n >= trail => Some(c.to_string()),
+            ("\u{a0}" | "\u{202f}", Some(ListChars { nbsp : Some(c), .. })) => Some(c.to_string()),
+            _ => None,
+        };
+        if cell >= row_cell + left {
+            let at = x + rows[r].lead + cell - row_cell - left;
+            match listed {
+                Some(s) => grid.put_str(row + r, at, &s, special_key.over(style)),
+                None if g == "\t" => grid.put_str(row + r, at, &" ".repeat(next - cell), style),
+                None => grid.put_grapheme(row + r, at, g, style),
+            };
+        }
+        cell = next;
+        n += g.chars().count();
+    }
+    // An empty line in the selection shows as one selected cell
+    let eol_selected = line.is_empty() && (selected.contains(end) || linewise && selected.start <= end && end <= selected.end);
+    let eol_style = if eol_selected { visual.over(non_text) } else { non_text };
+    let at = (rows[r].lead + cell - row_cell).checked_sub(left).map(|at| x + at);
+    match (lcs.and_then(|lcs| lcs.eol), at) {
+        (Some(c), Some(at)) => { grid.put_char(row + r, at, c, eol_style); }
+        (None, Some(at)) if eol_selected => { grid.put_char(row, at, ' ', eol_style); }
+        _ => (),
+    }
+    // Each column is on the row showing that cell of the line
+    let color_column = hl.style("ColorColumn");
+    for c in editor.color_columns() {
+        for (r, row_start) in row_cells.iter().enumerate() {
+            let row_end = row_cells.get(r + 1).copied().unwrap_or(usize::MAX);
+            if c >= row_start + left && c < row_end {
+                let at = x + rows[r].lead + c - row_start - left;
+                grid.underlay(row + r, at..at + 1, color_column);
+            }
+        }
+    }
+}
+
+// Screen cell of the cursor: the row below the top of its line and the
+// cell in that row
+fn cursor_cell(editor : &Editor, mode : i8, wrap : Option<&Wrap>) -> (usize, usize) {
+    let line = editor.buffer.text.get_line(LineNo(editor.line));
+    let ts = editor.screen_tabstop();
+    let col = editor.col;
+    let mut cell = width::cell_of_col(line, col, ts);
+    // Outside insert mode the cursor rests on the last cell of a tab,
+    // unless 'list' is set
+    if !is_insert(mode) && !editor.opt_bool("list") && line.chars().nth(col) == Some('\t') {
+        cell = width::cell_of_col(line, col + 1, ts) - 1;
+    }
+    match wrap {
+        Some(wrap) => {
+            let rows = wrap.rows(line);
+            let r = Wrap::row_of_col(&rows, col);
+            let row_cell = width::cell_of_col(line, rows[r].start, ts);
+            (r, (rows[r].lead + cell - row_cell).min(wrap.width - 1))
+        }
+        None => (0, cell.saturating_sub(editor.left)),
+    }
+}
+
+// Draw the sign and line number of buffer line `line_no` in the gutter of
+// screen row `row`, and the fold and sign columns of all `n` rows the line
+// takes. The number of the cursor line is drawn as CursorLineNr with
+// 'cursorline' set.
+fn draw_gutter(editor : &Editor, grid : &mut Grid, gutter : Gutter, (row, n) : (usize, usize), line_no : usize) {
+    let hl = &editor.highlights;
+    let sign_style = hl.style("SignColumn");
+    if gutter.sign > 0 {
+        grid.put_str(row, gutter.fold, &editor.sign(LineNo(line_no)), sign_style);
+    }
+    for r in row..row + n {
+        grid.underlay(r, 0..gutter.fold, hl.style("FoldColumn"));
+        grid.underlay(r, gutter.fold..gutter.fold + gutter.sign, sign_style);
+    }
+    if gutter.number > 0 {
+        let (number, relative) = (editor.opt_bool("number"), editor.opt_bool("relativenumber"));
+        let text = gutter::number_text(line_no, editor.line, number, relative, gutter.number);
+        let group = match line_no == editor.line && editor.opt_bool("cursorline") {
+            true => "CursorLineNr",
+            false => "LineNr",
+        };
+        grid.put_str(row, gutter.fold + gutter.sign, &text, hl.style(group));
+    }
+}
+
+// Draw the status line on screen row `row`: the file name and its state on
+// the left, and the file type, the cursor position and where the lines
+// shown are in the buffer on the right. `bottom` is the last line shown.
+fn draw_status_line(editor : &Editor, grid : &mut Grid, row : usize, width : usize, bottom : usize) {
+    let style = editor.highlights.style("StatusLine");
+    let buffer = &editor.buffer;
+    let mut left = format!(" {}", buffer.name.as_deref().unwrap_or("[No Name]"));
+    if buffer.modified() {
+        left.push_str(" [+]");
     }
-    //let (width, height) = terminal::size().ok().unwrap();
-    //stdout().execute(MoveTo(0, height)).unwrap();
-    //println!("Press q to exit.");
-    stdout().execute(RestorePosition).unwrap();
+    if editor.opt_bool("readonly") {
+        left.push_str(" [RO]");
+    }
+    let last = buffer.text.last_line().0;
+    let shown = match (editor.top, bottom >= last) {
+        (0, true) => String::from("All"),
+        (0, false) => String::from("Top"),
+        (_, true) => String::from("Bot"),
+        (top, _) => format!("{}%", top * 100 / (last + 1)),
+    };
+    let filetype = match editor.opt_str("filetype") {
+        "" => String::new(),
+        ft => format!("[{}] ", ft),
+    };
+    let right = format!("{}[{}]  {},{}  {:<3} ", filetype, editor.opt_str("fileformat"),
+        editor.line + 1, editor.col + 1, shown);
+    grid.put_str(row, 0, &" ".repeat(width), style);
+    grid.put_str(row, 0, &left, style);
+    let at = width.saturating_sub(UnicodeWidthStr::width(right.as_str())).max(UnicodeWidthStr::width(left.as_str()) + 1);
+    grid.put_str(row, at, &right, style);
 }
 
-fn handle_input_normal(code : KeyCode, buffer : &mut Text) -> i8 {
-    let mut x : u16; let mut y : u16;
-    match crossterm::cursor::position() {
-        Ok((col, row)) => {
-            x = col; y = row;
+// Draw the text and the last screen line into a new frame. A line that
+// does not fit below the others shows as rows of '@'.
+fn draw_frame(editor : &Editor, mode : i8, screen : &mut Screen) {
+    let (width, height) = terminal::size().unwrap_or((80, 24));
+    let (width, height) = (width as usize, height as usize);
+    screen.set_normal(editor.highlights.style("Normal"));
+    screen.begin(width, height);
+    screen.frame.set_tabstop(editor.opt_number("tabstop") as usize);
+    let gutter = editor.gutter();
+    let wrap = editor.wrap(text_width(editor));
+    let left = if wrap.is_some() { 0 } else { editor.left };
+    let (mut row, mut line_no) = (0, editor.top);
+    while row < text_rows(editor) && line_no < editor.buffer.text.line_count() {
+        let rows = line_rows(editor, line_no, wrap.as_ref());
+        if row + rows.len() > text_rows(editor) && line_no > editor.top {
+            let at = editor.highlights.style("NonText");
+            (row..text_rows(editor)).for_each(|r| { screen.frame.put_char(r, 0, '@', at); });
+            break;
+        }
+        draw_gutter(editor, &mut screen.frame, gutter, (row, rows.len()), line_no);
+        draw_line(editor, &mut screen.frame, (row, gutter.width()), line_no, &rows, left);
+        if line_no == editor.line {
+            let (r, cell) = cursor_cell(editor, mode, wrap.as_ref());
+            screen.cursor = (row + r, gutter.width() + cell);
+            if editor.opt_bool("cursorline") {
+                let style = editor.highlights.style("CursorLine");
+                (row..row + rows.len()).for_each(|r| screen.frame.underlay(r, gutter.width()..width, style));
+            }
+        }
+        row += rows.len();
+        line_no += 1;
+    }
+    if has_status_line(editor) {
+        draw_status_line(editor, &mut screen.frame, text_rows(editor), width, line_no.saturating_sub(1));
+    }
+    let last = height.saturating_sub(1);
+    if mode == COMMAND && !editor.silent {
+        let col = screen.frame.put_str(last, 0, &format!(":{}", editor.cmdline), Style::default());
+        screen.cursor = (last, col);
+    }
+    else {
+        screen.frame.put_str(last, 0, &editor.status, Style::default());
+    }
+}
+
+// Draw the screen and write the cells that changed to the terminal. A
+// pending message goes on the last screen line, or when it is longer than
+// one line above it until the main loop gets a key.
+// The end of a long message over the bottom of the screen, followed by
+// the prompt for a key. Returns the column after the prompt.
+fn draw_hit_enter(msg : &str, frame : &mut Grid) -> usize {
+    let height = frame.height();
+    let lines : Vec<&str> = msg.lines().collect();
+    let shown = lines.len().min(height.saturating_sub(1));
+    let top = height.saturating_sub(shown + 1);
+    for (n, line) in lines[lines.len() - shown..].iter().enumerate() {
+        frame.clear_row(top + n);
+        frame.put_str(top + n, 0, line, Style::default());
+    }
+    let last = height.saturating_sub(1);
+    frame.clear_row(last);
+    frame.put_str(last, 0, "Press any key to continue", Style::default())
+}
+
+fn draw(editor : &mut Editor, mode : i8, screen : &mut Screen) {
+    if std::mem::take(&mut editor.redraw) {
+        screen.invalidate();
+    }
+    // Every line shown takes at least one row
+    let last = editor.top + text_rows(editor);
+    editor.buffer.highlight.update(&mut editor.buffer.text, last);
+    let msg = editor.message.take().unwrap_or_default();
+    if msg.contains('\n') {
+        editor.hit_enter = Some(msg);
+    }
+    else if !msg.is_empty() {
+        editor.status = msg;
+    }
+    draw_frame(editor, mode, screen);
+    if let Some(msg) = &editor.hit_enter {
+        let col = draw_hit_enter(msg, &mut screen.frame);
+        screen.cursor = (screen.frame.height().saturating_sub(1), col);
+    }
+    let _ = screen.flush(&mut stdout());
+}
+
+// Move the viewport so the cursor line is visible
+fn scroll_to_cursor(editor : &mut Editor) {
+    let rows = text_rows(editor);
+    if editor.line < editor.top {
+        editor.top = editor.line;
+    }
+    else if editor.line >= editor.top + rows {
+        editor.top = editor.line + 1 - rows;
+    }
+    let wrap = editor.wrap(text_width(editor));
+    let wrap = match wrap {
+        Some(w) => w,
+        None => return scroll_sideways(editor),
+    };
+    // Wrapped lines take more rows, so the top line may have to go down
+    let mut top = editor.top;
+    let mut used : usize = (top..=editor.line).map(|n| line_rows(editor, n, Some(&wrap)).len()).sum();
+    while used > rows && top < editor.line {
+        used -= line_rows(editor, top, Some(&wrap)).len();
+        top += 1;
+    }
+    editor.top = top;
+}
+
+// With 'nowrap', scroll sideways to keep the cursor 'sidescrolloff' cells
+// from the sides of the screen, by at least 'sidescroll' cells, or putting
+// the cursor in the middle when it is 0
+fn scroll_sideways(editor : &mut Editor) {
+    let width = text_width(editor);
+    let line = editor.buffer.text.get_line(LineNo(editor.line));
+    let ts = editor.screen_tabstop();
+    let start = width::cell_of_col(line, editor.col, ts);
+    let end = width::cell_of_col(line, width::next_col(line, editor.col), ts).max(start + 1);
+    let off = (editor.opt_number("sidescrolloff") as usize).min((width - 1) / 2);
+    let step = editor.opt_number("sidescroll") as usize;
+    if start < editor.left + off {
+        editor.left = match step {
+            0 => start.saturating_sub(width / 2),
+            _ => start.saturating_sub(off).min(editor.left.saturating_sub(step)),
+        };
+    }
+    else if end + off > editor.left + width {
+        editor.left = match step {
+            0 => start.saturating_sub(width / 2),
+            _ => (end + off - width).max(editor.left + step),
+        };
+    }
+}
+
+// zh, zl, zs and ze: scroll sideways with 'nowrap', keeping the cursor on
+// screen
+fn scroll_horizontally(editor : &mut Editor, c : char, count : usize) {
+    if editor.opt_bool("wrap") {
+        return;
+    }
+    let width = text_width(editor);
+    let line = editor.buffer.text.get_line(LineNo(editor.line));
+    let ts = editor.screen_tabstop();
+    let start = width::cell_of_col(line, editor.col, ts);
+    let end = width::cell_of_col(line, width::next_col(line, editor.col), ts).max(start + 1);
+    let off = (editor.opt_number("sidescrolloff") as usize).min((width - 1) / 2);
+    let left = match c {
+        'h' => editor.left.saturating_sub(count),
+        'l' => editor.left + count,
+        's' => start.saturating_sub(off),
+        _ => (end + off).saturating_sub(width),
+    };
+    let col = if start < left + off {
+        width::col_at_cell(line, left + off, ts)
+    }
+    else if end + off > left + width {
+        width::col_at_cell(line, (left + width).saturating_sub(off + 1), ts)
+    }
+    else {
+        editor.col
+    };
+    editor.left = left;
+    editor.set_cursor(Position::new(editor.line, col));
+}
+
+// gj and gk: move the cursor `count` screen rows down or up, which are
+// parts of a line with 'wrap'
+fn move_screen_rows(editor : &mut Editor, down : bool, count : usize) {
+    let wrap = match editor.wrap(text_width(editor)) {
+        Some(w) => w,
+        None => return move_cursor(editor, if down { 'j' } else { 'k' }, count),
+    };
+    let text = &editor.buffer.text;
+    let (mut line_no, mut rows) = (editor.line, wrap.rows(text.get_line(LineNo(editor.line))));
+    let mut r = Wrap::row_of_col(&rows, editor.col);
+    let cell = wrap.cell_in_row(text.get_line(LineNo(line_no)), rows[r], editor.col);
+    for _ in 0..count {
+        if down && r + 1 < rows.len() {
+            r += 1;
+        }
+        else if down && line_no < text.last_line().0 {
+            line_no += 1;
+            rows = wrap.rows(text.get_line(LineNo(line_no)));
+            r = 0;
+        }
+        else if !down && r > 0 {
+            r -= 1;
+        }
+        else if !down && line_no > 0 {
+            line_no -= 1;
+            rows = wrap.rows(text.get_line(LineNo(line_no)));
+            r = rows.len() - 1;
+        }
+    }
+    let col = wrap.col_in_row(text.get_line(LineNo(line_no)), rows[r], cell, false);
+    editor.set_cursor(Position::new(line_no, col));
+}
+
+// g0 and g$: the first or last char of the screen row, or of the part of
+// the line on screen with 'nowrap'
+fn screen_row_end(editor : &mut Editor, last : bool) {
+    let width = text_width(editor);
+    let line = editor.buffer.text.get_line(LineNo(editor.line));
+    let col = match editor.wrap(width) {
+        Some(wrap) => {
+            let rows = wrap.rows(line);
+            let row = rows[Wrap::row_of_col(&rows, editor.col)];
+            match last {
+                true => width::prev_col(line, row.end).max(row.start),
+                false => row.start,
+            }
+        }
+        None => {
+            let ts = editor.screen_tabstop();
+            match last {
+                true => width::col_at_cell(line, editor.left + width - 1, ts),
+                false => width::col_at_cell(line, editor.left, ts),
+            }
+        }
+    };
+    editor.set_cursor(Position::new(editor.line, col));
+}
+
+// Scroll a page forward or back, taking the cursor along
+fn scroll_page(editor : &mut Editor, forward : bool) {
+    let page = text_rows(editor).saturating_sub(2).max(1);
+    let last = editor.buffer.text.last_line().0;
+    if forward {
+        editor.top = (editor.top + page).min(last);
+        editor.line = editor.line.max(editor.top);
+    }
+    else {
+        editor.top = editor.top.saturating_sub(page);
+        editor.line = editor.line.min(editor.top + text_rows(editor) - 1);
+    }
+}
+
+fn set_cursor_style(editor : &Editor, mode : i8) {
+    let option = match mode {
+        INSERT => "insertcursor",
+        REPLACE | VREPLACE => "replacecursor",
+        _ => "normalcursor",
+    };
+    let style = match editor.opt_str(option) {
+        "blinkingblock" => SetCursorStyle::BlinkingBlock,
+        "steadyblock" => SetCursorStyle::SteadyBlock,
+        "blinkingunderscore" => SetCursorStyle::BlinkingUnderScore,
+        "steadyunderscore" => SetCursorStyle::SteadyUnderScore,
+        "blinkingbar" => SetCursorStyle::BlinkingBar,
+        "steadybar" => SetCursorStyle::SteadyBar,
+        _ => SetCursorStyle::DefaultUserShape,
+    };
+    // Written with the next frame
+    let _ = queue!(stdout(), style);
+}
+
+// Insert `s` at the cursor and move the cursor past it
+fn insert_text(editor : &mut Editor, s : &str) {
+    replace_text(editor, 0, s);
+}
+
+// Replace `n` chars at the cursor with `s` and move the cursor past it
+fn replace_text(editor : &mut Editor, n : usize, s : &str) {
+    let start = editor.cursor_idx();
+    let buffer = &mut editor.buffer.text;
+    match buffer.replace_range(Range::new(start, start + n), s) {
+        Ok(_) => {
+            let pos = buffer.position(start + s.chars().count());
+            (editor.line, editor.col) = (pos.line.0, pos.col);
+            editor.inserted.push_str(s);
+        }
+        Err(e) => editor.add_message(&e.to_string()),
+    }
+}
+
+// Delete `n` chars before the cursor, joining lines at the start of a line
+fn delete_before(editor : &mut Editor, n : usize) {
+    let end = editor.cursor_idx();
+    let start = end - n.min(end.0);
+    let buffer = &mut editor.buffer.text;
+    match buffer.delete_range(Range::new(start, end)) {
+        Ok(removed) => {
+            let pos = buffer.position(start);
+            (editor.line, editor.col) = (pos.line.0, pos.col);
+            for _ in removed.chars() {
+                editor.inserted.pop();
+            }
+        }
+        Err(e) => editor.add_message(&e.to_string()),
+    }
+}
+
+// Put `s` back at the cursor without moving the cursor
+fn put_back(editor : &mut Editor, s : &str) {
+    let start = editor.cursor_idx();
+    if let Err(e) = editor.buffer.text.insert_str(start, s) {
+        editor.add_message(&e.to_string());
+    }
+}
+
+// r{char}: replace `count` graphemes with `c`. A line break replaces all of
+// them with a single line break.
+fn replace_chars(editor : &mut Editor, key : KeyEvent, count : usize) {
+    let c = match key.code {
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => c,
+        KeyCode::Enter => '\n',
+        KeyCode::Tab => '\t',
+        _ => return,
+    };
+    let idx = editor.cursor_idx();
+    let buffer = &mut editor.buffer.text;
+    let (n, new) = match replace::replace_count(buffer.get_line(LineNo(editor.line)), editor.col, c, count) {
+        Some(edit) => edit,
+        None => return,
+    };
+    if let Err(e) = buffer.replace_range(Range::new(idx, idx + n), &new) {
+        editor.add_message(&e.to_string());
+        return;
+    }
+    if c == '\n' {
+        editor.line += 1;
+        editor.col = 0;
+        return;
+    }
+    editor.col += count - 1;
+}
+
+// Last line of `count` lines starting at the cursor line
+fn count_lines(editor : &Editor, count : usize) -> LineNo {
+    LineNo(editor.line + count - 1).min(editor.buffer.text.last_line())
+}
+
+// J and gJ: join `count` lines, at least two
+fn join_count(editor : &mut Editor, count : usize, spaces : bool) {
+    let last = count_lines(editor, count.max(2));
+    if last.0 > editor.line
+        && let Err(e) = editor.join_lines(LineNo(editor.line), last, spaces) {
+        editor.add_message(&e);
+    }
+}
+
+// Char column of line `line_no` shown at the same screen cell as `from`,
+// for moving up and down
+fn col_below(editor : &Editor, from : Position, line_no : usize) -> usize {
+    let text = &editor.buffer.text;
+    let ts = editor.screen_tabstop();
+    let cell = width::cell_of_col(text.get_line(from.line), from.col, ts);
+    width::col_at_cell(text.get_line(LineNo(line_no)), cell, ts)
+}
+
+// Move the cursor with h, j, k or l, a grapheme or a line at a time
+fn move_cursor(editor : &mut Editor, c : char, count : usize) {
+    let buffer = &editor.buffer.text;
+    let (x, y) = (editor.col, editor.line);
+    let last = buffer.last_line().0;
+    let line = buffer.get_line(LineNo(y));
+    let target = match c {
+        'j' if y < last => (y + count).min(last),
+        'k' if y > 0 => y.saturating_sub(count),
+        'h' => {
+            editor.col = (0..count).fold(x, |col, _| width::prev_col(line, col));
+            return;
+        }
+        'l' => {
+            let end = width::prev_col(line, line.chars().count());
+            editor.col = (0..count).fold(x, |col, _| width::next_col(line, col)).min(end).max(x);
+            return;
         }
-        Err(_) => {
-            eprintln!("Error getting cursor position");
+        _ => return,
+    };
+    let col = col_below(editor, editor.cursor(), target);
+    editor.set_cursor(Position::new(target, col));
+}
+
+// %: the match of the next bracket in the line, or with a count the line
+// that far into the file, in percent. Returns the new cursor position.
+fn percent(editor : &Editor, typed_count : usize) -> Option<Position> {
+    let text = &editor.buffer.text;
+    if typed_count > 0 {
+        if typed_count > 100 {
+            return None;
+        }
+        let line = (typed_count * text.line_count()).div_ceil(100);
+        return Some(Position::new(line.max(1) - 1, 0));
+    }
+    editor.match_pair(editor.cursor_idx()).map(|m| text.position(m))
+}
+
+// 'x goes to the first non-blank of the line of mark x, `x exactly to it
+fn goto_mark(editor : &mut Editor, name : char, exact : bool) {
+    let pos = match editor.mark_position(name) {
+        Ok(p) => p,
+        Err(e) => {
+            editor.add_message(&e);
+            return;
+        }
+    };
+    editor.push_jump();
+    if exact {
+        editor.set_cursor(pos);
+    }
+    else {
+        editor.goto_first_non_blank(pos.line);
+    }
+}
+
+// G and gg: the line of the count, or the last or first line
+fn goto_line(editor : &mut Editor, typed_count : usize, default : LineNo) {
+    editor.push_jump();
+    let line = LineNo::from_one_based(typed_count).unwrap_or(default);
+    editor.goto_first_non_blank(line);
+}
+
+// Chars of lines `first` to `last`, without the last newline
+fn line_range(editor : &Editor, first : LineNo, last : LineNo) -> Range {
+    let text = &editor.buffer.text;
+    Range::new(text.line_start(first), text.line_start(last) + text.get_line_length(last))
+}
+
+// Apply operator `op` to the chars in `range`, or to the lines they are on
+// when `linewise`. Returns the mode to continue in.
+fn apply_operator(editor : &mut Editor, op : char, range : Range, linewise : bool) -> i8 {
+    let reg = editor.register.take().unwrap_or('"');
+    let text = &editor.buffer.text;
+    let first = text.line_of(range.start);
+    let last = text.line_of(if linewise { range.end } else { range.end.max(range.start + 1) - 1 });
+    let n = last - first + 1;
+
+    if op == '>' || op == '<' || op == '=' {
+        let shifted = match op {
+            '=' => editor.reindent_lines(first, last),
+            _ => editor.shift_lines(first, last, if op == '>' { 1 } else { -1 }),
+        };
+        if let Err(e) = shifted {
+            editor.add_message(&e);
+        }
+        return NORMAL;
+    }
+
+    // Nothing is yanked when the text cannot be changed
+    if op != 'y' && !editor.buffer.text.is_modifiable() {
+        editor.add_message(&TextError::ReadOnly.to_string());
+        return NORMAL;
+    }
+    let yanked = match linewise {
+        true => text.get_lines(first, last).join("\n") + "\n",
+        false => text.slice(range),
+    };
+    if let Err(e) = editor.registers.set(reg, &yanked, linewise) {
+        editor.add_message(&e);
+        return NORMAL;
+    }
+    let mut mode = NORMAL;
+    let changed = match (op, linewise) {
+        ('y', _) => {
+            let marks = editor.buffer.text.marks_mut();
+            marks.set('[', range.start);
+            marks.set(']', range.end.max(range.start + 1) - 1);
+            let pos = editor.buffer.text.position(range.start);
+            if linewise {
+                editor.set_cursor(Position { col : editor.col, ..pos });
+                editor.report(n, &format!("{} lines yanked", n));
+            }
+            else {
+                editor.set_cursor(pos);
+            }
             return NORMAL;
         }
+        ('d', true) => editor.buffer.text.delete_lines(first, last).map(|_| {
+            editor.goto_first_non_blank(first);
+            editor.report(n, &format!("{} fewer lines", n));
+        }),
+        ('c', true) => editor.buffer.text.replace_lines(first, last, &[String::new()]).map(|_| {
+            editor.line = first.0;
+            editor.col = 0;
+            mode = INSERT;
+        }),
+        _ => {
+            let buffer = &mut editor.buffer.text;
+            let removed = buffer.delete_range(range).map(|_| ());
+            let pos = buffer.position(range.start);
+            if op == 'c' && removed.is_ok() {
+                (editor.line, editor.col) = (pos.line.0, pos.col);
+                mode = INSERT;
+            }
+            else {
+                editor.set_cursor(pos);
+            }
+            removed
+        }
+    };
+    if let Err(e) = changed {
+        editor.add_message(&e.to_string());
     }
+    mode
+}
 
-    return match code {
-        KeyCode::Char('q') => -1,
+// After an operator: a text object, a motion or the operator again for
+// whole lines. `pending` holds the operator and what was typed after it.
+fn operator_pending(mut pending : String, c : char, count : usize, editor : &mut Editor) -> i8 {
+    let op = pending.remove(0);
+    let digits : String = pending.chars().take_while(char::is_ascii_digit).collect();
+    // Counts before and after the operator multiply
+    let typed_count = match (count, digits.parse::<usize>().unwrap_or(0)) {
+        (0, n) | (n, 0) => n,
+        (a, b) => a * b,
+    };
+    let count = typed_count.max(1);
+    let (x, y) = (editor.col, LineNo(editor.line));
+    let text = &editor.buffer.text;
+    let idx = editor.cursor_idx();
 
-        KeyCode::Char('a') => {
-            if x < buffer.get_line_length(y as usize) as u16 {
-                stdout().execute(MoveRight(1)).unwrap();
+    if let Some(prefix) = pending.chars().last().filter(|p| *p == 'i' || *p == 'a') {
+        return match text.text_object(idx, c, prefix == 'a', count) {
+            Some(range) => apply_operator(editor, op, range, c == 'p'),
+            None => NORMAL,
+        };
+    }
+    if let Some(prefix) = pending.chars().last().filter(|p| *p == '\'' || *p == '`') {
+        let pos = match editor.mark_position(c) {
+            Ok(p) => p,
+            Err(e) => {
+                editor.add_message(&e);
+                return NORMAL;
+            }
+        };
+        if prefix == '\'' {
+            let range = line_range(editor, y.min(pos.line), y.max(pos.line));
+            return apply_operator(editor, op, range, true);
+        }
+        let mark = text.char_idx(pos);
+        if mark == idx {
+            return NORMAL;
+        }
+        return apply_operator(editor, op, Range::new(idx.min(mark), idx.max(mark)), false);
+    }
+    if pending.ends_with('g') {
+        if c != 'g' {
+            return NORMAL;
+        }
+        let line = LineNo::from_one_based(typed_count).unwrap_or_default();
+        let range = line_range(editor, y.min(line), y.max(line).min(text.last_line()));
+        return apply_operator(editor, op, range, true);
+    }
+    match c {
+        '0'..='9' | 'i' | 'a' | '\'' | '`' | 'g' if c != '0' || !digits.is_empty() => {
+            editor.pending = format!("{}{}{}", op, pending, c);
+            NORMAL
+        }
+        _ if c == op => {
+            let range = line_range(editor, y, count_lines(editor, count));
+            apply_operator(editor, op, range, true)
+        }
+        'G' => {
+            let last = text.last_line();
+            let line = LineNo::from_one_based(typed_count).map_or(last, |l| l.min(last));
+            let range = line_range(editor, y.min(line), y.max(line));
+            apply_operator(editor, op, range, true)
+        }
+        'j' | 'k' => {
+            move_cursor(editor, c, count);
+            let (first, last) = (y.min(LineNo(editor.line)), y.max(LineNo(editor.line)));
+            if first == last {
+                return NORMAL;
+            }
+            let range = line_range(editor, first, last);
+            apply_operator(editor, op, range, true)
+        }
+        '%' => {
+            let pos = match percent(editor, typed_count) {
+                Some(p) => p,
+                None => return NORMAL,
+            };
+            if typed_count > 0 {
+                let range = line_range(editor, y.min(pos.line), y.max(pos.line));
+                return apply_operator(editor, op, range, true);
+            }
+            let other = text.char_idx(pos);
+            apply_operator(editor, op, Range::new(idx.min(other), idx.max(other) + 1), false)
+        }
+        'h' | 'l' => {
+            let range = match c {
+                'h' => Range::new(idx - count.min(x), idx),
+                _ => Range::new(idx, idx + count.min(text.get_line_length(y).saturating_sub(x))),
+            };
+            if range.is_empty() {
+                return NORMAL;
+            }
+            apply_operator(editor, op, range, false)
+        }
+        _ => NORMAL,
+    }
+}
+
+// Paste a register after the cursor, or before it with P
+fn put(editor : &mut Editor, before : bool, count : usize) {
+    let reg = editor.register.take().unwrap_or('"');
+    let (text, linewise) = match editor.registers.get(reg) {
+        Some(r) => (r.text.repeat(count), r.linewise),
+        None => match editor.register_text(reg) {
+            Some(t) => (t.repeat(count), false),
+            None => {
+                editor.add_message(&format!("E353: Nothing in register {}", reg));
+                return;
+            }
+        },
+    };
+    if linewise {
+        let lines : Vec<String> = text.strip_suffix('\n').unwrap_or(&text).split('\n').map(String::from).collect();
+        let at = LineNo(if before { editor.line } else { editor.line + 1 });
+        match editor.buffer.text.insert_lines(at, &lines) {
+            Ok(()) => {
+                editor.goto_first_non_blank(at);
+                editor.report(lines.len(), &format!("{} more lines", lines.len()));
+            }
+            Err(e) => editor.add_message(&e.to_string()),
+        }
+        return;
+    }
+    let line = editor.buffer.text.get_line(LineNo(editor.line));
+    if !before && !line.is_empty() {
+        editor.col = width::next_col(line, editor.col);
+    }
+    let start = editor.cursor_idx();
+    put_back(editor, &text);
+    let pos = editor.buffer.text.position(start + text.chars().count().max(1) - 1);
+    (editor.line, editor.col) = (pos.line.0, pos.col);
+}
+
+// Keys after the first key of a normal mode command
+fn handle_normal_pending(pending : String, key : KeyEvent, count : usize, editor : &mut Editor) -> i8 {
+    if pending == "r" {
+        replace_chars(editor, key, count.max(1));
+        return NORMAL;
+    }
+    let c = match key.code {
+        KeyCode::Char(c) if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => c,
+        _ => return NORMAL,
+    };
+    match (pending.as_str(), c) {
+        ("g", 'R') => VREPLACE,
+        ("g", 'j' | 'k') => {
+            move_screen_rows(editor, c == 'j', count.max(1));
+            NORMAL
+        }
+        ("g", '0' | '$') => {
+            screen_row_end(editor, c == '$');
+            NORMAL
+        }
+        ("z", 'h' | 'l' | 's' | 'e') => {
+            scroll_horizontally(editor, c, count.max(1));
+            NORMAL
+        }
+        ("z", _) => NORMAL,
+        ("g", 'J') => {
+            join_count(editor, count.max(1), false);
+            NORMAL
+        }
+        ("g", 'g') => {
+            goto_line(editor, count, LineNo(0));
+            NORMAL
+        }
+        ("g", ';' | ',') => {
+            let step = count.max(1) as i64;
+            match editor.buffer.text.marks_mut().change(if c == ';' { -step } else { step }) {
+                Ok(idx) => editor.goto_idx(idx),
+                Err(e) => editor.add_message(&e),
+            }
+            NORMAL
+        }
+        ("m", c) => {
+            if let Err(e) = editor.set_mark(c, editor.cursor_idx()) {
+                editor.add_message(&e);
+            }
+            NORMAL
+        }
+        ("'" | "`", c) => {
+            goto_mark(editor, c, pending == "`");
+            NORMAL
+        }
+        ("\"", c) => {
+            if register::is_valid(c) {
+                editor.register = Some(c);
+                editor.count = count;
             }
-            stdout().execute(SetCursorStyle::BlinkingBar).unwrap();
+            NORMAL
+        }
+        ("g", _) => NORMAL,
+        _ => operator_pending(pending, c, count, editor),
+    }
+}
+
+fn handle_input_normal(key : KeyEvent, editor : &mut Editor) -> i8 {
+    if !editor.pending.is_empty() {
+        let pending = std::mem::take(&mut editor.pending);
+        let count = std::mem::take(&mut editor.count);
+        return handle_normal_pending(pending, key, count, editor);
+    }
+    if key.modifiers.contains(KeyModifiers::CONTROL) {
+        let count = std::mem::take(&mut editor.count);
+        match key.code {
+            KeyCode::Char('l') => {
+                editor.status.clear();
+                editor.redraw = true;
+            }
+            KeyCode::Char('r') => {
+                editor.undo(true);
+            }
+            KeyCode::Char('o') => {
+                let (idx, count) = (editor.cursor_idx(), count.max(1));
+                if let Some(to) = editor.buffer.text.marks_mut().jump_back(idx, count) {
+                    editor.goto_idx(to);
+                }
+            }
+            _ => (),
+        }
+        return NORMAL;
+    }
+    if key.modifiers.contains(KeyModifiers::ALT) {
+        return NORMAL;
+    }
+
+    // A count before the command, where a leading 0 is not part of it
+    if let KeyCode::Char(c @ '0'..='9') = key.code
+        && (c != '0' || editor.count > 0) {
+        editor.count = editor.count * 10 + (c as usize - '0' as usize);
+        return NORMAL;
+    }
+    let typed_count = std::mem::take(&mut editor.count);
+    let count = typed_count.max(1);
+
+    let (x, y) = (editor.col, editor.line);
+    let buffer = &mut editor.buffer.text;
+
+    match key.code {
+        KeyCode::Char(c @ ('r' | 'g' | 'z' | '"' | 'd' | 'c' | 'y' | '>' | '<' | '=' | 'm' | '\'' | '`')) => {
+            editor.pending.push(c);
+            editor.count = typed_count;
+            NORMAL
+        }
+        KeyCode::Char(c @ ('v' | 'V')) => {
+            editor.visual = Some(Visual { line : y, col : x, linewise : c == 'V' });
+            VISUAL
+        }
+        KeyCode::Char(c @ ('p' | 'P')) => {
+            put(editor, c == 'P', count);
+            NORMAL
+        }
+        KeyCode::Char('R') => REPLACE,
+        KeyCode::Char('J') => {
+            join_count(editor, count, true);
+            NORMAL
+        }
+        KeyCode::Char('u') => {
+            editor.undo(false);
+            NORMAL
+        }
+
+        KeyCode::Char('q') => {
+            if let Err(e) = editor.write(None, false) {
+                editor.message = Some(e);
+                return NORMAL;
+            }
+            QUIT
+        }
+
+        KeyCode::Char(':') => {
+            editor.cmdline.clear();
+            COMMAND
+        }
+
+        KeyCode::Char('a') => {
+            editor.col = width::next_col(buffer.get_line(LineNo(y)), x);
             INSERT
         }
 
         KeyCode::Char('A') => {
-            let line_end : u16 = buffer.get_line_length(y as usize) as u16;
-            let _ = stdout().execute(MoveTo(line_end, y));
-            stdout().execute(SetCursorStyle::BlinkingBar).unwrap();
+            editor.col = buffer.get_line_length(LineNo(y));
             INSERT
         }
 
-        KeyCode::Char('i') => {
-            stdout().execute(SetCursorStyle::BlinkingBar).unwrap();
+        KeyCode::Char('i') => INSERT,
+        KeyCode::Char('I') => {
+            editor.col = 0;
             INSERT
         }
-        KeyCode::Char('I') => {
-            stdout().execute(SetCursorStyle::BlinkingBar).unwrap();
-            stdout().execute(MoveTo(0, y)).unwrap();
+
+        KeyCode::Char(c @ ('o' | 'O')) => {
+            if let Err(e) = editor.open_line(c == 'O') {
+                editor.add_message(&e);
+                return NORMAL;
+            }
             INSERT
         }
 
-        KeyCode::Char('o') => {
-            let line_end : u16 = buffer.get_line_length(y as usize) as u16;
-            if line_end > 0 {
-                match stdout().execute(MoveTo(line_end + 1, y)) {
-                    Ok(_) => (),
-                    Err(_) => panic!("failed to move to next line after o"),
-                }
+        KeyCode::Char(c @ ('h' | 'j' | 'k' | 'l')) => {
+            move_cursor(editor, c, count);
+            NORMAL
+        }
+        // Ctrl-I, which terminals send as Tab
+        KeyCode::Tab => {
+            if let Some(to) = editor.buffer.text.marks_mut().jump_forward(count) {
+                editor.goto_idx(to);
             }
-            match crossterm::cursor::position() {
-                Ok((col, row)) => {
-                    x = col; y = row;
-                }
-                Err(_) => {
-                    eprintln!("Error getting cursor position");
-                    return NORMAL;
-                }
+            NORMAL
+        }
+        KeyCode::Char('G') => {
+            goto_line(editor, typed_count, editor.buffer.text.last_line());
+            NORMAL
+        }
+        KeyCode::Char('%') => {
+            let target = percent(editor, typed_count);
+            if target.is_some() {
+                editor.push_jump();
             }
-            let idx : usize = buffer.get_string_index(y as usize, x as usize);
-            match buffer.write_char("\n", idx - 1) {
-                Ok(_) => (),
-                Err(e) => panic!("Failed writing \\n during 'o': {}\n",e),
+            match target {
+                Some(pos) if typed_count > 0 => editor.goto_first_non_blank(pos.line),
+                Some(pos) => editor.set_cursor(pos),
+                None => (),
             }
-            refresh_text(&buffer);
-            stdout().execute(MoveTo(0, y + 1)).unwrap();
-            stdout().execute(SetCursorStyle::BlinkingBar).unwrap();
-            stdout().flush().unwrap();
-            INSERT
+            NORMAL
         }
+        _ => NORMAL
+    }
+}
+
+// Text object typed after `i` or `a` in Visual mode. A selection of one
+// char becomes the object; a bigger one is extended by it.
+fn select_object(editor : &mut Editor, around : bool, obj : char, count : usize) {
+    let (selected, _) = match editor.selection() {
+        Some(s) => s,
+        None => return,
+    };
+    let text = &editor.buffer.text;
+    let cursor = editor.cursor_idx();
+    let range = if selected.len() <= 1 {
+        text.text_object(cursor, obj, around, count)
+    }
+    else if "wWsp".contains(obj) {
+        text.text_object(selected.end, obj, around, count)
+            .map(|r| Range::new(selected.start, r.end))
+    }
+    else {
+        // The innermost block around the selection that is bigger than it
+        (count..count + 100)
+            .map_while(|n| text.text_object(selected.start, obj, around, n))
+            .find(|r| r.start <= selected.start && selected.end <= r.end && *r != selected)
+    };
+    let range = match range {
+        Some(r) if !r.is_empty() => r,
+        _ => return,
+    };
+    let start = text.position(range.start);
+    let end = text.position(range.end - 1);
+    let v = editor.visual.as_mut().unwrap();
+    v.line = start.line.0;
+    v.col = start.col;
+    v.linewise |= obj == 'p';
+    (editor.line, editor.col) = (end.line.0, end.col);
+}
 
-        KeyCode::Char('O') => {
-            match stdout().execute(MoveTo(0, y)) {
-                    Ok(_) => (),
-                    Err(_) => panic!("failed to move to start of line after O"),
+fn handle_input_visual(key : KeyEvent, editor : &mut Editor) -> i8 {
+    // Keep '< and '> on the selection, so they are right once a key ends it
+    if let Some((range, _)) = editor.selection() {
+        let marks = editor.buffer.text.marks_mut();
+        marks.set('<', range.start);
+        marks.set('>', range.end.max(range.start + 1) - 1);
+    }
+    let new_mode = visual_key(key, editor);
+    if new_mode != VISUAL {
+        editor.visual = None;
+        editor.pending.clear();
+    }
+    new_mode
+}
+
+fn visual_key(key : KeyEvent, editor : &mut Editor) -> i8 {
+    let c = match key.code {
+        KeyCode::Char(c) if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => c,
+        KeyCode::Esc => return NORMAL,
+        _ => return VISUAL,
+    };
+    let typed_count = std::mem::take(&mut editor.count);
+    let count = typed_count.max(1);
+    let pending = std::mem::take(&mut editor.pending);
+    match pending.as_str() {
+        "i" | "a" => {
+            select_object(editor, pending == "a", c, count);
+            return VISUAL;
+        }
+        "\"" => {
+            if register::is_valid(c) {
+                editor.register = Some(c);
             }
+            return VISUAL;
+        }
+        _ => (),
+    }
+    if c.is_ascii_digit() && (c != '0' || typed_count > 0) {
+        editor.count = typed_count * 10 + (c as usize - '0' as usize);
+        return VISUAL;
+    }
 
-            match crossterm::cursor::position() {
-                Ok((col, row)) => {
-                    x = col; y = row;
-                }
-                Err(_) => {
-                    eprintln!("Error getting cursor position");
-                    return NORMAL;
+    let v = editor.visual.unwrap();
+    match c {
+        'i' | 'a' | '"' => {
+            editor.pending.push(c);
+            editor.count = typed_count;
+            VISUAL
+        }
+        'v' | 'V' if v.linewise == (c == 'V') => NORMAL,
+        ':' => {
+            editor.cmdline = String::from("'<,'>");
+            COMMAND
+        }
+        'v' | 'V' => {
+            editor.visual = Some(Visual { linewise : c == 'V', ..v });
+            VISUAL
+        }
+        'o' => {
+            editor.visual = Some(Visual { line : editor.line, col : editor.col, ..v });
+            (editor.line, editor.col) = (v.line, v.col);
+            VISUAL
+        }
+        'h' | 'j' | 'k' | 'l' => {
+            move_cursor(editor, c, count);
+            VISUAL
+        }
+        '%' => {
+            match percent(editor, typed_count) {
+                Some(pos) if typed_count > 0 => editor.goto_first_non_blank(pos.line),
+                Some(pos) => editor.set_cursor(pos),
+                None => (),
+            }
+            VISUAL
+        }
+        'd' | 'x' | 'c' | 's' | 'y' | '>' | '<' | '=' | 'J' => {
+            let (range, linewise) = editor.selection().unwrap();
+            editor.visual = None;
+            let op = match c {
+                'x' => 'd',
+                's' => 'c',
+                c => c,
+            };
+            if op == 'J' {
+                let first = editor.buffer.text.line_of(range.start);
+                let last = editor.buffer.text.line_of(range.end.max(range.start + 1) - 1);
+                let last = last.max(first + 1).min(editor.buffer.text.last_line());
+                if let Err(e) = editor.join_lines(first, last, true) {
+                    editor.add_message(&e);
                 }
+                return NORMAL;
             }
+            apply_operator(editor, op, range, linewise)
+        }
+        _ => VISUAL,
+    }
+}
 
-            let idx : usize = buffer.get_string_index(y as usize, x as usize);
-            match buffer.write_char("\n", idx) {
-                Ok(_) => (),
-                Err(e) => panic!("Failed writing \\n during 'O': {}\n",e),
+// Insert the character for a number typed after Ctrl-V
+fn insert_code(editor : &mut Editor, lead : Option<char>, digits : &str, radix : u32) {
+    match u32::from_str_radix(digits, radix).ok().and_then(char::from_u32) {
+        Some(c) if !digits.is_empty() => insert_text(editor, &c.to_string()),
+        _ => {
+            if let Some(l) = lead {
+                insert_text(editor, &format!("{}{}", l, digits));
             }
-
-            refresh_text(&buffer);
-            stdout().execute(MoveTo(0, y)).unwrap();
-            stdout().execute(SetCursorStyle::BlinkingBar).unwrap();
-            stdout().flush().unwrap();
-            INSERT
         }
+    }
+}
 
-        KeyCode::Char('h') => {
-            if x >= 1 {
-                stdout().execute(MoveLeft(1)).unwrap();
+// Handle the key following Ctrl-R or Ctrl-V. Returns true when the key was
+// used up.
+fn handle_insert_pending(key : KeyEvent, editor : &mut Editor) -> bool {
+    let plain_char = match key.code {
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => Some(c),
+        _ => None,
+    };
+
+    match std::mem::replace(&mut editor.insert_pending, InsertPending::None) {
+        InsertPending::None => false,
+        InsertPending::Register => {
+            if let Some(name) = plain_char
+                && let Some(text) = editor.register_text(name) {
+                insert_text(editor, &text);
             }
-            NORMAL
-        },
-        KeyCode::Char('j') => {
-            if buffer.line_count() - 1 > y {
-                stdout().execute(MoveDown(1)).unwrap();
-                let next_line_length = buffer.get_line_length(1+y as usize) as u16;
-                if next_line_length == 0 {
-                    stdout().execute(MoveTo(0, y+1)).unwrap();
+            true
+        }
+        InsertPending::Literal => {
+            let literal = match (key.code, plain_char) {
+                (_, Some(c @ ('u' | 'U' | 'x' | 'X' | 'o' | 'O'))) => {
+                    let (radix, max) = match c {
+                        'u' => (16, 4),
+                        'U' => (16, 8),
+                        'x' | 'X' => (16, 2),
+                        _ => (8, 3),
+                    };
+                    editor.insert_pending = InsertPending::Code { radix, max, lead : Some(c), digits : String::new() };
+                    return true;
                 }
-                else if x >= next_line_length && x >= 1 {
-                    stdout().execute(MoveTo(next_line_length - 1, y+1)).unwrap();
+                (_, Some(c)) if c.is_ascii_digit() => {
+                    editor.insert_pending = InsertPending::Code { radix : 10, max : 3, lead : None, digits : c.to_string() };
+                    return true;
                 }
+                (_, Some(c)) => Some(c),
+                (KeyCode::Char(c), None) => char::from_u32((c as u32) & 0x1f),
+                (KeyCode::Tab, _) => Some('\t'),
+                (KeyCode::Enter, _) => Some('\r'),
+                (KeyCode::Esc, _) => Some('\x1b'),
+                (KeyCode::Backspace, _) => Some('\x08'),
+                _ => None,
+            };
+            if let Some(c) = literal {
+                insert_text(editor, &c.to_string());
             }
-            NORMAL
-        },
-        KeyCode::Char('k') => {
-            if y > 0 {
-                stdout().execute(MoveUp(1)).unwrap();
-                let next_line_length = buffer.get_line_length(y as usize - 1) as u16;
-                if next_line_length == 0 {
-                    stdout().execute(MoveTo(0, y - 1)).unwrap();
+            true
+        }
+        InsertPending::Code { radix, max, lead, mut digits } => {
+            if let Some(c) = plain_char.filter(|c| c.is_digit(radix)) {
+                digits.push(c);
+                if digits.len() < max {
+                    editor.insert_pending = InsertPending::Code { radix, max, lead, digits };
                 }
-                else if x >= next_line_length {
-                    stdout().execute(MoveTo(next_line_length - 1, y-1)).unwrap();
+                else {
+                    insert_code(editor, lead, &digits, radix);
                 }
+                return true;
             }
-            NORMAL
-        },
-        KeyCode::Char('l') => {
-            if x + 1 < buffer.get_line_length(y as usize) as u16 {
-                stdout().execute(MoveRight(1)).unwrap();
+            // Any other key ends the number and is then handled as usual
+            insert_code(editor, lead, &digits, radix);
+            false
+        }
+    }
+}
+
+fn handle_input_insert(key : KeyEvent, editor : &mut Editor) -> i8 {
+    if handle_insert_pending(key, editor) {
+        return INSERT;
+    }
+    let (x, y) = (editor.col, editor.line);
+    // Anything typed keeps the indent 'autoindent' gave the line
+    let auto_indented = editor.auto_indent.take() == Some(y);
+    let ts = editor.opt_number("tabstop") as usize;
+    let sts = editor.opt_number("softtabstop");
+    let sw = match editor.opt_number("shiftwidth") {
+        0 => ts,
+        n => n as usize,
+    };
+
+    if key.modifiers.contains(KeyModifiers::CONTROL) {
+        match key.code {
+            KeyCode::Char('h') => delete_before(editor, 1),
+            KeyCode::Char('j') => break_line(editor, auto_indented),
+            KeyCode::Char('w') => {
+                // Delete white space, then a run of word or other characters
+                let line : Vec<char> = editor.buffer.text.get_line(LineNo(y)).chars().collect();
+                let mut start = x;
+                while start > 0 && line[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                let is_word = |c : char| c.is_alphanumeric() || c == '_';
+                if start > 0 {
+                    let word = is_word(line[start - 1]);
+                    while start > 0 && !line[start - 1].is_whitespace() && is_word(line[start - 1]) == word {
+                        start -= 1;
+                    }
+                }
+                delete_before(editor, if x == 0 { 1 } else { x - start });
+            }
+            KeyCode::Char('u') => {
+                // Delete what was typed on this line, else back to the indent,
+                // else to the start of the line
+                let line = editor.buffer.text.get_line(LineNo(y));
+                let indent = line.chars().take_while(|c| c.is_whitespace()).count();
+                let start = editor.insert_start.and_then(|a| editor.buffer.text.anchor_position(a));
+                let start = match start {
+                    Some(pos) if pos.line.0 == y && pos.col < x => pos.col,
+                    _ if indent < x => indent,
+                    _ => 0,
+                };
+                delete_before(editor, if x == 0 { 1 } else { x - start });
+            }
+            KeyCode::Char('r') => {
+                editor.insert_pending = InsertPending::Register;
+                editor.status = String::from("\"");
+            }
+            KeyCode::Char('v') | KeyCode::Char('q') => {
+                editor.insert_pending = InsertPending::Literal;
+                editor.status = String::from("^V");
+            }
+            KeyCode::Char('o') => {
+                // Run one normal mode command, then come back to insert mode
+                editor.insert_oneshot = Some(INSERT);
+                return NORMAL;
+            }
+            _ => (),
+        }
+        return INSERT;
+    }
+    if key.modifiers.contains(KeyModifiers::ALT) {
+        return INSERT;
+    }
+
+    match key.code {
+        KeyCode::Esc => {
+            if auto_indented {
+                drop_auto_indent(editor);
+            }
+            let line = editor.buffer.text.get_line(LineNo(y));
+            let line_length = line.chars().count();
+            if editor.col >= line_length && line_length > 0 {
+                editor.col = width::prev_col(line, line_length);
             }
             NORMAL
+        }
+        KeyCode::Enter => {
+            break_line(editor, auto_indented);
+            INSERT
+        }
+        KeyCode::Tab => {
+            let line = String::from(editor.buffer.text.get_line(LineNo(y)));
+            let (delete, text) = indent::soft_tab(&line, x, ts, sts, sw, editor.opt_bool("expandtab"));
+            delete_before(editor, delete);
+            insert_text(editor, &text);
+            INSERT
+        }
+        KeyCode::Backspace => {
+            let line = String::from(editor.buffer.text.get_line(LineNo(y)));
+            match indent::soft_backspace(&line, x, ts, sts, sw) {
+                Some((delete, fill)) => {
+                    delete_before(editor, delete);
+                    insert_text(editor, &fill);
+                }
+                None if x == 0 => delete_before(editor, 1),
+                None => delete_before(editor, x - width::prev_col(&line, x)),
+            }
+            INSERT
+        }
+        KeyCode::Delete => {
+            let buffer = &mut editor.buffer.text;
+            let line = buffer.get_line(LineNo(y));
+            // The whole grapheme, or the line break at the end of the line
+            let n = match width::next_col(line, x) {
+                end if end > x => end - x,
+                _ => 1,
+            };
+            let idx = buffer.char_idx(Position::new(y, x));
+            let removed = match n {
+                1 => buffer.remove_at(idx).map(|_| ()),
+                n => buffer.delete_range(Range::new(idx, idx + n)).map(|_| ()),
+            };
+            match removed {
+                Ok(()) => (),
+                Err(TextError::IndexOutOfRange { .. }) => (),   // Nothing after the cursor
+                Err(e) => editor.add_message(&e.to_string()),
+            }
+            INSERT
+        }
+        KeyCode::Left => {
+            editor.col = width::prev_col(editor.buffer.text.get_line(LineNo(y)), x);
+            INSERT
+        }
+        KeyCode::Right => {
+            editor.col = width::next_col(editor.buffer.text.get_line(LineNo(y)), x);
+            INSERT
+        }
+        KeyCode::Up | KeyCode::Down | KeyCode::PageUp | KeyCode::PageDown => {
+            let last = editor.buffer.text.line_count() - 1;
+            match key.code {
+                KeyCode::Up => editor.line = y.saturating_sub(1),
+                KeyCode::Down => editor.line = (y + 1).min(last),
+                KeyCode::PageUp => scroll_page(editor, false),
+                _ => scroll_page(editor, true),
+            }
+            editor.col = col_below(editor, Position::new(y, x), editor.line);
+            INSERT
+        }
+        KeyCode::Home => {
+            editor.col = 0;
+            INSERT
+        }
+        KeyCode::End => {
+            editor.col = editor.buffer.text.get_line_length(LineNo(y));
+            INSERT
+        }
+        KeyCode::Char('}') if editor.opt_bool("smartindent")
+            && editor.buffer.text.get_line(LineNo(y)).chars().take(x).all(|c| c == ' ' || c == '\t') => {
+            close_brace(editor);
+            INSERT
+        }
+        KeyCode::Char(c) => {
+            insert_text(editor, &c.to_string());
+            INSERT
         },
-        KeyCode::Char('r') => {
-            refresh_text(&buffer);
-            NORMAL
-        },
-        _ => NORMAL
+        _ => INSERT
+    }
+}
+
+// Remove the indent 'autoindent' left on a line nothing was typed on
+fn drop_auto_indent(editor : &mut Editor) {
+    let line = editor.buffer.text.get_line(LineNo(editor.line));
+    if editor.col > 0 && line.chars().all(|c| c == ' ' || c == '\t') {
+        delete_before(editor, editor.col);
     }
 }
 
-fn handle_input_insert(code : KeyCode, buffer : &mut Text) -> i8 {
-    let x : u16; let y : u16;
-    match crossterm::cursor::position() {
-        Ok((col, row)) => {
-            x = col; y = row;
+// Enter in insert mode. With 'autoindent' the new line gets its indent in
+// place of the blanks after the cursor.
+fn break_line(editor : &mut Editor, auto_indented : bool) {
+    if auto_indented {
+        drop_auto_indent(editor);
+    }
+    let line = String::from(editor.buffer.text.get_line(LineNo(editor.line)));
+    let (before, after) = line.split_at(line.char_indices().nth(editor.col).map_or(line.len(), |(i, _)| i));
+    let indent = match editor.new_line_indent(before, after) {
+        Some(indent) => indent,
+        None => return insert_text(editor, "\n"),
+    };
+    let blanks = after.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+    replace_text(editor, blanks, &("\n".to_owned() + &indent));
+    if !indent.is_empty() {
+        editor.auto_indent = Some(editor.line);
+    }
+}
+
+// A `}` typed first on a line with 'smartindent' goes to the indent of the
+// line with its `{`
+fn close_brace(editor : &mut Editor) {
+    let line = editor.buffer.text.get_line(LineNo(editor.line));
+    let blanks : String = line.chars().take(editor.col).collect();
+    if editor.col > 0 {
+        delete_before(editor, editor.col);
+    }
+    insert_text(editor, "}");
+    let indent = match editor.match_pair(editor.cursor_idx() - 1) {
+        Some(open) => {
+            let ts = editor.opt_number("tabstop") as usize;
+            let open_line = editor.buffer.text.get_line(editor.buffer.text.position(open).line);
+            indent::indent_text(indent::indent_width(open_line, ts), ts, editor.opt_bool("expandtab"))
         }
-        Err(_) => {
-            eprintln!("Error getting cursor position");
-            return INSERT;
+        None => blanks,
+    };
+    editor.col = 0;
+    put_back(editor, &indent);
+    editor.col = indent.chars().count() + 1;
+}
+
+// Backspace in Replace mode: put back what the last typed char replaced
+fn replace_backspace(editor : &mut Editor) {
+    let replaced = match editor.replaced.pop() {
+        Some(replaced) => replaced,
+        None => {
+            editor.col = width::prev_col(editor.buffer.text.get_line(LineNo(editor.line)), editor.col);
+            return;
+        }
+    };
+    match editor.buffer.text.restore_replaced(editor.cursor(), &replaced) {
+        Ok(pos) => {
+            (editor.line, editor.col) = (pos.line.0, pos.col);
+            if let Replaced::Char { .. } = replaced {
+                editor.inserted.pop();
+            }
         }
+        Err(e) => editor.add_message(&e.to_string()),
     }
-    let idx : usize = buffer.get_string_index(y as usize, x as usize);
+}
 
-    return match code {
-        KeyCode::Esc => {
-            if x >= buffer.get_line_length(y as usize) as u16 {
-                if buffer.get_line_length(y as usize) > 0 {
-                    stdout().execute(MoveTo(buffer.get_line_length(y as usize) as u16 - 1, y)).unwrap();
-                }
+// Type `c` over the text at the cursor, in Virtual Replace mode over the
+// screen cells it takes
+fn replace_typed(editor : &mut Editor, c : char, virtual_replace : bool) {
+    let ts = editor.opt_number("tabstop") as usize;
+    let line = editor.buffer.text.get_line(LineNo(editor.line));
+    let end = replace::replaced_end(line, editor.col, c, ts, virtual_replace);
+    let removed : String = line.chars().skip(editor.col).take(end - editor.col).collect();
+    replace_text(editor, end - editor.col, &c.to_string());
+    editor.replaced.push(Replaced::Char { removed });
+}
+
+fn handle_input_replace(key : KeyEvent, editor : &mut Editor, mode : i8) -> i8 {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    if editor.insert_pending != InsertPending::None {
+        return match handle_input_insert(key, editor) {
+            INSERT => mode,
+            m => m,
+        };
+    }
+    match key.code {
+        KeyCode::Char(c) if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => {
+            replace_typed(editor, c, mode == VREPLACE);
+        }
+        KeyCode::Tab => replace_typed(editor, '\t', mode == VREPLACE),
+        KeyCode::Backspace => replace_backspace(editor),
+        KeyCode::Char('h') if ctrl => replace_backspace(editor),
+        KeyCode::Enter if mode == VREPLACE => {
+            // The line break replaces the rest of the line and replacing
+            // goes on in the next line, which is only added at the end of
+            // the buffer
+            let idx = editor.cursor_idx();
+            let buffer = &mut editor.buffer.text;
+            let line : String = buffer.get_line(LineNo(editor.line)).chars().skip(editor.col).collect();
+            let n = line.chars().count();
+            if editor.line + 1 == buffer.line_count() {
+                replace_text(editor, n, "\n");
+                editor.replaced.push(Replaced::Char { removed : line });
+            }
+            else if let Err(e) = buffer.delete_range(Range::new(idx, idx + n)) {
+                editor.add_message(&e.to_string());
+            }
+            else {
+                editor.line += 1;
+                editor.col = 0;
+                editor.replaced.push(Replaced::Line { removed : line });
             }
-            stdout().execute(SetCursorStyle::SteadyBlock).unwrap();
-            NORMAL
         }
         KeyCode::Enter => {
-            if buffer.size() < idx {
-                return INSERT;
+            insert_text(editor, "\n");
+            editor.replaced.push(Replaced::Char { removed : String::new() });
+        }
+        _ => {
+            // Other keys work as in insert mode, but moving the cursor
+            // forgets what can be restored
+            editor.replaced.clear();
+            if ctrl && key.code == KeyCode::Char('o') {
+                editor.insert_oneshot = Some(mode);
+                return NORMAL;
             }
-            let _ = buffer.write_char("\n", idx);
-            refresh_text(&buffer);
-            stdout().execute(MoveTo(0, y + 1)).unwrap();
-            stdout().flush().unwrap();
-            INSERT
+            return match handle_input_insert(key, editor) {
+                INSERT => mode,
+                m => m,
+            };
+        }
+    }
+    mode
+}
 
+fn handle_input_command(key : KeyEvent, editor : &mut Editor) -> i8 {
+    if key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+        return COMMAND;
+    }
+    match key.code {
+        KeyCode::Esc => {
+            editor.status.clear();
+            NORMAL
+        }
+        KeyCode::Backspace if editor.cmdline.is_empty() => {
+            editor.status.clear();
+            NORMAL
         }
         KeyCode::Backspace => {
-            if buffer.size() == 0 || idx == 0 {
-                return INSERT;
-            }
-            if x == 0 {
-                let old_prev_line_length = buffer.get_line_length(y as usize - 1) as u16;
-                let _ = buffer.remove_at(idx - 1);
-                if y == 0 {
-                    return INSERT;
+            editor.cmdline.pop();
+            COMMAND
+        }
+        KeyCode::Enter => {
+            editor.status.clear();
+            let line = std::mem::take(&mut editor.cmdline);
+            editor.registers.set_special(':', &line);
+            match ex::execute(editor, &line) {
+                Ok(mode) => mode,
+                Err(e) => {
+                    editor.add_message(&e);
+                    NORMAL
                 }
-                stdout().execute(MoveTo(old_prev_line_length , y - 1)).unwrap();
-                refresh_text(&buffer);
             }
-            else {
-                let _ = buffer.remove_at(idx - 1);
-                if x >= 1 {
-                    stdout().execute(MoveLeft(1)).unwrap();
-                    stdout().execute(SavePosition).unwrap();
-                    stdout().execute(MoveTo(0,y)).unwrap();
-                    print!("{} ", buffer.get_line(y as usize));
-                    stdout().execute(RestorePosition).unwrap();
+        }
+        KeyCode::Char(c) => {
+            editor.cmdline.push(c);
+            COMMAND
+        }
+        _ => COMMAND
+    }
+}
+
+// Hand one key to the handler for the current mode
+fn dispatch(key : KeyEvent, mode : i8, editor : &mut Editor) -> i8 {
+    let before = editor.cursor_idx();
+    let new_mode = match mode {
+        NORMAL => handle_input_normal(key, editor),
+        INSERT => handle_input_insert(key, editor),
+        REPLACE | VREPLACE => handle_input_replace(key, editor, mode),
+        VISUAL => handle_input_visual(key, editor),
+        COMMAND => handle_input_command(key, editor),
+        _ => mode,
+    };
+    finish_input(editor, mode, new_mode, before)
+}
+
+// Screen cell `column`, `row` as a cursor position, on the last line when
+// below it. Rows past the text area give None.
+fn position_at_cell(editor : &Editor, column : u16, row : u16) -> Option<Position> {
+    if row as usize >= text_rows(editor) {
+        return None;
+    }
+    // A click in the gutter goes to the start of the line
+    let column = (column as usize).saturating_sub(editor.gutter().width());
+    let text = &editor.buffer.text;
+    let wrap = match editor.wrap(text_width(editor)) {
+        Some(w) => w,
+        None => {
+            let line = LineNo(editor.top + row as usize).min(text.last_line());
+            let cell = editor.left + column;
+            return Some(Position { line, col : width::col_at_cell(text.get_line(line), cell, editor.screen_tabstop()) });
+        }
+    };
+    let mut first = 0;
+    for line_no in editor.top..text.line_count() {
+        let line = text.get_line(LineNo(line_no));
+        let rows = wrap.rows(line);
+        if (row as usize) < first + rows.len() || line_no == text.last_line().0 {
+            let r = (row as usize - first).min(rows.len() - 1);
+            let col = wrap.col_in_row(line, rows[r], column, r + 1 == rows.len());
+            return Some(Position::new(line_no, col));
+        }
+        first += rows.len();
+    }
+    None
+}
+
+// Mouse events where 'mouse' enables them: a click puts the cursor there,
+// a double or triple click selects a word or the line, dragging selects
+// text and the wheel scrolls
+fn handle_mouse(ev : MouseEvent, mode : i8, editor : &mut Editor, clicks : &mut Clicks) -> i8 {
+    let flag = match mode {
+        INSERT | REPLACE | VREPLACE => 'i',
+        VISUAL => 'v',
+        COMMAND => return mode,
+        _ => 'n',
+    };
+    if !mouse::enabled(editor.opt_str("mouse"), flag) {
+        return mode;
+    }
+    let before = editor.cursor_idx();
+    let new_mode = match ev.kind {
+        MouseEventKind::ScrollDown | MouseEventKind::ScrollUp => {
+            let last = editor.buffer.text.last_line().0;
+            editor.top = match ev.kind {
+                MouseEventKind::ScrollDown => (editor.top + 3).min(last),
+                _ => editor.top.saturating_sub(3),
+            };
+            // Take the cursor along when it goes off screen
+            let line = editor.line.clamp(editor.top, editor.top + text_rows(editor) - 1);
+            if line != editor.line {
+                editor.set_cursor(Position::new(line, editor.col));
+            }
+            mode
+        }
+        MouseEventKind::Down(MouseButton::Left) => {
+            let within = Duration::from_millis(editor.opt_number("mousetime") as u64);
+            let pos = match position_at_cell(editor, ev.column, ev.row) {
+                Some(p) => p,
+                None => return mode,
+            };
+            editor.visual = None;
+            let text = &editor.buffer.text;
+            match clicks.click(Instant::now(), ev.column, ev.row, within) {
+                1 if is_insert(mode) => {
+                    (editor.line, editor.col) = (pos.line.0, pos.col.min(text.get_line_length(pos.line)));
+                    mode
+                }
+                1 => {
+                    editor.set_cursor(pos);
+                    NORMAL
+                }
+                2 => {
+                    let idx = text.char_idx(pos);
+                    let word = text.text_object(idx, 'w', false, 1).unwrap_or(Range::new(idx, idx + 1));
+                    let start = text.position(word.start);
+                    editor.visual = Some(Visual { line : start.line.0, col : start.col, linewise : false });
+                    editor.set_cursor(text.position(word.end.max(word.start + 1) - 1));
+                    VISUAL
+                }
+                _ => {
+                    editor.visual = Some(Visual { line : pos.line.0, col : 0, linewise : true });
+                    editor.set_cursor(pos);
+                    VISUAL
                 }
             }
-            INSERT
+        }
+        MouseEventKind::Drag(MouseButton::Left) => {
+            let row = ev.row.min(text_rows(editor) as u16 - 1);
+            let pos = match position_at_cell(editor, ev.column, row) {
+                Some(p) => p,
+                None => return mode,
+            };
+            if editor.visual.is_none() {
+                let (column, row) = clicks.cell().unwrap_or((ev.column, row));
+                let start = position_at_cell(editor, column, row).unwrap_or(pos);
+                editor.set_cursor(start);
+                editor.visual = Some(Visual { line : editor.line, col : editor.col, linewise : false });
+            }
+            editor.set_cursor(pos);
+            VISUAL
+        }
+        _ => mode,
+    };
+    finish_input(editor, mode, new_mode, before)
+}
+
+// Keep the cursor line on screen at the new terminal size. The next frame
+// is drawn in full since its size changed.
+fn resized(editor : &mut Editor) {
+    scroll_to_cursor(editor);
+}
+
+// Back in the terminal: the file may have been changed meanwhile
+fn focus_gained(editor : &mut Editor) {
+    let tick = editor.buffer.text.changedtick();
+    editor.fire(Event::FocusGained);
+    editor.check_time();
+    if editor.buffer.text.changedtick() != tick {
+        scroll_to_cursor(editor);
+    }
+}
 
+// Pasted text, which arrives in one piece with bracketed paste. It is
+// inserted as it is, also in normal and Visual mode where typing it would
+// run it as commands.
+fn paste(s : &str, mode : i8, editor : &mut Editor) -> i8 {
+    let before = editor.cursor_idx();
+    let (s, last) = format::pasted(s);
+    let new_mode = match mode {
+        COMMAND => {
+            editor.cmdline.push_str(&s.replace('\n', " "));
+            COMMAND
         }
-        KeyCode::Char(c) => {
-            if buffer.size() < idx {
-                return INSERT;
-            }
-            match buffer.write_char(&c.to_string(), idx) {
-                Ok(_) => (),
-                Err(_) => {
-                    let new_y : u16 = buffer.line_count() - 1;
-                    let new_x : u16 = buffer.get_line_length(new_y as usize + 1 ) as u16;
-                    MoveTo(new_x,new_y);
+        INSERT | REPLACE | VREPLACE => {
+            // The paste is not the key Ctrl-R or Ctrl-V waits for, and it
+            // ends a number typed after Ctrl-V
+            if let InsertPending::Code { radix, lead, digits, .. } = std::mem::replace(&mut editor.insert_pending, InsertPending::None) {
+                insert_code(editor, lead, &digits, radix);
+            }
+            editor.replaced.clear();
+            insert_text(editor, &s);
+            mode
+        }
+        _ => {
+            editor.visual = None;
+            editor.pending.clear();
+            editor.count = 0;
+            match editor.buffer.text.insert_str(before, &s) {
+                Ok(()) => {
+                    let pos = editor.buffer.text.position(before + last);
+                    editor.set_cursor(pos);
                 }
+                Err(e) => editor.add_message(&e.to_string()),
             }
-            //print!("{}",c)
-            //stdout().flush().unwrap();
-            if c == '\n' {
-                stdout().execute(MoveTo(0, y+1)).unwrap();
+            NORMAL
+        }
+    };
+    finish_input(editor, mode, new_mode, before)
+}
+
+// What follows a key or a paste that took `mode` to `new_mode`, with the
+// screen scrolled to `top` and the cursor at `before` when it came.
+fn finish_input(editor : &mut Editor, mode : i8, mut new_mode : i8, before : CharIdx) -> i8 {
+    // After Ctrl-O, go back to insert mode once the command is done
+    let oneshot = editor.insert_oneshot;
+    if let Some(back) = oneshot
+        && !is_insert(mode) && new_mode == NORMAL && editor.pending.is_empty() && editor.count == 0 {
+        new_mode = back;
+    }
+    if new_mode != NORMAL && new_mode != COMMAND {
+        editor.insert_oneshot = None;
+    }
+
+    if is_insert(new_mode) && !is_insert(mode) {
+        if oneshot.is_none() {
+            let idx = editor.cursor_idx();
+            let text = &mut editor.buffer.text;
+            if let Some(old) = editor.insert_start.take() {
+                text.remove_anchor(old);
             }
-            else {
-                stdout().execute(MoveRight(1)).unwrap();
-            }
-            
-            stdout().execute(SavePosition).unwrap();
-            stdout().execute(MoveTo(0,y)).unwrap();
-            print!("{}", buffer.get_line(y as usize));
-            stdout().execute(RestorePosition).unwrap();
-            
-            INSERT
-        },
-        _ => INSERT
+            // Text typed at the start goes after it
+            editor.insert_start = Some(text.anchor(idx, Gravity::Left));
+            editor.inserted.clear();
+            editor.replaced.clear();
+            editor.fire(Event::InsertEnter);
+        }
+    }
+    else if is_insert(mode) && !is_insert(new_mode) && editor.insert_oneshot.is_none() {
+        let inserted = std::mem::take(&mut editor.inserted);
+        editor.registers.set_special('.', &inserted);
+        editor.buffer.text.marks_mut().set('^', before);
+        editor.fire(Event::InsertLeave);
+    }
+    if new_mode != mode {
+        set_cursor_style(editor, new_mode);
+    }
+    // Everything typed in insert mode is one change, and so is each command
+    if !is_insert(new_mode) && new_mode != COMMAND && editor.pending.is_empty() {
+        editor.buffer.text.close_change();
+    }
+
+    scroll_to_cursor(editor);
+    show_match(editor, new_mode);
+    new_mode
+}
+
+// Highlight the bracket under the cursor and its match. In insert mode the
+// bracket just before the cursor counts too.
+fn show_match(editor : &mut Editor, mode : i8) {
+    let idx = editor.cursor_idx();
+    // Only a match on screen is shown, so the search stops at its lines
+    let first = LineNo(editor.top);
+    let last = LineNo((editor.top + text_rows(editor)).saturating_sub(1)).min(editor.buffer.text.last_line());
+    let mut matched = None;
+    if mode != COMMAND {
+        matched = editor.bracket_pair(idx, first, last);
+        if matched.is_none() && is_insert(mode) && editor.col > 0 {
+            matched = editor.bracket_pair(idx - 1, first, last);
+        }
+    }
+    editor.matched = matched;
+}
+
+// A key on its way from the terminal to the mode handlers
+struct QueuedKey {
+    key : Key,
+    noremap : bool, // Came from a noremap right-hand side
+    silent : bool,  // Came from a <silent> mapping
+}
+
+fn map_mode(mode : i8, editor : &Editor) -> u8 {
+    match mode {
+        INSERT | REPLACE | VREPLACE => MAP_INSERT,
+        COMMAND => MAP_CMDLINE,
+        VISUAL => MAP_VISUAL,
+        _ if editor.pending.starts_with(['d', 'c', 'y', '>', '<', '=']) => MAP_OP_PENDING,
+        _ => MAP_NORMAL,
     }
 }
 
+// Run queued keys through the key mappings and pass the result on to the
+// mode handlers. Keys that may still become part of a mapping stay queued.
+fn process_keys(editor : &mut Editor, mut mode : i8, queue : &mut VecDeque<QueuedKey>, timed_out : bool) -> i8 {
+    let mut depth : i64 = 0;
+    while let Some(first) = queue.front() {
+        if mode == QUIT {
+            break;
+        }
+        if first.noremap {
+            let q = queue.pop_front().unwrap();
+            editor.silent = q.silent;
+            mode = dispatch(q.key.event(), mode, editor);
+            continue;
+        }
+
+        let silent = first.silent;
+        let keys : Vec<Key> = queue.iter().take_while(|q| !q.noremap).map(|q| q.key).collect();
+        let maps = [&editor.buffer.maps, &editor.maps];
+        match keymap::resolve(&maps, map_mode(mode, editor), &keys, timed_out) {
+            Resolved::Wait => break,
+            Resolved::Mapped(m, consumed) => {
+                depth += 1;
+                if depth > editor.opt_number("maxmapdepth") {
+                    queue.clear();
+                    editor.add_message("E223: recursive mapping");
+                    break;
+                }
+                // Like vi, a right-hand side starting with its own
+                // left-hand side does not remap that part
+                let own_prefix = if m.rhs.starts_with(&m.lhs) { m.lhs.len() } else { 0 };
+                let noremap = m.noremap;
+                let silent = silent || m.silent;
+                let rhs = m.rhs.clone();
+                queue.drain(..consumed);
+                for (i, key) in rhs.into_iter().enumerate().rev() {
+                    queue.push_front(QueuedKey { key, noremap : noremap || i < own_prefix, silent });
+                }
+            }
+            Resolved::Unmapped => {
+                let q = queue.pop_front().unwrap();
+                editor.silent = q.silent;
+                mode = dispatch(q.key.event(), mode, editor);
+            }
+        }
+    }
+    editor.silent = false;
+    mode
+}
+
 fn main() -> std::io::Result<()> {
     let mut stdout = stdout();
     let args : Vec<String> = env::args().collect();
-    let mut buffer: Text;
-
-    if args.len() > 1 {
-        let filename = args.get(1).unwrap();
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)?; 
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        buffer = Text::new(&contents);
+    let mut rc : Option<&str> = None;
+    let mut filename : Option<&str> = None;
+
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "-u" => rc = arg_iter.next().map(String::as_str),
+            _ => filename = Some(arg),
+        }
     }
-    else {
-        println!("Usage: editor {{filename}}");
-        std::process::exit(1);
+    let filename = match filename {
+        Some(f) => f,
+        None => {
+            println!("Usage: editor [-u {{config}}|NONE] {{filename}}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut editor = Editor::new();
+    if let Err(e) = config::load(&mut editor, rc) {
+        editor.add_message(&e);
     }
 
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(filename)?; 
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    editor.read_buffer(&contents, Some(filename));
+    editor.buffer.mark_disk_time();
+    editor.fire(Event::BufReadPost);
+    editor.detect_filetype();
+    editor.apply_modelines();
+    editor.fire(Event::VimEnter);
+
     let _guard = RawModeGuard::new();
-    stdout.execute(terminal::Clear(ClearType::All)).unwrap();
-    //let (width, height) = terminal::size().unwrap();
-    stdout.execute(MoveTo(0,0)).unwrap();
-    refresh_text(&buffer);
-    stdout.execute(SetCursorStyle::SteadyBlock).unwrap();
+    set_cursor_style(&editor, NORMAL);
+    let mut screen = Screen::new();
+    draw(&mut editor, NORMAL, &mut screen);
 
     // Main loop
     let mut mode = NORMAL;
+    let mut queue : VecDeque<QueuedKey> = VecDeque::new();
+    let mut last_key = Instant::now();
+    let mut clicks = Clicks::default();
+    let mut capturing = false;
     loop {
-        if event::poll(std::time::Duration::from_millis(500)).unwrap() {
-            if let event::Event::Key(KeyEvent {code, ..}) = event::read().unwrap() {
-                mode = match mode {
-                    NORMAL => {
-                        handle_input_normal(code, &mut buffer)
-                    },
-                    INSERT => {
-                        handle_input_insert(code, &mut buffer)
-                    },
-                    
-                    _ => continue,
+        // Only take the mouse from the terminal while 'mouse' is set
+        if editor.opt_str("mouse").is_empty() == capturing {
+            capturing = !capturing;
+            let _ = match capturing {
+                true => stdout.execute(EnableMouseCapture),
+                false => stdout.execute(DisableMouseCapture),
+            };
+        }
+        let timeoutlen = Duration::from_millis(editor.opt_number("timeoutlen") as u64);
+        let waiting = !queue.is_empty() && editor.opt_bool("timeout");
+        let timeout = if waiting {
+            timeoutlen.saturating_sub(last_key.elapsed())
+        } else {
+            Duration::from_millis(editor.opt_number("updatetime") as u64)
+        };
+        if event::poll(timeout).unwrap() {
+            match event::read().unwrap() {
+                // A key only takes a long message away
+                event::Event::Key(key) if key.kind != KeyEventKind::Release && editor.hit_enter.is_some() => {
+                    editor.hit_enter = None;
+                    editor.status.clear();
+                }
+                event::Event::Key(key) if key.kind != KeyEventKind::Release => {
+                    queue.push_back(QueuedKey { key : key.into(), noremap : false, silent : false });
+                    last_key = Instant::now();
+                    mode = process_keys(&mut editor, mode, &mut queue, false);
+                }
+                event::Event::Mouse(ev) => {
+                    mode = process_keys(&mut editor, mode, &mut queue, true);
+                    if mode != QUIT {
+                        mode = handle_mouse(ev, mode, &mut editor, &mut clicks);
+                    }
+                }
+                event::Event::Resize(_, _) => resized(&mut editor),
+                event::Event::FocusGained => focus_gained(&mut editor),
+                event::Event::FocusLost => editor.fire(Event::FocusLost),
+                event::Event::Paste(s) => {
+                    // Keys typed before it are not waiting for more
+                    mode = process_keys(&mut editor, mode, &mut queue, true);
+                    if mode != QUIT {
+                        mode = paste(&s, mode, &mut editor);
+                    }
                 }
+                _ => (),
             }
         }
+        else if waiting && last_key.elapsed() >= timeoutlen {
+            // Nothing more was typed, so use what matches so far
+            mode = process_keys(&mut editor, mode, &mut queue, true);
+        }
         if mode == QUIT {
             break;
         }
+        draw(&mut editor, mode, &mut screen);
     }
+    editor.fire(Event::VimLeavePre);
     stdout.execute(terminal::Clear(ClearType::All)).unwrap();
-    MoveTo(0,0);
+    stdout.execute(MoveTo(0,0)).unwrap();
+    stdout.execute(SetCursorStyle::DefaultUserShape).unwrap();
     drop(_guard);
     println!("Exiting...");
-    if args.len() > 1 {
-        //let mut file = File::create(format!("{}{}",args.get(1).unwrap(), ".new"))?;
-        //file.write_all(buffer.get_text());
-        let _ = fs::write(args.get(1).unwrap(), buffer.get_text());
-    }
-    return Ok(());
+    Ok(())
 }
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use crate::anchor::Gravity;
+use crate::pos::CharIdx;
+
+/// Marks and the jump and change lists of a buffer. Positions are char
+/// indices, which `Text` moves along when text before them changes.
+#[derive(Default)]
+pub struct Marks {
+    named : HashMap<char, CharIdx>,
+    jumps : Vec<CharIdx>,
+    jump_idx : usize,       // Where Ctrl-O and Ctrl-I are in the jump list
+    changes : Vec<CharIdx>,
+    change_idx : usize,     // Where g; and g, are in the change list
+}
+
+impl Marks {
+    pub fn get(&self, name : char) -> Option<CharIdx> {
+        self.named.get(&name).copied()
+    }
+
+    pub fn set(&mut self, name : char, idx : CharIdx) {
+        self.named.insert(name, idx);
+    }
+
+    /// Marks in name order.
+    pub fn list(&self) -> Vec<(char, CharIdx)> {
+        let mut marks : Vec<(char, CharIdx)> = self.named.iter().map(|(c, i)| (*c, *i)).collect();
+        marks.sort();
+        marks
+    }
+
+    fn positions(&mut self) -> impl Iterator<Item = &mut CharIdx> {
+        self.named.values_mut().chain(self.jumps.iter_mut()).chain(self.changes.iter_mut())
+    }
+
+    /// Follow an edit replacing `removed` chars at `idx` with `inserted`
+    /// chars. Marks stay with the char they are on, so they have right
+    /// gravity.
+    pub fn adjust(&mut self, idx : usize, removed : usize, inserted : usize) {
+        for pos in self.positions() {
+            pos.0 = Gravity::Right.adjust(pos.0, idx, removed, inserted);
+        }
+    }
+
+    /// Remember `from` as the place a jump was made from.
+    pub fn push_jump(&mut self, from : CharIdx) {
+        self.jumps.retain(|j| *j != from);
+        self.jumps.push(from);
+        self.jump_idx = self.jumps.len();
+        self.named.insert('\'', from);
+    }
+
+    /// Ctrl-O: the position `count` jumps back from `current`.
+    pub fn jump_back(&mut self, current : CharIdx, count : usize) -> Option<CharIdx> {
+        if self.jump_idx < count {
+            return None;
+        }
+        if self.jump_idx == self.jumps.len() {
+            // Come back here with Ctrl-I
+            self.push_jump(current);
+            self.jump_idx -= 1;
+            if self.jump_idx < count {
+                return None;
+            }
+        }
+        self.jump_idx -= count;
+        Some(self.jumps[self.jump_idx])
+    }
+
+    /// Ctrl-I: the position `count` jumps forward.
+    pub fn jump_forward(&mut self, count : usize) -> Option<CharIdx> {
+        if self.jump_idx + count >= self.jumps.len() {
+            return None;
+        }
+        self.jump_idx += count;
+        Some(self.jumps[self.jump_idx])
+    }
+
+    pub fn last_change(&self) -> Option<CharIdx> {
+        self.changes.last().copied()
+    }
+
+    /// Add a change at `idx`, or move the last one there with `replace_last`,
+    /// as when they are on the same line.
+    pub fn push_change(&mut self, idx : CharIdx, replace_last : bool) {
+        match self.changes.last_mut() {
+            Some(last) if replace_last => *last = idx,
+            _ => self.changes.push(idx),
+        }
+        self.change_idx = self.changes.len();
+    }
+
+    /// g; with a negative `step` and g, with a positive one.
+    pub fn change(&mut self, step : i64) -> Result<CharIdx, String> {
+        if self.changes.is_empty() {
+            return Err(String::from("E664: Changelist is empty"));
+        }
+        let to = self.change_idx as i64 + step;
+        if to < 0 {
+            return Err(String::from("E662: At start of changelist"));
+        }
+        if to as usize >= self.changes.len() {
+            return Err(String::from("E663: At end of changelist"));
+        }
+        self.change_idx = to as usize;
+        Ok(self.changes[self.change_idx])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(idx : usize) -> Option<CharIdx> {
+        Some(CharIdx(idx))
+    }
+
+    #[test]
+    fn test_adjust() {
+        let mut m = Marks::default();
+        m.set('a', CharIdx(5));
+        m.set('b', CharIdx(10));
+        m.set('c', CharIdx(2));
+        m.adjust(5, 0, 3);
+        assert_eq!((m.get('a'), m.get('b'), m.get('c')), (at(8), at(13), at(2)));
+        m.adjust(6, 4, 0);
+        assert_eq!((m.get('a'), m.get('b')), (at(6), at(9)));
+        m.adjust(0, 2, 1);
+        assert_eq!((m.get('c'), m.get('a')), (at(1), at(5)));
+    }
+
+    #[test]
+    fn test_jump_list() {
+        let mut m = Marks::default();
+        assert_eq!(m.jump_back(CharIdx(0), 1), None);
+        m.push_jump(CharIdx(10));
+        m.push_jump(CharIdx(20));
+        assert_eq!(m.get('\''), at(20));
+        assert_eq!(m.jump_back(CharIdx(30), 1), at(20));
+        assert_eq!(m.jump_back(CharIdx(20), 1), at(10));
+        assert_eq!(m.jump_back(CharIdx(10), 1), None);
+        assert_eq!(m.jump_forward(1), at(20));
+        assert_eq!(m.jump_forward(1), at(30));
+        assert_eq!(m.jump_forward(1), None);
+        // Jumping again from an earlier place drops its old entry
+        m.push_jump(CharIdx(10));
+        assert_eq!(m.jumps, vec![CharIdx(20), CharIdx(30), CharIdx(10)]);
+    }
+
+    #[test]
+    fn test_change_list() {
+        let mut m = Marks::default();
+        assert!(m.change(-1).unwrap_err().starts_with("E664"));
+        m.push_change(CharIdx(3), false);
+        m.push_change(CharIdx(5), true);
+        m.push_change(CharIdx(40), false);
+        assert_eq!(m.changes, vec![CharIdx(5), CharIdx(40)]);
+        assert_eq!(m.change(-1), Ok(CharIdx(40)));
+        assert_eq!(m.change(-1), Ok(CharIdx(5)));
+        assert!(m.change(-1).unwrap_err().starts_with("E662"));
+        assert_eq!(m.change(1), Ok(CharIdx(40)));
+        assert!(m.change(1).unwrap_err().starts_with("E663"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::pos::{CharIdx, LineNo, Range};
+use crate::text::Text;
+
+/// Pairs in a 'matchpairs' value like "(:),[:]".
+pub fn parse_pairs(s : &str) -> Option<Vec<(char, char)>> {
+    s.split(',').filter(|p| !p.is_empty()).map(|p| {
+        let chars : Vec<char> = p.chars().collect();
+        match chars[..] {
+            [open, ':', close] if open != close => Some((open, close)),
+            _ => None,
+        }
+    }).collect()
+}
+
+// For each char, whether it is inside a string delimited by one of
+// `quotes`. Strings end at the end of the line.
+fn in_string(chars : &[char], quotes : &str) -> Vec<bool> {
+    let mut inside = Vec::with_capacity(chars.len());
+    let mut open : Option<char> = None;
+    let mut escaped = false;
+    for c in chars {
+        let quote = !escaped && quotes.contains(*c);
+        match open {
+            _ if *c == '\n' => open = None,
+            Some(q) if quote && *c == q => {
+                inside.push(true);
+                open = None;
+                escaped = false;
+                continue;
+            }
+            None if quote => open = Some(*c),
+            _ => (),
+        }
+        escaped = !escaped && *c == '\\';
+        inside.push(open.is_some());
+    }
+    inside
+}
+
+impl Text {
+    /// Where `%` goes from char index `idx`: the match of the first bracket
+    /// of `pairs` or C comment delimiter at or after `idx` in its line.
+    /// Brackets inside strings delimited by `quotes`, or inside a char
+    /// literal like '(', are skipped unless the cursor is in a string too.
+    pub fn match_pair(&self, idx : CharIdx, pairs : &[(char, char)], quotes : &str) -> Option<CharIdx> {
+        let chars : Vec<char> = self.get_text().chars().collect();
+        find_match(&chars, idx.0, pairs, quotes).map(CharIdx)
+    }
+
+    /// Like `match_pair`, but only looking at lines `first` to `last`, the
+    /// way the match shown at the cursor stops at the lines on screen.
+    pub fn match_pair_in(&self, idx : CharIdx, pairs : &[(char, char)], quotes : &str, first : LineNo, last : LineNo) -> Option<CharIdx> {
+        let start = self.line_start(first);
+        let end = self.line_start(last) + self.get_line_length(last);
+        if idx < start || idx > end {
+            return None;
+        }
+        let chars : Vec<char> = self.slice(Range::new(start, end)).chars().collect();
+        find_match(&chars, idx.0 - start.0, pairs, quotes).map(|i| start + i)
+    }
+}
+
+// The `%` match of the bracket or comment at or after `idx`
+fn find_match(chars : &[char], idx : usize, pairs : &[(char, char)], quotes : &str) -> Option<usize> {
+    let inside = in_string(chars, quotes);
+    let is_bracket = |i : usize| pairs.iter().any(|(o, c)| chars[i] == *o || chars[i] == *c);
+    let literal = |i : usize| i > 0 && chars[i - 1] == '\'' && chars.get(i + 1) == Some(&'\'');
+    let at = |i : usize, s : &str| s.chars().enumerate().all(|(n, c)| chars.get(i + n) == Some(&c));
+
+    let line_end = chars[idx.min(chars.len())..].iter().position(|c| *c == '\n').map_or(chars.len(), |n| idx + n);
+    for pos in idx..line_end {
+        // Comment delimiters, with the cursor on either of their chars
+        if at(pos, "/*") || pos > 0 && at(pos - 1, "/*") && pos == idx {
+            let start = if chars[pos] == '*' { pos + 1 } else { pos + 2 };
+            return (start..chars.len()).find(|i| at(*i, "*/")).map(|i| i + 1);
+        }
+        if at(pos, "*/") || pos > 0 && at(pos - 1, "*/") && pos == idx {
+            let close = if chars[pos] == '*' { pos } else { pos - 1 };
+            return (0..close).rev().find(|i| at(*i, "/*"));
+        }
+        if !is_bracket(pos) || pos != idx && (inside[pos] != inside[idx] || literal(pos)) {
+            continue;
+        }
+        let skip = |i : usize| inside[i] != inside[pos] || literal(i);
+        let (open, close) = *pairs.iter().find(|(o, c)| chars[pos] == *o || chars[pos] == *c)?;
+        let forward = chars[pos] == open;
+        let mut depth = 0;
+        let mut i = pos;
+        loop {
+            if forward {
+                i += 1;
+                if i >= chars.len() {
+                    return None;
+                }
+            }
+            else {
+                i = i.checked_sub(1)?;
+            }
+            if skip(i) {
+                continue;
+            }
+            if chars[i] == chars[pos] {
+                depth += 1;
+            }
+            else if chars[i] == if forward { close } else { open } {
+                if depth == 0 {
+                    return Some(i);
+                }
+                depth -= 1;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAIRS : &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}')];
+
+    fn matched(s : &str, idx : usize) -> Option<usize> {
+        Text::new(s).match_pair(CharIdx(idx), PAIRS, "\"").map(|m| m.0)
+    }
+
+    #[test]
+    fn test_parse_pairs() {
+        assert_eq!(parse_pairs("(:),<:>"), Some(vec![('(', ')'), ('<', '>')]));
+        assert_eq!(parse_pairs(""), Some(vec![]));
+        assert_eq!(parse_pairs("(:)x"), None);
+        assert_eq!(parse_pairs("a:a"), None);
+    }
+
+    #[test]
+    fn test_brackets() {
+        let s = "f(a[1], {b})";
+        assert_eq!(matched(s, 1), Some(11));
+        assert_eq!(matched(s, 11), Some(1));
+        assert_eq!(matched(s, 3), Some(5));
+        assert_eq!(matched(s, 8), Some(10));
+        // Searches forward in the line for a bracket
+        assert_eq!(matched(s, 0), Some(11));
+        assert_eq!(matched("x = 1", 0), None);
+        assert_eq!(matched("(unclosed", 0), None);
+    }
+
+    #[test]
+    fn test_over_lines_and_multibyte() {
+        let s = "fn æ() {\n    ø;\n}";
+        assert_eq!(matched(s, 7), Some(16));
+        assert_eq!(matched(s, 16), Some(7));
+        assert_eq!(matched(s, 0), Some(5));
+    }
+
+    #[test]
+    fn test_strings_and_literals() {
+        let s = "f(\")\", ')', x)";
+        assert_eq!(matched(s, 1), Some(13));
+        // Inside a string, only brackets in strings count
+        assert_eq!(matched("a(\"(b)\")", 3), Some(5));
+        assert_eq!(matched("f(\"\\\")\")", 1), Some(7));
+        // Without string quotes, the bracket in the string is the match
+        assert_eq!(Text::new("f(\")\")").match_pair(CharIdx(1), PAIRS, ""), Some(CharIdx(3)));
+    }
+
+    #[test]
+    fn test_comments() {
+        let s = "a /* b\n c */ d";
+        assert_eq!(matched(s, 2), Some(11));
+        assert_eq!(matched(s, 3), Some(11));
+        assert_eq!(matched(s, 11), Some(2));
+        assert_eq!(matched(s, 10), Some(2));
+        assert_eq!(matched(s, 0), Some(11));
+    }
+
+    #[test]
+    fn test_match_in_lines() {
+        let text = Text::new("{\n(a\n)\n}");
+        let within = |idx, first, last| text.match_pair_in(CharIdx(idx), PAIRS, "\"", LineNo(first), LineNo(last)).map(|m| m.0);
+        assert_eq!(within(2, 1, 2), Some(5));
+        assert_eq!(within(5, 1, 3), Some(2));
+        // The match is past the last line looked at
+        assert_eq!(within(0, 0, 2), None);
+        assert_eq!(within(0, 0, 3), Some(7));
+        // Strings start over on each line, so they are found the same
+        assert_eq!(Text::new("x\n\"(\" (y)").match_pair_in(CharIdx(6), PAIRS, "\"", LineNo(1), LineNo(1)), Some(CharIdx(8)));
+    }
+}
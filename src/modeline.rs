@@ -0,0 +1,74 @@
+// What starts a modeline, after a blank or at the start of the line
+const MARKERS : &[&str] = &["vim:", "Vim:", "vi:", "ex:"];
+
+/// The `:set` arguments of a modeline in `line`, in either of its forms:
+/// `vim: ts=4 sw=4` with options up to the end of the line, or
+/// `/* vim: set ts=4 sw=4: */` with options up to the next `:`. A `\:`
+/// is a colon in a value. Returns None when the line has no modeline.
+pub fn parse(line : &str) -> Option<Vec<String>> {
+    let start = line.char_indices().find_map(|(i, _)| {
+        let blank_before = line[..i].chars().next_back().is_none_or(char::is_whitespace);
+        let marker = MARKERS.iter().find(|m| line[i..].starts_with(**m))?;
+        blank_before.then_some(i + marker.len())
+    })?;
+    let rest = line[start..].trim_start();
+    let (rest, set_form) = match rest.strip_prefix("set ").or(rest.strip_prefix("se ")) {
+        Some(r) => (r, true),
+        None => (rest, false),
+    };
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut chars = rest.chars();
+    let mut ended = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.clone().next() == Some(':') => {
+                arg.push(':');
+                chars.next();
+                continue;
+            }
+            ':' if set_form => {
+                ended = true;
+                break;
+            }
+            ' ' | '\t' | ':' => (),
+            c => {
+                arg.push(c);
+                continue;
+            }
+        }
+        if !arg.is_empty() {
+            args.push(std::mem::take(&mut arg));
+        }
+    }
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+    // The set form has to end in a colon
+    match set_form && !ended {
+        true => None,
+        false => Some(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(v : &[&str]) -> Option<Vec<String>> {
+        Some(v.iter().map(|s| String::from(*s)).collect())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("# vim: ts=4 sw=4"), args(&["ts=4", "sw=4"]));
+        assert_eq!(parse("vi:noet:ts=2"), args(&["noet", "ts=2"]));
+        assert_eq!(parse("/* vim: set ft=c et: */"), args(&["ft=c", "et"]));
+        assert_eq!(parse("// ex: se sbr=\\:x:"), args(&["sbr=:x"]));
+        // The set form needs its closing colon
+        assert_eq!(parse("vim: set ts=4"), None);
+        // The marker has to follow a blank
+        assert_eq!(parse("index: 4"), None);
+        assert_eq!(parse("no modeline here"), None);
+    }
+}
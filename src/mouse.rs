@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+
+/// Flags of the 'mouse' option: normal, Visual and insert mode, or all.
+pub const MOUSE_FLAGS : &str = "anvi";
+
+/// Whether 'mouse' set to `option` enables the mouse in the mode of `flag`.
+pub fn enabled(option : &str, flag : char) -> bool {
+    option.contains('a') || option.contains(flag)
+}
+
+/// Counts clicks in the same cell following each other quickly enough, so
+/// double and triple clicks can be told apart. A fourth click starts over.
+#[derive(Default)]
+pub struct Clicks {
+    last : Option<(Instant, u16, u16)>,
+    count : usize,
+}
+
+impl Clicks {
+    /// Record a click at screen `column` and `row`, and return how many
+    /// clicks it makes, from 1 to 3.
+    pub fn click(&mut self, at : Instant, column : u16, row : u16, within : Duration) -> usize {
+        self.count = match self.last {
+            Some((t, c, r)) if c == column && r == row && at.duration_since(t) <= within => self.count % 3 + 1,
+            _ => 1,
+        };
+        self.last = Some((at, column, row));
+        self.count
+    }
+
+    /// Screen cell of the last click, where a drag starts from.
+    pub fn cell(&self) -> Option<(u16, u16)> {
+        self.last.map(|(_, c, r)| (c, r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enabled() {
+        assert!(enabled("a", 'n') && enabled("nv", 'v'));
+        assert!(!enabled("", 'n') && !enabled("nv", 'i'));
+    }
+
+    #[test]
+    fn test_clicks() {
+        let mut clicks = Clicks::default();
+        let (t, within) = (Instant::now(), Duration::from_millis(500));
+        assert_eq!(clicks.click(t, 3, 1, within), 1);
+        assert_eq!(clicks.click(t + Duration::from_millis(200), 3, 1, within), 2);
+        assert_eq!(clicks.click(t + Duration::from_millis(400), 3, 1, within), 3);
+        assert_eq!(clicks.click(t + Duration::from_millis(600), 3, 1, within), 1);
+        // Too slow, or somewhere else
+        assert_eq!(clicks.click(t + Duration::from_millis(1200), 3, 1, within), 1);
+        assert_eq!(clicks.click(t + Duration::from_millis(1300), 4, 1, within), 1);
+        assert_eq!(clicks.cell(), Some((4, 1)));
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+
+use crate::fileformat;
+use crate::listchars;
+use crate::matching;
+use crate::mouse;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Number(i64),
+    Str(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    Global,
+    Buffer,
+}
+
+// Which values `:set`, `:setlocal` and `:setglobal` touch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetScope {
+    Both,
+    Local,
+    Global,
+}
+
+enum DefaultValue {
+    Bool(bool),
+    Number(i64),
+    Str(&'static str),
+}
+
+struct OptionDef {
+    name : &'static str,
+    short : &'static str,
+    scope : Scope,
+    default : DefaultValue,
+    valid : Option<fn(&Value) -> bool>,
+}
+
+pub const CURSOR_STYLES : &[&str] = &[
+    "default",
+    "blinkingblock", "steadyblock",
+    "blinkingunderscore", "steadyunderscore",
+    "blinkingbar", "steadybar",
+];
+
+fn valid_cursor_style(v : &Value) -> bool {
+    matches!(v, Value::Str(s) if CURSOR_STYLES.contains(&s.as_str()))
+}
+
+fn positive(v : &Value) -> bool {
+    matches!(v, Value::Number(n) if *n > 0)
+}
+
+fn not_negative(v : &Value) -> bool {
+    matches!(v, Value::Number(n) if *n >= 0)
+}
+
+fn valid_pairs(v : &Value) -> bool {
+    matches!(v, Value::Str(s) if matching::parse_pairs(s).is_some())
+}
+
+fn valid_signcolumn(v : &Value) -> bool {
+    matches!(v, Value::Str(s) if ["auto", "yes", "no"].contains(&s.as_str()))
+}
+
+fn valid_foldcolumn(v : &Value) -> bool {
+    matches!(v, Value::Number(n) if (0..=12).contains(n))
+}
+
+fn valid_colorcolumn(v : &Value) -> bool {
+    matches!(v, Value::Str(s) if s.split(',').filter(|c| !c.is_empty()).all(|c| c.parse::<usize>().is_ok_and(|n| n > 0)))
+}
+
+fn valid_commentstring(v : &Value) -> bool {
+    matches!(v, Value::Str(s) if s.is_empty() || s.contains("%s"))
+}
+
+// A file type or syntax name: letters, digits, '_', '-' and '.'
+fn valid_name(v : &Value) -> bool {
+    matches!(v, Value::Str(s) if s.chars().all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c)))
+}
+
+fn valid_laststatus(v : &Value) -> bool {
+    matches!(v, Value::Number(n) if (0..=2).contains(n))
+}
+
+fn valid_fileformat(v : &Value) -> bool {
+    matches!(v, Value::Str(s) if fileformat::FORMATS.iter().any(|(f, _)| f == s))
+}
+
+fn valid_listchars(v : &Value) -> bool {
+    matches!(v, Value::Str(s) if listchars::parse(s).is_some())
+}
+
+fn valid_mouse(v : &Value) -> bool {
+    matches!(v, Value::Str(s) if s.chars().all(|c| mouse::MOUSE_FLAGS.contains(c)))
+}
+
+const OPTIONS : &[OptionDef] = &[
+    OptionDef { name: "autoindent", short: "ai", scope: Scope::Buffer,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "autoread", short: "ar", scope: Scope::Global,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "breakat", short: "brk", scope: Scope::Global,
+        default: DefaultValue::Str(" \t!@*-+;:,./?"), valid: None },
+    OptionDef { name: "breakindent", short: "bri", scope: Scope::Global,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "colorcolumn", short: "cc", scope: Scope::Global,
+        default: DefaultValue::Str(""), valid: Some(valid_colorcolumn) },
+    OptionDef { name: "commentstring", short: "cms", scope: Scope::Buffer,
+        default: DefaultValue::Str("/* %s */"), valid: Some(valid_commentstring) },
+    OptionDef { name: "cursorline", short: "cul", scope: Scope::Global,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "expandtab", short: "et", scope: Scope::Buffer,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "fileformat", short: "ff", scope: Scope::Buffer,
+        default: DefaultValue::Str("unix"), valid: Some(valid_fileformat) },
+    OptionDef { name: "filetype", short: "ft", scope: Scope::Buffer,
+        default: DefaultValue::Str(""), valid: Some(valid_name) },
+    OptionDef { name: "foldcolumn", short: "fdc", scope: Scope::Global,
+        default: DefaultValue::Number(0), valid: Some(valid_foldcolumn) },
+    OptionDef { name: "insertcursor", short: "icu", scope: Scope::Global,
+        default: DefaultValue::Str("blinkingbar"), valid: Some(valid_cursor_style) },
+    OptionDef { name: "joinspaces", short: "js", scope: Scope::Global,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "laststatus", short: "ls", scope: Scope::Global,
+        default: DefaultValue::Number(2), valid: Some(valid_laststatus) },
+    OptionDef { name: "linebreak", short: "lbr", scope: Scope::Global,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "list", short: "", scope: Scope::Global,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "listchars", short: "lcs", scope: Scope::Global,
+        default: DefaultValue::Str("eol:$"), valid: Some(valid_listchars) },
+    OptionDef { name: "mapleader", short: "", scope: Scope::Global,
+        default: DefaultValue::Str("\\"), valid: None },
+    OptionDef { name: "matchpairs", short: "mps", scope: Scope::Buffer,
+        default: DefaultValue::Str("(:),{:},[:]"), valid: Some(valid_pairs) },
+    OptionDef { name: "maxmapdepth", short: "mmd", scope: Scope::Global,
+        default: DefaultValue::Number(1000), valid: Some(positive) },
+    OptionDef { name: "modifiable", short: "ma", scope: Scope::Buffer,
+        default: DefaultValue::Bool(true), valid: None },
+    OptionDef { name: "modeline", short: "ml", scope: Scope::Buffer,
+        default: DefaultValue::Bool(true), valid: None },
+    OptionDef { name: "modelines", short: "mls", scope: Scope::Global,
+        default: DefaultValue::Number(5), valid: Some(not_negative) },
+    OptionDef { name: "mouse", short: "", scope: Scope::Global,
+        default: DefaultValue::Str(""), valid: Some(valid_mouse) },
+    OptionDef { name: "mousetime", short: "mouset", scope: Scope::Global,
+        default: DefaultValue::Number(500), valid: Some(positive) },
+    OptionDef { name: "normalcursor", short: "ncu", scope: Scope::Global,
+        default: DefaultValue::Str("steadyblock"), valid: Some(valid_cursor_style) },
+    OptionDef { name: "number", short: "nu", scope: Scope::Global,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "numberwidth", short: "nuw", scope: Scope::Global,
+        default: DefaultValue::Number(4), valid: Some(positive) },
+    OptionDef { name: "readonly", short: "ro", scope: Scope::Buffer,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "relativenumber", short: "rnu", scope: Scope::Global,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "replacecursor", short: "rcu", scope: Scope::Global,
+        default: DefaultValue::Str("steadyunderscore"), valid: Some(valid_cursor_style) },
+    OptionDef { name: "report", short: "", scope: Scope::Global,
+        default: DefaultValue::Number(2), valid: Some(not_negative) },
+    OptionDef { name: "shiftround", short: "sr", scope: Scope::Global,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "shiftwidth", short: "sw", scope: Scope::Buffer,
+        default: DefaultValue::Number(8), valid: Some(not_negative) },
+    OptionDef { name: "showbreak", short: "sbr", scope: Scope::Global,
+        default: DefaultValue::Str(""), valid: None },
+    OptionDef { name: "sidescroll", short: "ss", scope: Scope::Global,
+        default: DefaultValue::Number(0), valid: Some(not_negative) },
+    OptionDef { name: "sidescrolloff", short: "siso", scope: Scope::Global,
+        default: DefaultValue::Number(0), valid: Some(not_negative) },
+    OptionDef { name: "signcolumn", short: "scl", scope: Scope::Global,
+        default: DefaultValue::Str("auto"), valid: Some(valid_signcolumn) },
+    OptionDef { name: "smartindent", short: "si", scope: Scope::Buffer,
+        default: DefaultValue::Bool(false), valid: None },
+    OptionDef { name: "softtabstop", short: "sts", scope: Scope::Buffer,
+        default: DefaultValue::Number(0), valid: None },
+    OptionDef { name: "syntax", short: "syn", scope: Scope::Buffer,
+        default: DefaultValue::Str(""), valid: Some(valid_name) },
+    OptionDef { name: "tabstop", short: "ts", scope: Scope::Buffer,
+        default: DefaultValue::Number(8), valid: Some(positive) },
+    OptionDef { name: "timeout", short: "to", scope: Scope::Global,
+        default: DefaultValue::Bool(true), valid: None },
+    OptionDef { name: "timeoutlen", short: "tm", scope: Scope::Global,
+        default: DefaultValue::Number(1000), valid: Some(positive) },
+    OptionDef { name: "updatetime", short: "ut", scope: Scope::Global,
+        default: DefaultValue::Number(500), valid: Some(positive) },
+    OptionDef { name: "wrap", short: "", scope: Scope::Global,
+        default: DefaultValue::Bool(true), valid: None },
+];
+
+fn find(name : &str) -> Option<&'static OptionDef> {
+    OPTIONS.iter().find(|o| o.name == name || (!o.short.is_empty() && o.short == name))
+}
+
+fn default_value(def : &OptionDef) -> Value {
+    match def.default {
+        DefaultValue::Bool(b) => Value::Bool(b),
+        DefaultValue::Number(n) => Value::Number(n),
+        DefaultValue::Str(s) => Value::Str(String::from(s)),
+    }
+}
+
+fn describe(name : &str, v : &Value) -> String {
+    match v {
+        Value::Bool(true) => String::from(name),
+        Value::Bool(false) => format!("no{}", name),
+        Value::Number(n) => format!("{}={}", name, n),
+        Value::Str(s) => format!("{}={}", name, s),
+    }
+}
+
+/// Values of buffer-local options, one set per buffer.
+pub struct LocalOptions {
+    values : HashMap<&'static str, Value>,
+}
+
+impl LocalOptions {
+    /// The buffer's value of string option `name`, by its full name.
+    pub fn get_str(&self, name : &str) -> &str {
+        match self.values.get(name) {
+            Some(Value::Str(s)) => s,
+            _ => "",
+        }
+    }
+}
+
+/// Global option values and the registry of known options.
+pub struct Options {
+    global : HashMap<&'static str, Value>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    pub fn new() -> Options {
+        let mut global = HashMap::new();
+        for def in OPTIONS {
+            global.insert(def.name, default_value(def));
+        }
+        Options { global }
+    }
+
+    /// Local values for a new buffer, copied from the current globals.
+    pub fn new_local(&self) -> LocalOptions {
+        let mut values = HashMap::new();
+        for def in OPTIONS.iter().filter(|d| d.scope == Scope::Buffer) {
+            values.insert(def.name, self.global[def.name].clone());
+        }
+        LocalOptions { values }
+    }
+
+    pub fn get<'a>(&'a self, local : &'a LocalOptions, name : &str) -> Option<&'a Value> {
+        let def = find(name)?;
+        match def.scope {
+            Scope::Buffer => local.values.get(def.name).or(self.global.get(def.name)),
+            Scope::Global => self.global.get(def.name),
+        }
+    }
+
+    pub fn get_bool(&self, local : &LocalOptions, name : &str) -> bool {
+        matches!(self.get(local, name), Some(Value::Bool(true)))
+    }
+
+    pub fn get_number(&self, local : &LocalOptions, name : &str) -> i64 {
+        match self.get(local, name) {
+            Some(Value::Number(n)) => *n,
+            _ => 0,
+        }
+    }
+
+    pub fn get_str<'a>(&'a self, local : &'a LocalOptions, name : &str) -> &'a str {
+        match self.get(local, name) {
+            Some(Value::Str(s)) => s,
+            _ => "",
+        }
+    }
+
+    /// Whether `:set` argument `arg` is for a buffer-local option.
+    pub fn is_buffer_local(arg : &str) -> bool {
+        let name = arg.split(['=', ':', '+', '-', '^', '?', '!', '&']).next().unwrap_or(arg);
+        let def = find(name).or_else(|| name.strip_prefix("no").or(name.strip_prefix("inv")).and_then(find));
+        def.is_some_and(|d| d.scope == Scope::Buffer)
+    }
+
+    /// Current value of every option, as shown by `:set all`.
+    pub fn describe_all(&self, local : &LocalOptions) -> Vec<String> {
+        OPTIONS.iter()
+            .map(|def| describe(def.name, &self.current(local, def, SetScope::Both)))
+            .collect()
+    }
+
+    fn store(&mut self, local : &mut LocalOptions, def : &'static OptionDef, v : Value, scope : SetScope) {
+        if def.scope == Scope::Global || scope != SetScope::Local {
+            self.global.insert(def.name, v.clone());
+        }
+        if def.scope == Scope::Buffer && scope != SetScope::Global {
+            local.values.insert(def.name, v);
+        }
+    }
+
+    fn current(&self, local : &LocalOptions, def : &OptionDef, scope : SetScope) -> Value {
+        if def.scope == Scope::Buffer && scope != SetScope::Global
+            && let Some(v) = local.values.get(def.name) {
+            return v.clone();
+        }
+        self.global[def.name].clone()
+    }
+
+    /// Apply every argument of a `:set` line. Returns the text to show for
+    /// queries such as `:set ts?`.
+    pub fn set_line(&mut self, local : &mut LocalOptions, args : &str, scope : SetScope)
+        -> Result<Option<String>, String> {
+        let mut shown : Vec<String> = Vec::new();
+        for arg in split_args(args) {
+            if let Some(s) = self.set(local, &arg, scope)? {
+                shown.push(s);
+            }
+        }
+        if shown.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(shown.join("  ")))
+    }
+
+    /// Apply a single `:set` argument: `opt`, `noopt`, `invopt`, `opt!`,
+    /// `opt&`, `opt?`, `opt=val`, `opt+=val`, `opt-=val` or `opt^=val`.
+    pub fn set(&mut self, local : &mut LocalOptions, arg : &str, scope : SetScope)
+        -> Result<Option<String>, String> {
+        let (name, op, val) = match arg.find(['=', ':']) {
+            Some(i) => {
+                let (name, op) = match &arg[..i] {
+                    n if n.ends_with(['+', '-', '^']) => (&n[..n.len() - 1], &arg[i - 1..=i]),
+                    n => (n, &arg[i..=i]),
+                };
+                (name, op, &arg[i + 1..])
+            }
+            None => match arg.char_indices().last() {
+                Some((i, c @ ('?' | '!' | '&'))) => (&arg[..i], &arg[i..i + c.len_utf8()], ""),
+                _ => (arg, "", ""),
+            },
+        };
+
+        let def = match find(name) {
+            Some(d) => d,
+            None => {
+                let stripped = name.strip_prefix("no").or(name.strip_prefix("inv"));
+                match stripped.and_then(find) {
+                    Some(d) if matches!(d.default, DefaultValue::Bool(_)) && op.is_empty() => {
+                        let v = if name.starts_with("no") {
+                            Value::Bool(false)
+                        } else {
+                            let Value::Bool(b) = self.current(local, d, scope) else { unreachable!() };
+                            Value::Bool(!b)
+                        };
+                        self.store(local, d, v, scope);
+                        return Ok(None);
+                    }
+                    Some(_) => return Err(format!("E474: Invalid argument: {}", arg)),
+                    None => return Err(format!("E518: Unknown option: {}", name)),
+                }
+            }
+        };
+
+        let current = self.current(local, def, scope);
+        let new = match (op, &current) {
+            ("?", _) => return Ok(Some(describe(def.name, &current))),
+            ("&", _) => default_value(def),
+            ("", Value::Bool(_)) => Value::Bool(true),
+            ("", _) => return Ok(Some(describe(def.name, &current))),
+            ("!", Value::Bool(b)) => Value::Bool(!b),
+            (_, Value::Bool(_)) => return Err(format!("E474: Invalid argument: {}", arg)),
+            (_, Value::Number(n)) => {
+                let parsed = val.parse::<i64>()
+                    .map_err(|_| format!("E521: Number required after =: {}", arg))?;
+                match op {
+                    "+=" => Value::Number(n + parsed),
+                    "-=" => Value::Number(n - parsed),
+                    "^=" => Value::Number(n * parsed),
+                    _ => Value::Number(parsed),
+                }
+            }
+            (_, Value::Str(s)) => match op {
+                "+=" => Value::Str(format!("{}{}", s, val)),
+                "-=" => Value::Str(s.replacen(val, "", 1)),
+                "^=" => Value::Str(format!("{}{}", val, s)),
+                _ => Value::Str(String::from(val)),
+            },
+        };
+        if def.valid.is_some_and(|valid| !valid(&new)) {
+            return Err(format!("E474: Invalid argument: {}", arg));
+        }
+        self.store(local, def, new, scope);
+        Ok(None)
+    }
+}
+
+// Split `:set` arguments on whitespace, honouring backslash escapes
+fn split_args(args : &str) -> Vec<String> {
+    let mut out : Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+            }
+            ' ' | '\t' => {
+                if !current.is_empty() {
+                    out.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        out.push(current);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let o = Options::new();
+        let l = o.new_local();
+        assert_eq!(o.get_number(&l, "updatetime"), 500);
+        assert_eq!(o.get_number(&l, "ut"), 500);
+        assert_eq!(o.get_str(&l, "normalcursor"), "steadyblock");
+        assert!(!o.get_bool(&l, "readonly"));
+    }
+
+    #[test]
+    fn test_set_bool_forms() {
+        let mut o = Options::new();
+        let mut l = o.new_local();
+        o.set(&mut l, "ro", SetScope::Both).unwrap();
+        assert!(o.get_bool(&l, "readonly"));
+        o.set(&mut l, "noreadonly", SetScope::Both).unwrap();
+        assert!(!o.get_bool(&l, "readonly"));
+        o.set(&mut l, "invro", SetScope::Both).unwrap();
+        assert!(o.get_bool(&l, "readonly"));
+        o.set(&mut l, "ro!", SetScope::Both).unwrap();
+        assert!(!o.get_bool(&l, "readonly"));
+    }
+
+    #[test]
+    fn test_set_number_and_query() {
+        let mut o = Options::new();
+        let mut l = o.new_local();
+        o.set_line(&mut l, "ut=100", SetScope::Both).unwrap();
+        assert_eq!(o.get_number(&l, "updatetime"), 100);
+        o.set(&mut l, "ut+=50", SetScope::Both).unwrap();
+        assert_eq!(o.get_number(&l, "updatetime"), 150);
+        assert_eq!(o.set(&mut l, "ut?", SetScope::Both), Ok(Some(String::from("updatetime=150"))));
+        assert_eq!(o.set(&mut l, "ro?", SetScope::Both), Ok(Some(String::from("noreadonly"))));
+        o.set(&mut l, "ut&", SetScope::Both).unwrap();
+        assert_eq!(o.get_number(&l, "updatetime"), 500);
+    }
+
+    #[test]
+    fn test_set_errors() {
+        let mut o = Options::new();
+        let mut l = o.new_local();
+        assert!(o.set(&mut l, "nosuchoption", SetScope::Both).unwrap_err().starts_with("E518"));
+        assert!(o.set(&mut l, "ut=abc", SetScope::Both).unwrap_err().starts_with("E521"));
+        assert!(o.set(&mut l, "ut=0", SetScope::Both).unwrap_err().starts_with("E474"));
+        assert!(o.set(&mut l, "normalcursor=box", SetScope::Both).unwrap_err().starts_with("E474"));
+        assert!(o.set(&mut l, "noupdatetime", SetScope::Both).unwrap_err().starts_with("E474"));
+        assert!(o.set(&mut l, "mouse=nx", SetScope::Both).unwrap_err().starts_with("E474"));
+        assert!(o.set(&mut l, "lcs=tab:>", SetScope::Both).unwrap_err().starts_with("E474"));
+        assert!(o.set(&mut l, "fdc=13", SetScope::Both).unwrap_err().starts_with("E474"));
+        assert!(o.set(&mut l, "scl=maybe", SetScope::Both).unwrap_err().starts_with("E474"));
+        assert!(o.set(&mut l, "ft=a/b", SetScope::Both).unwrap_err().starts_with("E474"));
+        assert!(o.set(&mut l, "cms=#", SetScope::Both).unwrap_err().starts_with("E474"));
+    }
+
+    #[test]
+    fn test_local_and_global_scope() {
+        let mut o = Options::new();
+        let mut l = o.new_local();
+        o.set(&mut l, "ro", SetScope::Local).unwrap();
+        assert!(o.get_bool(&l, "readonly"));
+        assert!(!o.get_bool(&o.new_local(), "readonly"));
+
+        o.set(&mut l, "noro", SetScope::Local).unwrap();
+        o.set(&mut l, "ro", SetScope::Global).unwrap();
+        assert!(!o.get_bool(&l, "readonly"));
+        assert!(o.get_bool(&o.new_local(), "readonly"));
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(split_args(" ut=10  ro "), vec!["ut=10", "ro"]);
+        assert_eq!(split_args("a=b\\ c"), vec!["a=b c"]);
+    }
+}
@@ -0,0 +1,126 @@
+use std::ops::{Add, Sub};
+
+/// Index of a char in a `Text`, counting newlines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CharIdx(pub usize);
+
+/// Offset of a byte in the UTF-8 contents of a `Text`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteIdx(pub usize);
+
+/// Line number counting from 0. Users see it counting from 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineNo(pub usize);
+
+/// Line and column, where the column counts chars.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub line : LineNo,
+    pub col : usize,
+}
+
+/// Chars from `start` up to, not including, `end`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Range {
+    pub start : CharIdx,
+    pub end : CharIdx,
+}
+
+impl LineNo {
+    /// Line `n` counting from 1, as users write it. There is no line 0.
+    pub fn from_one_based(n : usize) -> Option<LineNo> {
+        n.checked_sub(1).map(LineNo)
+    }
+
+    pub fn one_based(self) -> usize {
+        self.0 + 1
+    }
+}
+
+impl Position {
+    pub fn new(line : usize, col : usize) -> Position {
+        Position { line : LineNo(line), col }
+    }
+}
+
+impl Range {
+    pub fn new(start : CharIdx, end : CharIdx) -> Range {
+        Range { start, end }
+    }
+
+    pub fn len(self) -> usize {
+        self.end.0.saturating_sub(self.start.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.end <= self.start
+    }
+
+    pub fn contains(self, idx : CharIdx) -> bool {
+        self.start <= idx && idx < self.end
+    }
+}
+
+impl From<std::ops::Range<CharIdx>> for Range {
+    fn from(r : std::ops::Range<CharIdx>) -> Range {
+        Range { start : r.start, end : r.end }
+    }
+}
+
+impl From<Range> for std::ops::Range<CharIdx> {
+    fn from(r : Range) -> std::ops::Range<CharIdx> {
+        r.start..r.end
+    }
+}
+
+// Moving an index or line number by a number of chars or lines, and the
+// distance between two of them
+macro_rules! offsets {
+    ($($t:ident),*) => {$(
+        impl Add<usize> for $t {
+            type Output = $t;
+            fn add(self, n : usize) -> $t {
+                $t(self.0 + n)
+            }
+        }
+
+        impl Sub<usize> for $t {
+            type Output = $t;
+            fn sub(self, n : usize) -> $t {
+                $t(self.0 - n)
+            }
+        }
+
+        impl Sub for $t {
+            type Output = usize;
+            fn sub(self, other : $t) -> usize {
+                self.0 - other.0
+            }
+        }
+    )*};
+}
+
+offsets!(CharIdx, ByteIdx, LineNo);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_numbers() {
+        assert_eq!(LineNo::from_one_based(1), Some(LineNo(0)));
+        assert_eq!(LineNo::from_one_based(0), None);
+        assert_eq!(LineNo(41).one_based(), 42);
+        assert_eq!(LineNo(3) + 2 - LineNo(1), 4);
+    }
+
+    #[test]
+    fn test_range() {
+        let r = Range::new(CharIdx(2), CharIdx(5));
+        assert_eq!(r.len(), 3);
+        assert!(r.contains(CharIdx(2)) && !r.contains(CharIdx(5)));
+        assert!(Range::new(CharIdx(3), CharIdx(3)).is_empty());
+        assert_eq!(Range::from(CharIdx(1)..CharIdx(4)), Range::new(CharIdx(1), CharIdx(4)));
+        assert!(Position::new(1, 9) < Position::new(2, 0));
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Register {
+    pub text : String,
+    pub linewise : bool,    // Holds whole lines rather than a piece of text
+}
+
+/// Named registers. `"` is the unnamed register, `a`-`z` are named ones
+/// (`A`-`Z` append), `.` and `:` hold the last inserted text and the last
+/// command line, and `_` discards what is written to it.
+#[derive(Default)]
+pub struct Registers {
+    regs : HashMap<char, Register>,
+}
+
+pub fn is_valid(name : char) -> bool {
+    name.is_ascii_alphanumeric() || "\"-.:%/_".contains(name)
+}
+
+impl Registers {
+    pub fn get(&self, name : char) -> Option<&Register> {
+        self.regs.get(&name.to_ascii_lowercase())
+    }
+
+    /// Store text written by a yank or delete.
+    pub fn set(&mut self, name : char, text : &str, linewise : bool) -> Result<(), String> {
+        match name {
+            '_' => return Ok(()),
+            '.' | ':' | '%' | '/' => return Err(format!("E354: Invalid register name: '{}'", name)),
+            c if !is_valid(c) => return Err(format!("E354: Invalid register name: '{}'", name)),
+            _ => (),
+        }
+        if name.is_ascii_uppercase() {
+            let reg = self.regs.entry(name.to_ascii_lowercase())
+                .or_insert(Register { text : String::new(), linewise });
+            if reg.linewise && !linewise && !reg.text.ends_with('\n') {
+                reg.text.push('\n');
+            }
+            reg.text.push_str(text);
+            reg.linewise |= linewise;
+        } else {
+            self.regs.insert(name, Register { text : String::from(text), linewise });
+        }
+        let stored = self.regs[&name.to_ascii_lowercase()].clone();
+        if name != '"' {
+            self.regs.insert('"', stored);
+        }
+        Ok(())
+    }
+
+    /// Set one of the registers the editor maintains itself, such as `.`.
+    pub fn set_special(&mut self, name : char, text : &str) {
+        self.regs.insert(name, Register { text : String::from(text), linewise : false });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_get() {
+        let mut r = Registers::default();
+        r.set('a', "one", false).unwrap();
+        assert_eq!(r.get('a').unwrap().text, "one");
+        assert_eq!(r.get('"').unwrap().text, "one");
+        assert!(r.get('b').is_none());
+    }
+
+    #[test]
+    fn test_uppercase_appends() {
+        let mut r = Registers::default();
+        r.set('a', "one", false).unwrap();
+        r.set('A', "two", false).unwrap();
+        assert_eq!(r.get('a').unwrap().text, "onetwo");
+        r.set('b', "line\n", true).unwrap();
+        r.set('B', "more", false).unwrap();
+        assert_eq!(r.get('b').unwrap().text, "line\nmore");
+        assert!(r.get('b').unwrap().linewise);
+    }
+
+    #[test]
+    fn test_special_registers() {
+        let mut r = Registers::default();
+        r.set('_', "gone", false).unwrap();
+        assert!(r.get('_').is_none());
+        assert!(r.get('"').is_none());
+        assert!(r.set('.', "x", false).is_err());
+        assert!(r.set('!', "x", false).is_err());
+        r.set_special('.', "typed");
+        assert_eq!(r.get('.').unwrap().text, "typed");
+    }
+}
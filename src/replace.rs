@@ -0,0 +1,127 @@
+use crate::pos::{LineNo, Position, Range};
+use crate::text::{Text, TextError};
+use crate::width;
+
+/// What a char typed in Replace mode replaced, so Backspace can restore it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Replaced {
+    Char { removed : String },  // Typed one char in place of `removed`
+    Line { removed : String },  // Virtual Replace line break: went to the next line
+}
+
+/// Edit made by `r{c}` with `count` at char `col` of `line`: the number of
+/// chars replaced and the text put in their place, or None when fewer than
+/// `count` graphemes are left. A line break replaces them all with one.
+pub fn replace_count(line : &str, col : usize, c : char, count : usize) -> Option<(usize, String)> {
+    let len = line.chars().count();
+    let mut end = col;
+    for _ in 0..count {
+        if end >= len {
+            return None;
+        }
+        end = width::next_col(line, end);
+    }
+    let new = match c {
+        '\n' => String::from("\n"),
+        c => c.to_string().repeat(count),
+    };
+    Some((end - col, new))
+}
+
+/// Char column where the text replaced by `c` typed at char `col` of `line`
+/// ends. In Virtual Replace mode it replaces screen cells, so a Tab can
+/// cover several chars and a char typed over a Tab only narrows it.
+pub fn replaced_end(line : &str, col : usize, c : char, ts : usize, virtual_replace : bool) -> usize {
+    if !virtual_replace {
+        return width::next_col(line, col);
+    }
+    let ts = ts.max(1);
+    let len = line.chars().count();
+    let vcol = width::cell_of_col(line, col, ts);
+    let covered = match c {
+        '\t' => (vcol / ts + 1) * ts,
+        c => vcol + width::grapheme_width(c.encode_utf8(&mut [0; 4])).max(1),
+    };
+    let mut end = col;
+    while end < len && width::cell_of_col(line, end, ts) < covered {
+        let next = width::next_col(line, end);
+        if line.chars().nth(end) == Some('\t') && width::cell_of_col(line, next, ts) > covered {
+            break;
+        }
+        end = next;
+    }
+    end
+}
+
+impl Text {
+    /// Undo `replaced` for Backspace in Replace mode with the cursor at
+    /// `pos`: the char typed before the cursor goes and what it replaced
+    /// comes back. Returns where the cursor goes.
+    pub fn restore_replaced(&mut self, pos : Position, replaced : &Replaced) -> Result<Position, TextError> {
+        let (at, removed) = match replaced {
+            Replaced::Char { removed } => {
+                let end = self.char_idx(pos);
+                let start = end - 1.min(end.0);
+                self.delete_range(Range::new(start, end))?;
+                (start, removed)
+            }
+            Replaced::Line { removed } => {
+                let prev = LineNo(pos.line.0.saturating_sub(1));
+                (self.line_start(prev) + self.get_line_length(prev), removed)
+            }
+        };
+        self.insert_str(at, removed)?;
+        Ok(self.position(at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_count() {
+        assert_eq!(replace_count("abcd", 1, 'x', 2), Some((2, String::from("xx"))));
+        assert_eq!(replace_count("abcd", 3, 'x', 2), None);
+        // A line break replaces every char counted
+        assert_eq!(replace_count("abcd", 0, '\n', 3), Some((3, String::from("\n"))));
+        // A count is graphemes, which may be several chars
+        assert_eq!(replace_count("e\u{301}f", 0, 'x', 2), Some((3, String::from("xx"))));
+    }
+
+    #[test]
+    fn test_replaced_end() {
+        assert_eq!(replaced_end("abc", 1, 'x', 8, false), 2);
+        assert_eq!(replaced_end("abc", 3, 'x', 8, false), 3);
+        assert_eq!(replaced_end("a\tb", 1, 'x', 8, false), 2);
+        // Virtual Replace: a char typed over a Tab narrows it
+        assert_eq!(replaced_end("a\tb", 1, 'x', 8, true), 1);
+        // ... and a Tab covers the chars up to the next stop, a Tab included
+        assert_eq!(replaced_end("abc\tx", 0, '\t', 4, true), 4);
+        assert_eq!(replaced_end("abcdef", 1, '\t', 4, true), 4);
+        // A wide char covers two cells
+        assert_eq!(replaced_end("abc", 0, '日', 8, true), 2);
+    }
+
+    #[test]
+    fn test_restore_replaced() {
+        let mut text = Text::new("xbc");
+        let removed = String::from("a");
+        let pos = text.restore_replaced(Position::new(0, 1), &Replaced::Char { removed }).unwrap();
+        assert_eq!((text.get_text(), pos), ("abc", Position::new(0, 0)));
+    }
+
+    #[test]
+    fn test_restore_across_line_break() {
+        // Enter in Replace mode inserts a line break, which Backspace joins
+        let mut text = Text::new("a\nb\ncd");
+        let removed = String::new();
+        let pos = text.restore_replaced(Position::new(1, 0), &Replaced::Char { removed }).unwrap();
+        assert_eq!((text.get_text(), pos), ("ab\ncd", Position::new(0, 1)));
+        // In Virtual Replace mode it replaced the rest of the line
+        let mut text = Text::new("a\ncd");
+        let removed = String::from("b");
+        let pos = text.restore_replaced(Position::new(1, 0), &Replaced::Line { removed }).unwrap();
+        assert_eq!((text.get_text(), pos), ("ab\ncd", Position::new(0, 1)));
+    }
+}
@@ -0,0 +1,404 @@
+use std::io::{self, Write};
+
+use crossterm::{
+    cursor::MoveTo,
+    queue,
+    style::{Attribute, Color, Print, SetAttribute, SetBackgroundColor, SetForegroundColor},
+    terminal::{Clear, ClearType},
+};
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::width;
+
+/// How the text of a cell is drawn. Colors left out are those of the
+/// Normal highlight group, or the terminal's own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub fg : Option<Color>,
+    pub bg : Option<Color>,
+    pub bold : bool,
+    pub italic : bool,
+    pub underline : bool,
+    pub reverse : bool,
+}
+
+impl Style {
+    /// This style drawn over `below`: colors it leaves out are taken from
+    /// `below`, and attributes of either are kept.
+    pub fn over(self, below : Style) -> Style {
+        Style {
+            fg : self.fg.or(below.fg),
+            bg : self.bg.or(below.bg),
+            bold : self.bold || below.bold,
+            italic : self.italic || below.italic,
+            underline : self.underline || below.underline,
+            reverse : self.reverse || below.reverse,
+        }
+    }
+}
+
+/// One screen cell, showing a grapheme cluster: its first char and the
+/// rest, such as combining marks. A wide one takes two cells: its own, and
+/// the next one with width 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cell {
+    pub c : char,
+    pub rest : String,
+    pub width : u8,
+    pub style : Style,
+}
+
+impl Cell {
+    fn new(c : char, width : u8, style : Style) -> Cell {
+        Cell { c, rest : String::new(), width, style }
+    }
+}
+
+impl Default for Cell {
+    fn default() -> Cell {
+        Cell::new(' ', 1, Style::default())
+    }
+}
+
+/// Screen contents as rows of cells.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grid {
+    width : usize,
+    height : usize,
+    cells : Vec<Cell>,
+    tabstop : usize,    // Cells a tab stretches to the next multiple of
+}
+
+impl Grid {
+    pub fn new(width : usize, height : usize) -> Grid {
+        Grid { width, height, cells : vec![Cell::default(); width * height], tabstop : 8 }
+    }
+
+    /// Show tabs up to the next multiple of `ts` cells, or as `^I` when
+    /// `ts` is 0, like `width::next_cell`.
+    pub fn set_tabstop(&mut self, ts : usize) {
+        self.tabstop = ts;
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, row : usize, col : usize) -> &Cell {
+        &self.cells[row * self.width + col]
+    }
+
+    fn set(&mut self, row : usize, col : usize, cell : Cell) {
+        self.cells[row * self.width + col] = cell;
+    }
+
+    /// Put grapheme cluster `g` at `row` and `col` and return the column
+    /// after it. Tabs go to the next tabstop, control chars are shown as
+    /// `^X`, and a grapheme that does not fit in the row, or takes no cells,
+    /// is left out.
+    pub fn put_grapheme(&mut self, row : usize, col : usize, g : &str, style : Style) -> usize {
+        let c = match g.chars().next() {
+            Some(c) if row < self.height => c,
+            _ => return col,
+        };
+        let width = width::next_cell(g, col, self.tabstop) - col;
+        if width == 0 || col + width > self.width {
+            return col;
+        }
+        // Overwriting half of a wide char blanks the other half
+        if self.get(row, col).width == 0 && col > 0 {
+            let style = self.get(row, col - 1).style;
+            self.set(row, col - 1, Cell::new(' ', 1, style));
+        }
+        let end = col + width;
+        if end < self.width && self.get(row, end).width == 0 {
+            let style = self.get(row, end).style;
+            self.set(row, end, Cell::new(' ', 1, style));
+        }
+        match c {
+            '\t' if self.tabstop > 0 => (col..end).for_each(|n| self.set(row, n, Cell::new(' ', 1, style))),
+            c if c.is_ascii_control() => {
+                self.set(row, col, Cell::new('^', 1, style));
+                let ctrl = char::from_u32((c as u32) ^ 0x40).unwrap_or('?');
+                self.set(row, col + 1, Cell::new(ctrl, 1, style));
+            }
+            c => {
+                let rest = String::from(&g[c.len_utf8()..]);
+                self.set(row, col, Cell { c, rest, width : width as u8, style });
+                if width == 2 {
+                    self.set(row, col + 1, Cell::new(' ', 0, style));
+                }
+            }
+        }
+        end
+    }
+
+    /// Put `c` at `row` and `col` like `put_grapheme`, and return the
+    /// column after it.
+    pub fn put_char(&mut self, row : usize, col : usize, c : char, style : Style) -> usize {
+        self.put_grapheme(row, col, c.encode_utf8(&mut [0; 4]), style)
+    }
+
+    /// Blank `row`.
+    pub fn clear_row(&mut self, row : usize) {
+        (0..self.width).for_each(|col| self.set(row, col, Cell::default()));
+    }
+
+    /// Draw the cells of `row` from `cols` over `style`, keeping their own
+    /// colors and attributes on top. Cells past the edge are left out.
+    pub fn underlay(&mut self, row : usize, cols : std::ops::Range<usize>, style : Style) {
+        if row >= self.height {
+            return;
+        }
+        for col in cols.start..cols.end.min(self.width) {
+            let cell = &mut self.cells[row * self.width + col];
+            cell.style = cell.style.over(style);
+        }
+    }
+
+    /// Put `s` at `row` and `col` like `put_grapheme`, and return the column
+    /// after it.
+    pub fn put_str(&mut self, row : usize, col : usize, s : &str, style : Style) -> usize {
+        s.graphemes(true).fold(col, |col, g| self.put_grapheme(row, col, g, style))
+    }
+
+    /// Text of `row`, for tests.
+    #[cfg(test)]
+    pub fn row_text(&self, row : usize) -> String {
+        (0..self.width).map(|col| self.get(row, col)).filter(|c| c.width > 0)
+            .map(|c| format!("{}{}", c.c, c.rest)).collect()
+    }
+}
+
+/// The frame being drawn and what is on the terminal, so that only the
+/// cells that changed are written.
+pub struct Screen {
+    pub frame : Grid,
+    pub cursor : (usize, usize),    // Row and column
+    normal : Style,                 // Under the style of every cell
+    shown : Option<Grid>,           // None when what is on the terminal is not known
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen { frame : Grid::new(0, 0), cursor : (0, 0), normal : Style::default(), shown : None }
+    }
+
+    /// Draw every cell over `normal`, the Normal highlight group. A new one
+    /// draws the next frame in full.
+    pub fn set_normal(&mut self, normal : Style) {
+        if normal != self.normal {
+            self.normal = normal;
+            self.shown = None;
+        }
+    }
+
+    /// Start a blank frame of `width` by `height` cells.
+    pub fn begin(&mut self, width : usize, height : usize) {
+        self.frame = Grid::new(width, height);
+        self.cursor = (0, 0);
+    }
+
+    /// Forget what is on the terminal, so the next frame is drawn in full.
+    pub fn invalidate(&mut self) {
+        self.shown = None;
+    }
+
+    /// Runs of cells in the frame that differ from what is shown, as the
+    /// row and the first and past-the-end columns.
+    pub fn changes(&self) -> Vec<(usize, usize, usize)> {
+        let frame = &self.frame;
+        let shown = match &self.shown {
+            Some(s) if s.width == frame.width && s.height == frame.height => s,
+            _ => return (0..frame.height).map(|row| (row, 0, frame.width)).collect(),
+        };
+        let mut runs = Vec::new();
+        for row in 0..frame.height {
+            let mut col = 0;
+            while col < frame.width {
+                if frame.get(row, col) == shown.get(row, col) {
+                    col += 1;
+                    continue;
+                }
+                // A run starts with a whole char
+                let mut start = col;
+                while start > 0 && frame.get(row, start).width == 0 {
+                    start -= 1;
+                }
+                while col < frame.width && frame.get(row, col) != shown.get(row, col) {
+                    col += 1;
+                }
+                while col < frame.width && frame.get(row, col).width == 0 {
+                    col += 1;
+                }
+                match runs.last_mut() {
+                    Some((r, _, end)) if *r == row && *end >= start => *end = col,
+                    _ => runs.push((row, start, col)),
+                }
+            }
+        }
+        runs
+    }
+
+    /// Write the cells that changed and place the cursor, flushing once.
+    pub fn flush(&mut self, out : &mut impl Write) -> io::Result<()> {
+        let runs = self.changes();
+        if self.shown.as_ref().is_none_or(|s| s.width != self.frame.width || s.height != self.frame.height) {
+            queue!(out, SetAttribute(Attribute::Reset), Clear(ClearType::All))?;
+        }
+        let mut style = Style::default();
+        queue!(out, SetAttribute(Attribute::Reset))?;
+        for (row, start, end) in runs {
+            queue!(out, MoveTo(start as u16, row as u16))?;
+            for col in start..end {
+                let cell = self.frame.get(row, col);
+                if cell.width == 0 {
+                    continue;
+                }
+                let cell_style = cell.style.over(self.normal);
+                if cell_style != style {
+                    queue_style(out, style, cell_style)?;
+                    style = cell_style;
+                }
+                queue!(out, Print(cell.c))?;
+                if !cell.rest.is_empty() {
+                    queue!(out, Print(&cell.rest))?;
+                }
+            }
+        }
+        if style != Style::default() {
+            queue!(out, SetAttribute(Attribute::Reset))?;
+        }
+        queue!(out, MoveTo(self.cursor.1 as u16, self.cursor.0 as u16))?;
+        out.flush()?;
+        self.shown = Some(self.frame.clone());
+        Ok(())
+    }
+}
+
+// Change the terminal's style from `from` to `to`
+fn queue_style(out : &mut impl Write, from : Style, to : Style) -> io::Result<()> {
+    if from.bold != to.bold {
+        queue!(out, SetAttribute(if to.bold { Attribute::Bold } else { Attribute::NormalIntensity }))?;
+    }
+    if from.italic != to.italic {
+        queue!(out, SetAttribute(if to.italic { Attribute::Italic } else { Attribute::NoItalic }))?;
+    }
+    if from.underline != to.underline {
+        queue!(out, SetAttribute(if to.underline { Attribute::Underlined } else { Attribute::NoUnderline }))?;
+    }
+    if from.reverse != to.reverse {
+        queue!(out, SetAttribute(if to.reverse { Attribute::Reverse } else { Attribute::NoReverse }))?;
+    }
+    if from.fg != to.fg {
+        queue!(out, SetForegroundColor(to.fg.unwrap_or(Color::Reset)))?;
+    }
+    if from.bg != to.bg {
+        queue!(out, SetBackgroundColor(to.bg.unwrap_or(Color::Reset)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_put() {
+        let mut g = Grid::new(10, 2);
+        assert_eq!(g.put_str(0, 0, "a\tb", Style::default()), 9);
+        assert_eq!(g.row_text(0), "a       b ");
+        assert_eq!(g.put_str(1, 0, "日x\x01", Style::default()), 5);
+        assert_eq!(g.row_text(1), "日x^A     ");
+        // Half of a wide char overwritten
+        g.put_char(1, 1, 'y', Style::default());
+        assert_eq!(g.row_text(1), " yx^A     ");
+        // Too wide for the rest of the row
+        assert_eq!(g.put_char(0, 9, '日', Style::default()), 9);
+        // A combining mark goes in the cell of its base
+        assert_eq!(g.put_str(0, 0, "e\u{301}z", Style::default()), 2);
+        assert_eq!(g.row_text(0), "e\u{301}z      b ");
+    }
+
+    #[test]
+    fn test_put_tab_uses_tabstop() {
+        let mut g = Grid::new(10, 2);
+        g.set_tabstop(4);
+        assert_eq!(g.put_str(0, 0, "a\tb", Style::default()), 5);
+        assert_eq!(g.row_text(0), "a   b     ");
+        g.set_tabstop(0);
+        assert_eq!(g.put_str(1, 0, "a\tb", Style::default()), 4);
+        assert_eq!(g.row_text(1), "a^Ib      ");
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut s = Screen::new();
+        s.begin(6, 2);
+        s.frame.put_str(0, 0, "abc", Style::default());
+        assert_eq!(s.changes(), vec![(0, 0, 6), (1, 0, 6)]);
+        s.flush(&mut Vec::new()).unwrap();
+        assert_eq!(s.changes(), vec![]);
+
+        s.begin(6, 2);
+        s.frame.put_str(0, 0, "abd", Style::default());
+        s.frame.put_str(1, 2, "日", Style::default());
+        assert_eq!(s.changes(), vec![(0, 2, 3), (1, 2, 4)]);
+        s.flush(&mut Vec::new()).unwrap();
+
+        // Only the second half of the wide char changes style
+        s.begin(6, 2);
+        s.frame.put_str(0, 0, "abd", Style::default());
+        s.frame.put_str(1, 2, "日", Style { reverse : true, ..Style::default() });
+        assert_eq!(s.changes(), vec![(1, 2, 4)]);
+    }
+
+    #[test]
+    fn test_flush_writes_changes_only() {
+        let mut s = Screen::new();
+        s.begin(4, 1);
+        s.frame.put_str(0, 0, "abcd", Style::default());
+        s.flush(&mut Vec::new()).unwrap();
+        s.begin(4, 1);
+        s.frame.put_str(0, 0, "abXd", Style::default());
+        s.cursor = (0, 1);
+        let mut out = Vec::new();
+        s.flush(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\x1b[1;3HX"));
+        assert!(!out.contains('a') && !out.contains('d'));
+        assert!(out.ends_with("\x1b[1;2H"));
+        // A new size is drawn in full
+        s.begin(5, 1);
+        s.frame.put_str(0, 0, "abXd", Style::default());
+        let mut out = Vec::new();
+        s.flush(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("\x1b[2J"));
+    }
+
+    #[test]
+    fn test_normal_and_underlay() {
+        let mut s = Screen::new();
+        s.begin(4, 1);
+        let red = Style { fg : Some(Color::Red), ..Style::default() };
+        s.frame.put_str(0, 0, "ab", red);
+        s.frame.underlay(0, 1..9, Style { fg : Some(Color::Blue), bg : Some(Color::Grey), ..Style::default() });
+        assert_eq!(s.frame.get(0, 0).style, red);
+        assert_eq!(s.frame.get(0, 1).style, Style { bg : Some(Color::Grey), ..red });
+        assert_eq!(s.frame.get(0, 3).style.fg, Some(Color::Blue));
+        s.flush(&mut Vec::new()).unwrap();
+
+        // A new Normal group draws everything again, over it
+        s.set_normal(Style { bg : Some(Color::Black), ..Style::default() });
+        let mut out = Vec::new();
+        s.flush(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("\x1b[48;5;0ma"));
+    }
+}
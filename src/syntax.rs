@@ -0,0 +1,597 @@
+use crate::text::{LineChange, Text};
+
+/// What a piece of highlighted text is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Group {
+    Comment,
+    String,
+    Number,
+    Keyword,
+    Type,
+    Constant,
+    PreProc,
+    Title,
+    Identifier,
+    Special,
+}
+
+impl Group {
+    /// The highlight group the text is drawn with.
+    pub fn name(self) -> &'static str {
+        match self {
+            Group::Comment => "Comment",
+            Group::String => "String",
+            Group::Number => "Number",
+            Group::Keyword => "Keyword",
+            Group::Type => "Type",
+            Group::Constant => "Constant",
+            Group::PreProc => "PreProc",
+            Group::Title => "Title",
+            Group::Identifier => "Identifier",
+            Group::Special => "Special",
+        }
+    }
+}
+
+/// Text from `start` to `end`, which may span lines, like a string or a
+/// block comment.
+pub struct Region {
+    pub start : &'static str,
+    pub end : &'static str,
+    pub escape : Option<char>,  // Makes the char after it part of the region
+    pub multiline : bool,       // Goes on to the next line when not ended
+    pub single : bool,          // Holds one char or escape, as a char literal
+    pub group : Group,
+}
+
+/// How to highlight a language.
+pub struct Lang {
+    pub name : &'static str,    // The 'syntax' value that selects it
+    pub line_comments : &'static [&'static str],
+    pub regions : &'static [Region],     // Tried in order, so longer starts go first
+    pub line_starts : &'static [(&'static str, Group)], // Lines starting with these after indent
+    pub keywords : &'static [&'static str],
+    pub types : &'static [&'static str],
+    pub constants : &'static [&'static str],
+    pub capital_types : bool,   // Words starting with a capital are types
+    pub numbers : bool,
+    pub word_chars : &'static str,  // Taken as part of words besides letters, digits and '_'
+    pub key : Option<char>,     // A word or string followed by it is a key
+    pub variable : Option<char>,    // Starts a variable like `$HOME` or `${HOME}`
+    pub indent_after : &'static str,    // A line ending in one of these indents the next
+}
+
+const fn region(start : &'static str, end : &'static str, escape : Option<char>, multiline : bool, group : Group) -> Region {
+    Region { start, end, escape, multiline, single : false, group }
+}
+
+const CHAR : Region = Region { start : "'", end : "'", escape : Some('\\'), multiline : false, single : true, group : Group::String };
+const BLOCK_COMMENT : Region = region("/*", "*/", None, true, Group::Comment);
+
+pub static LANGS : &[Lang] = &[
+    Lang {
+        name : "rust",
+        line_comments : &["//"],
+        regions : &[BLOCK_COMMENT, region("\"", "\"", Some('\\'), true, Group::String), CHAR],
+        line_starts : &[("#", Group::PreProc)],
+        keywords : &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+            "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "type",
+            "unsafe", "use", "where", "while",
+        ],
+        types : &[
+            "bool", "char", "str", "i8", "i16", "i32", "i64", "i128", "isize",
+            "u8", "u16", "u32", "u64", "u128", "usize", "f32", "f64",
+        ],
+        constants : &["true", "false", "None", "Some", "Ok", "Err"],
+        capital_types : true,
+        numbers : true,
+        word_chars : "",
+        key : None,
+        variable : None,
+        indent_after : "{([",
+    },
+    Lang {
+        name : "c",
+        line_comments : &["//"],
+        regions : &[BLOCK_COMMENT, region("\"", "\"", Some('\\'), false, Group::String), CHAR],
+        line_starts : &[("#", Group::PreProc)],
+        keywords : &[
+            "auto", "break", "case", "const", "continue", "default", "do", "else", "enum", "extern",
+            "for", "goto", "if", "inline", "register", "restrict", "return", "sizeof", "static",
+            "struct", "switch", "typedef", "union", "volatile", "while",
+        ],
+        types : &[
+            "bool", "char", "double", "float", "int", "long", "short", "signed", "unsigned", "void",
+            "size_t", "ssize_t", "int8_t", "int16_t", "int32_t", "int64_t",
+            "uint8_t", "uint16_t", "uint32_t", "uint64_t", "FILE",
+        ],
+        constants : &["NULL", "true", "false", "EOF"],
+        capital_types : false,
+        numbers : true,
+        word_chars : "",
+        key : None,
+        variable : None,
+        indent_after : "{([",
+    },
+    Lang {
+        name : "python",
+        line_comments : &["#"],
+        regions : &[
+            region("\"\"\"", "\"\"\"", Some('\\'), true, Group::String),
+            region("'''", "'''", Some('\\'), true, Group::String),
+            region("\"", "\"", Some('\\'), false, Group::String),
+            region("'", "'", Some('\\'), false, Group::String),
+        ],
+        line_starts : &[("@", Group::PreProc)],
+        keywords : &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is",
+            "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with", "yield",
+        ],
+        types : &["bool", "bytes", "dict", "float", "int", "list", "object", "set", "str", "tuple"],
+        constants : &["True", "False", "None"],
+        capital_types : false,
+        numbers : true,
+        word_chars : "",
+        key : None,
+        variable : None,
+        indent_after : "{([:",
+    },
+    Lang {
+        name : "sh",
+        line_comments : &["#"],
+        regions : &[
+            region("\"", "\"", Some('\\'), true, Group::String),
+            region("'", "'", None, true, Group::String),
+        ],
+        line_starts : &[],
+        keywords : &[
+            "case", "do", "done", "elif", "else", "esac", "exit", "export", "fi", "for", "function",
+            "if", "in", "local", "readonly", "return", "select", "then", "until", "while",
+        ],
+        types : &[],
+        constants : &[],
+        capital_types : false,
+        numbers : true,
+        word_chars : "",
+        key : None,
+        variable : Some('$'),
+        indent_after : "{(",
+    },
+    Lang {
+        name : "markdown",
+        line_comments : &[],
+        regions : &[
+            region("```", "```", None, true, Group::String),
+            region("`", "`", None, false, Group::String),
+            region("**", "**", None, false, Group::Special),
+        ],
+        line_starts : &[("#", Group::Title), (">", Group::Comment)],
+        keywords : &[],
+        types : &[],
+        constants : &[],
+        capital_types : false,
+        numbers : false,
+        word_chars : "",
+        key : None,
+        variable : None,
+        indent_after : "",
+    },
+    Lang {
+        name : "json",
+        line_comments : &[],
+        regions : &[region("\"", "\"", Some('\\'), false, Group::String)],
+        line_starts : &[],
+        keywords : &[],
+        types : &[],
+        constants : &["true", "false", "null"],
+        capital_types : false,
+        numbers : true,
+        word_chars : "",
+        key : Some(':'),
+        variable : None,
+        indent_after : "{[",
+    },
+    Lang {
+        name : "toml",
+        line_comments : &["#"],
+        regions : &[
+            region("\"\"\"", "\"\"\"", Some('\\'), true, Group::String),
+            region("'''", "'''", None, true, Group::String),
+            region("\"", "\"", Some('\\'), false, Group::String),
+            region("'", "'", None, false, Group::String),
+        ],
+        line_starts : &[("[", Group::Title)],
+        keywords : &[],
+        types : &[],
+        constants : &["true", "false"],
+        capital_types : false,
+        numbers : true,
+        word_chars : "-",
+        key : Some('='),
+        variable : None,
+        indent_after : "[{",
+    },
+    Lang {
+        name : "yaml",
+        line_comments : &["#"],
+        regions : &[
+            region("\"", "\"", Some('\\'), false, Group::String),
+            region("'", "'", None, false, Group::String),
+        ],
+        line_starts : &[("---", Group::PreProc)],
+        keywords : &[],
+        types : &[],
+        constants : &["true", "false", "null", "yes", "no", "on", "off"],
+        capital_types : false,
+        numbers : true,
+        word_chars : "-",
+        key : Some(':'),
+        variable : None,
+        indent_after : "{[:",
+    },
+];
+
+/// The language 'syntax' value `name` selects.
+pub fn lang(name : &str) -> Option<&'static Lang> {
+    LANGS.iter().find(|lang| lang.name == name)
+}
+
+/// Chars `start` to `end` of a line are in `group`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start : usize,
+    pub end : usize,
+    pub group : Group,
+}
+
+/// Where lexing is at the start of a line: the region left open on the
+/// line before, if any.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct State {
+    region : Option<usize>,
+}
+
+impl Lang {
+    /// Chars that start and end strings on one line, for `%` to skip
+    /// brackets inside them.
+    pub fn quotes(&self) -> String {
+        self.regions.iter()
+            .filter(|r| r.group == Group::String && !r.single && r.start.chars().count() == 1)
+            .map(|r| r.start).collect()
+    }
+
+    fn is_word_char(&self, c : char) -> bool {
+        c.is_alphanumeric() || c == '_' || self.word_chars.contains(c)
+    }
+
+    // Whether the text at `pos` is followed by the key char, past blanks
+    fn is_key(&self, chars : &[char], pos : usize) -> bool {
+        let next = chars[pos..].iter().find(|c| **c != ' ' && **c != '\t');
+        self.key.is_some() && next == self.key.as_ref()
+    }
+
+    fn word_group(&self, word : &str) -> Option<Group> {
+        match word {
+            _ if self.keywords.contains(&word) => Some(Group::Keyword),
+            _ if self.constants.contains(&word) => Some(Group::Constant),
+            _ if self.types.contains(&word) => Some(Group::Type),
+            _ if self.capital_types && word.starts_with(char::is_uppercase) => Some(Group::Type),
+            _ => None,
+        }
+    }
+
+    // Where region `r` that has its contents from `pos` ends: just after
+    // its end, or None when it goes on past the line
+    fn region_end(&self, r : &Region, chars : &[char], mut pos : usize) -> Option<usize> {
+        while pos < chars.len() {
+            if Some(chars[pos]) == r.escape {
+                pos += 2;
+            }
+            else if at(chars, pos, r.end) {
+                return Some(pos + r.end.chars().count());
+            }
+            else {
+                pos += 1;
+            }
+        }
+        None
+    }
+
+    // End of a char literal like 'a' or '\n' with its contents from `pos`,
+    // or None when the quote is something else, like a Rust lifetime
+    fn single_end(&self, r : &Region, chars : &[char], pos : usize) -> Option<usize> {
+        match chars.get(pos) {
+            Some(c) if Some(*c) == r.escape => {
+                let end = self.region_end(r, chars, pos)?;
+                (end - pos <= 12).then_some(end)
+            }
+            Some(_) if at(chars, pos + 1, r.end) => Some(pos + 1 + r.end.chars().count()),
+            _ => None,
+        }
+    }
+
+    /// Highlight `line`, starting in `state`. Returns the highlighted parts
+    /// of the line and the state the next line starts in.
+    pub fn lex(&self, line : &str, state : State) -> (Vec<Span>, State) {
+        let chars : Vec<char> = line.chars().collect();
+        let mut spans = Vec::new();
+        let mut pos = 0;
+        if let Some(n) = state.region {
+            let r = &self.regions[n];
+            match self.region_end(r, &chars, 0) {
+                Some(end) => {
+                    spans.push(Span { start : 0, end, group : r.group });
+                    pos = end;
+                }
+                None => {
+                    spans.push(Span { start : 0, end : chars.len(), group : r.group });
+                    return (spans, state);
+                }
+            }
+        }
+        else {
+            let indent = chars.iter().take_while(|c| c.is_whitespace()).count();
+            if let Some((_, group)) = self.line_starts.iter().find(|(s, _)| at(&chars, indent, s)) {
+                spans.push(Span { start : indent, end : chars.len(), group : *group });
+                return (spans, State::default());
+            }
+        }
+        while pos < chars.len() {
+            let c = chars[pos];
+            let after_word = pos > 0 && self.is_word_char(chars[pos - 1]);
+            if self.line_comments.iter().any(|s| at(&chars, pos, s)) {
+                spans.push(Span { start : pos, end : chars.len(), group : Group::Comment });
+                break;
+            }
+            if let Some(n) = self.regions.iter().position(|r| at(&chars, pos, r.start)) {
+                let r = &self.regions[n];
+                let from = pos + r.start.chars().count();
+                let closed = self.region_end(r, &chars, from);
+                let end = match r.single {
+                    true => self.single_end(r, &chars, from),
+                    false => Some(closed.unwrap_or(chars.len())),
+                };
+                if let Some(end) = end {
+                    let group = if self.is_key(&chars, end) { Group::Identifier } else { r.group };
+                    spans.push(Span { start : pos, end, group });
+                    if closed.is_none() && r.multiline {
+                        return (spans, State { region : Some(n) });
+                    }
+                    pos = end;
+                    continue;
+                }
+            }
+            if Some(c) == self.variable {
+                let end = match chars.get(pos + 1) {
+                    Some('{') => chars[pos..].iter().position(|c| *c == '}').map_or(chars.len(), |n| pos + n + 1),
+                    Some(c) if self.is_word_char(*c) => {
+                        pos + 1 + chars[pos + 1..].iter().take_while(|c| self.is_word_char(**c)).count()
+                    }
+                    Some(_) => pos + 2,
+                    None => pos + 1,
+                };
+                spans.push(Span { start : pos, end, group : Group::Identifier });
+                pos = end;
+                continue;
+            }
+            if self.numbers && c.is_ascii_digit() && !after_word {
+                let mut end = pos + 1;
+                while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_'
+                    || chars[end] == '.' && chars.get(end + 1).is_some_and(char::is_ascii_digit)) {
+                    end += 1;
+                }
+                spans.push(Span { start : pos, end, group : Group::Number });
+                pos = end;
+                continue;
+            }
+            if (c.is_alphabetic() || c == '_') && !after_word {
+                let len = chars[pos..].iter().take_while(|c| self.is_word_char(**c)).count();
+                let word : String = chars[pos..pos + len].iter().collect();
+                let group = match self.is_key(&chars, pos + len) {
+                    true => Some(Group::Identifier),
+                    false => self.word_group(&word),
+                };
+                if let Some(group) = group {
+                    spans.push(Span { start : pos, end : pos + len, group });
+                }
+                pos += len;
+                continue;
+            }
+            pos += 1;
+        }
+        (spans, State::default())
+    }
+}
+
+// Whether `s` is in `chars` at `pos`
+fn at(chars : &[char], pos : usize, s : &str) -> bool {
+    !s.is_empty() && s.chars().enumerate().all(|(n, c)| chars.get(pos + n) == Some(&c))
+}
+
+// A highlighted line and the state it was lexed from
+#[derive(Clone, Debug)]
+struct Line {
+    start : State,
+    end : State,
+    spans : Vec<Span>,
+}
+
+/// Highlighting of the lines of a buffer. Each line keeps the state it was
+/// lexed from, so after a change only the lines that changed, and those
+/// after them that now start in a different state, are lexed again.
+#[derive(Default)]
+pub struct Highlighter {
+    lang : Option<&'static Lang>,
+    lines : Vec<Option<Line>>,  // None for lines not lexed since they changed
+    valid : usize,              // Lines before it start in the right state
+}
+
+impl Highlighter {
+    pub fn new(lang : Option<&'static Lang>) -> Highlighter {
+        Highlighter { lang, lines : Vec::new(), valid : 0 }
+    }
+
+    /// The language the lines are highlighted as.
+    pub fn lang(&self) -> Option<&'static Lang> {
+        self.lang
+    }
+
+    /// Highlight the lines as `lang` from now on.
+    pub fn set_lang(&mut self, lang : Option<&'static Lang>) {
+        if lang.map(|l| l.name) != self.lang.map(|l| l.name) {
+            *self = Highlighter::new(lang);
+        }
+    }
+
+    // Forget the highlighting of the lines that changed
+    fn apply(&mut self, changes : &[LineChange]) {
+        for change in changes {
+            let end = (change.line + change.removed + 1).min(self.lines.len());
+            let start = change.line.min(end);
+            self.lines.splice(start..end, std::iter::repeat_n(None, change.added + 1));
+            self.valid = self.valid.min(change.line);
+        }
+    }
+
+    /// Bring the highlighting of `text` up to date up to line `last`.
+    pub fn update(&mut self, text : &mut Text, last : usize) {
+        self.apply(&text.take_line_changes());
+        let lang = match self.lang {
+            Some(lang) => lang,
+            None => return,
+        };
+        self.lines.resize(text.line_count(), None);
+        let last = last.min(text.line_count() - 1);
+        for n in self.valid..=last {
+            let start = match n {
+                0 => State::default(),
+                n => self.lines[n - 1].as_ref().map_or(State::default(), |l| l.end),
+            };
+            if self.lines[n].as_ref().is_some_and(|l| l.start == start) {
+                continue;
+            }
+            let (spans, end) = lang.lex(text.get_line(crate::pos::LineNo(n)), start);
+            self.lines[n] = Some(Line { start, end, spans });
+        }
+        self.valid = self.valid.max(last + 1);
+    }
+
+    /// The highlighted parts of line `n`, as of the last update.
+    pub fn spans(&self, n : usize) -> &[Span] {
+        self.lines.get(n).and_then(Option::as_ref).map_or(&[], |l| &l.spans)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pos::CharIdx;
+
+    fn lang(name : &str) -> &'static Lang {
+        super::lang(name).unwrap()
+    }
+
+    // The text of each span of `line` with its group
+    fn tokens(lang : &Lang, line : &str) -> Vec<(String, Group)> {
+        let chars : Vec<char> = line.chars().collect();
+        let (spans, _) = lang.lex(line, State::default());
+        spans.iter().map(|s| (chars[s.start..s.end].iter().collect(), s.group)).collect()
+    }
+
+    fn tok(s : &str, group : Group) -> (String, Group) {
+        (String::from(s), group)
+    }
+
+    #[test]
+    fn test_lex_rust() {
+        let rust = lang("rust");
+        assert_eq!(tokens(rust, "let x : Vec<u8> = 0x1f; // done"), vec![
+            tok("let", Group::Keyword), tok("Vec", Group::Type), tok("u8", Group::Type),
+            tok("0x1f", Group::Number), tok("// done", Group::Comment),
+        ]);
+        assert_eq!(tokens(rust, r#"f("a\"b", '\n', 'c')"#), vec![
+            tok(r#""a\"b""#, Group::String), tok(r"'\n'", Group::String), tok("'c'", Group::String),
+        ]);
+        // A lifetime is not a char literal
+        assert_eq!(tokens(rust, "fn f<'a>(x : &'a str)"), vec![
+            tok("fn", Group::Keyword), tok("str", Group::Type),
+        ]);
+        assert_eq!(tokens(rust, "x1 = 2"), vec![tok("2", Group::Number)]);
+        assert_eq!(tokens(rust, "  #[derive(Debug)]"), vec![tok("#[derive(Debug)]", Group::PreProc)]);
+    }
+
+    #[test]
+    fn test_lex_multiline() {
+        let c = lang("c");
+        let (spans, state) = c.lex("int x; /* start", State::default());
+        assert_eq!(spans.last(), Some(&Span { start : 7, end : 15, group : Group::Comment }));
+        assert_eq!(state, State { region : Some(0) });
+        let (spans, end) = c.lex("still */ return", state);
+        assert_eq!(spans, vec![
+            Span { start : 0, end : 8, group : Group::Comment },
+            Span { start : 9, end : 15, group : Group::Keyword },
+        ]);
+        assert_eq!(end, State::default());
+        // C strings end at the end of the line
+        assert_eq!(c.lex("\"open", State::default()).1, State::default());
+    }
+
+    #[test]
+    fn test_lex_others() {
+        assert_eq!(tokens(lang("python"), "def f(): return '''doc"), vec![
+            tok("def", Group::Keyword), tok("return", Group::Keyword), tok("'''doc", Group::String),
+        ]);
+        assert_eq!(tokens(lang("sh"), "echo \"$HOME\" ${x} $1 # note"), vec![
+            tok("\"$HOME\"", Group::String), tok("${x}", Group::Identifier),
+            tok("$1", Group::Identifier), tok("# note", Group::Comment),
+        ]);
+        assert_eq!(tokens(lang("json"), r#"{"a": [1.5, true, "b"]}"#), vec![
+            tok("\"a\"", Group::Identifier), tok("1.5", Group::Number),
+            tok("true", Group::Constant), tok("\"b\"", Group::String),
+        ]);
+        assert_eq!(tokens(lang("toml"), "[package]"), vec![tok("[package]", Group::Title)]);
+        assert_eq!(tokens(lang("toml"), "dev-deps = 'x' # c"), vec![
+            tok("dev-deps", Group::Identifier), tok("'x'", Group::String), tok("# c", Group::Comment),
+        ]);
+        assert_eq!(tokens(lang("yaml"), "- name: null"), vec![
+            tok("name", Group::Identifier), tok("null", Group::Constant),
+        ]);
+        assert_eq!(tokens(lang("markdown"), "## Title"), vec![tok("## Title", Group::Title)]);
+        assert_eq!(tokens(lang("markdown"), "use `x` and **y**"), vec![
+            tok("`x`", Group::String), tok("**y**", Group::Special),
+        ]);
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(lang("rust").quotes(), "\"");
+        assert_eq!(lang("python").quotes(), "\"'");
+        assert_eq!(lang("markdown").quotes(), "`");
+        assert!(super::lang("make").is_none());
+    }
+
+    #[test]
+    fn test_highlighter() {
+        let mut text = Text::new("a\n/* b\nc\nd */ e\nf");
+        let mut hl = Highlighter::new(Some(lang("c")));
+        hl.update(&mut text, 10);
+        assert_eq!(hl.spans(2), &[Span { start : 0, end : 1, group : Group::Comment }]);
+        assert!(hl.spans(4).is_empty());
+
+        // Only the changed line is lexed again
+        text.write_char("x", CharIdx(0)).unwrap();
+        hl.apply(&text.take_line_changes());
+        assert!(hl.lines[0].is_none());
+        assert!(hl.lines[1..].iter().all(Option::is_some));
+
+        // Ending the comment early changes the lines after it
+        text.insert_str(CharIdx(7), "*/").unwrap();
+        hl.update(&mut text, 10);
+        assert_eq!(hl.spans(1), &[Span { start : 0, end : 6, group : Group::Comment }]);
+        assert!(hl.spans(2).is_empty());
+        assert!(hl.spans(3).is_empty());
+    }
+}
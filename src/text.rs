@@ -1,8 +1,71 @@
 use std::fmt;
 
+use crate::anchor::{Anchor, Anchors, Gravity};
+use crate::mark::Marks;
+use crate::pos::{ByteIdx, CharIdx, LineNo, Position, Range};
+
+/// Why an operation on a `Text` failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TextError {
+    IndexOutOfRange { idx : CharIdx, size : usize },
+    LineOutOfRange { line : LineNo, count : usize },
+    NotCharBoundary(ByteIdx),   // Byte offset inside a char or past the end
+    EmptyInput,
+    MultipleChars,              // Given a string where one char was expected
+    ReadOnly,
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextError::IndexOutOfRange { idx, size } =>
+                write!(f, "Index {} out of range for text of {} chars", idx.0, size),
+            TextError::LineOutOfRange { line, count } =>
+                write!(f, "E16: Invalid range: line {} of {}", line.one_based(), count),
+            TextError::NotCharBoundary(b) => write!(f, "Byte {} is not at the start of a char", b.0),
+            TextError::EmptyInput => write!(f, "Cannot insert empty text"),
+            TextError::MultipleChars => write!(f, "Cannot insert more than one char"),
+            TextError::ReadOnly => write!(f, "E21: Cannot make changes, 'modifiable' is off"),
+        }
+    }
+}
+
+impl std::error::Error for TextError {}
+
+impl From<TextError> for String {
+    fn from(e : TextError) -> String {
+        e.to_string()
+    }
+}
+
+// One edit: `removed` was replaced by `inserted` at char index `idx`
+#[derive(Clone, Debug)]
+struct Edit {
+    idx : usize,
+    removed : String,
+    inserted : String,
+}
+
+/// Lines `line` to `line + removed` of the text were replaced by lines
+/// `line` to `line + added`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineChange {
+    pub line : usize,
+    pub removed : usize,
+    pub added : usize,
+}
+
 pub struct Text {
     text : String,              // Text being edited
     line_lengths : Vec<usize>,  // Length of each line, not counting newlines
+    changedtick : usize,        // Incremented on every change
+    undo : Vec<Vec<Edit>>,      // Finished changes, each undone as a whole
+    redo : Vec<Vec<Edit>>,      // Undone changes
+    current : Vec<Edit>,        // Edits of the change being made
+    marks : Marks,              // Kept on their text as it changes
+    anchors : Anchors,
+    modifiable : bool,          // Refuse all changes when false
+    line_changes : Vec<LineChange>, // Not yet taken by `take_line_changes`
 }
 
 impl Text {
@@ -10,9 +73,17 @@ impl Text {
         let mut t = Text {
             text : String::from(s),
             line_lengths : vec![0],
+            changedtick : 0,
+            undo : Vec::new(),
+            redo : Vec::new(),
+            current : Vec::new(),
+            marks : Marks::default(),
+            anchors : Anchors::default(),
+            modifiable : true,
+            line_changes : Vec::new(),
         };
         t.refresh_line_lengths();
-        return t;
+        t
     }
     fn refresh_line_lengths(&mut self) {
         let mut line_count : usize = 1;
@@ -21,10 +92,10 @@ impl Text {
         for c in self.text.chars() {
             match c {
                 '\n' => {
-                    if self.line_lengths.len() < line_count as usize {
+                    if self.line_lengths.len() < line_count {
                         self.line_lengths.push(0);
                     }
-                    self.line_lengths[(line_count - 1) as usize] = line_length;
+                    self.line_lengths[line_count - 1] = line_length;
                     line_count += 1;
                     line_length = 0;
                 }
@@ -32,162 +103,366 @@ impl Text {
             }
         }
 
-        if self.line_lengths.len() < line_count as usize {
+        if self.line_lengths.len() < line_count {
             self.line_lengths.push(0);
         }
-        self.line_lengths[(line_count - 1) as usize] = line_length;
+        self.line_lengths[line_count - 1] = line_length;
 
-        if self.line_lengths.len() > line_count as usize {
+        if self.line_lengths.len() > line_count {
             self.line_lengths[line_count] = 0;
         }
 
     }
 
-    pub fn find_line_number(&self, index : usize) -> Result<usize,&str> {
-        let mut line_count : usize = 1;
-        let mut total_length : usize = 0;
-
-        if self.text.chars().count() - 1 < index as usize {
-            return Err("Index must be within string");
-        }
-
-        for length in &self.line_lengths {
-            total_length += length + 1;
-            if total_length > index {
-                return Ok(line_count);
-            }
-            line_count += 1;
-        }
-        return Ok(line_count);
+    /// The line char `idx` is on. A newline is on the line it ends.
+    pub fn line_of(&self, idx : CharIdx) -> LineNo {
+        self.position(idx).line
     }
 
-    pub fn get_line_length(&self, line_no : usize) -> usize {
-        return match self.line_lengths.get(line_no) {
+    pub fn get_line_length(&self, line : LineNo) -> usize {
+        match self.line_lengths.get(line.0) {
             None => 0,
             Some(l) => *l,
         }
     }
 
-    pub fn get_line(&self, line_no : usize) -> &str {
-        if line_no >= self.line_lengths.len() {
+    pub fn get_line(&self, line : LineNo) -> &str {
+        if line.0 >= self.line_lengths.len() {
             return "";
         }
-        // Find index of substring
-        let mut start_idx : usize = 0;
-        for linelength in &self.line_lengths[..line_no] {
-            start_idx += linelength + 1;            
-        }
-        
-        match self
-            .index_to_byteoffset(start_idx) {
-            Some(o) => start_idx = o,
+        let start = match self.byte_idx(self.line_start(line)) {
+            Some(o) => o.0,
             None => panic!("Failed to get byteoffset to line"),
+        };
+        match self.text[start..].find('\n') {
+            Some(n_distance) => &self.text[start..start + n_distance],
+            None => &self.text[start..],
         }
-        let mut end_idx : usize = start_idx;
-        if self.line_lengths[line_no] > 0 && line_no + 1 < self.line_lengths.len() {
-            match self.text[start_idx..]
-                .find('\n') {
-                Some(n_distance) => {
-                    end_idx += n_distance;
-                },
-                None => return &self.text[start_idx..],
+    }
+
+    /// Char index of the first char of `line`.
+    pub fn line_start(&self, line : LineNo) -> CharIdx {
+        CharIdx(self.line_lengths[..line.0].iter().map(|l| l + 1).sum())
+    }
+
+    /// Char index of `pos`. Columns past the end of the line go on into the
+    /// lines after it.
+    pub fn char_idx(&self, pos : Position) -> CharIdx {
+        self.line_start(pos.line) + pos.col
+    }
+
+    /// Line and column of char index `idx`. The end of the text is after
+    /// the last char of the last line.
+    pub fn position(&self, idx : CharIdx) -> Position {
+        let mut start : usize = 0;
+        for (line, length) in self.line_lengths.iter().enumerate() {
+            if idx.0 <= start + length {
+                return Position::new(line, idx.0 - start);
             }
+            start += length + 1;
         }
-        else if line_no + 1 == self.line_lengths.len() {
-            return &self.text[start_idx..]
-        }
-        return &self.text[start_idx..end_idx]
+        let last = self.last_line();
+        Position { line : last, col : self.get_line_length(last) }
     }
 
-    pub fn get_string_index(&self, line_no : usize, xoffset : usize) -> usize {
-        let mut idx : usize = 0;
-        let offset : usize;
-        for line_length in &self.line_lengths[0..line_no] {
-            idx += line_length + 1;
+    /// Byte offset of char index `idx`, which may be the end of the text.
+    pub fn byte_idx(&self, idx : CharIdx) -> Option<ByteIdx> {
+        if self.size() > idx.0 {
+            return self.text.char_indices().nth(idx.0).map(|(b, _)| ByteIdx(b));
         }
-
-        let current_line_length = self.get_line(line_no).chars().count();
-        if xoffset == current_line_length  {
-            offset = current_line_length + idx; 
+        else if self.size() == idx.0 {
+            return Some(ByteIdx(self.text.len()));
         }
-        else {
-            idx += xoffset; //self.text[idx..].chars().count(); //.char_indices().nth(xoffset).map(|(i, _)| i).unwrap();
-            offset = idx;
-            //let _ = self.index_to_byteoffset(idx)
-            //    .expect("Failed to find offset at get-string-index");
+        None
+    }
+
+    /// Char index at byte offset `b`, when a char starts there.
+    pub fn char_idx_at(&self, b : ByteIdx) -> Result<CharIdx, TextError> {
+        match self.text.is_char_boundary(b.0) {
+            true => Ok(CharIdx(self.text[..b.0].chars().count())),
+            false => Err(TextError::NotCharBoundary(b)),
         }
-            offset
     }
 
     pub fn size(&self) -> usize {
-        return self.text.chars().count();
+        self.text.chars().count()
+    }
+
+    /// Char index just past the last char.
+    pub fn end(&self) -> CharIdx {
+        CharIdx(self.size())
     }
     
-    pub fn line_count(&self) -> u16 {
-        return self.line_lengths.len() as u16;
+    pub fn line_count(&self) -> usize {
+        self.line_lengths.len()
+    }
+
+    pub fn last_line(&self) -> LineNo {
+        LineNo(self.line_lengths.len() - 1)
     }
 
     pub fn get_text(&self) -> &str {
-        return &self.text
+        &self.text
     }
 
-    pub fn index_to_byteoffset(&self, n: usize) -> Option<usize> {
-        if self.size() == 0 {
-            return Some(0);
-        }
-        if self.size() > n {
-            return self.text.char_indices().nth(n).map(|(idx, _)| idx);
+    /// The chars in `range`.
+    pub fn slice(&self, range : Range) -> String {
+        self.text.chars().skip(range.start.0).take(range.len()).collect()
+    }
+
+    pub fn changedtick(&self) -> usize {
+        self.changedtick
+    }
+
+    pub fn is_modifiable(&self) -> bool {
+        self.modifiable
+    }
+
+    /// Allow or refuse changes, including undo and redo.
+    pub fn set_modifiable(&mut self, modifiable : bool) {
+        self.modifiable = modifiable;
+    }
+
+    fn check_modifiable(&self) -> Result<(), TextError> {
+        match self.modifiable {
+            true => Ok(()),
+            false => Err(TextError::ReadOnly),
         }
-        else if self.size() == n {
-            let (idx, c) = self.text.char_indices().last().unwrap();
-            return Some(idx + c.len_utf8());
+    }
+
+    fn check_line(&self, line : LineNo) -> Result<(), TextError> {
+        match line.0 < self.line_count() {
+            true => Ok(()),
+            false => Err(TextError::LineOutOfRange { line, count : self.line_count() }),
         }
-        return None;
     }
 
-    pub fn write_char<'a>(&mut self, c : &'a str, idx : usize) -> Result<&'a str, &'a str> {
+    pub fn write_char<'a>(&mut self, c : &'a str, idx : CharIdx) -> Result<&'a str, TextError> {
         match c.chars().count() {
-            1 => {
-                let offset : usize;
-                match self.index_to_byteoffset(idx) {
-                    Some(o) => offset = o,
-                    None => return Err("invalid index"),
-                }
-                self.text.insert_str(offset, &c);
-                match c {
-                    "\n" => {
-                        let current_line = self.find_line_number(idx).ok().unwrap() - 1;
-                        self.line_lengths.insert(current_line, 0);
-                        self.refresh_line_lengths();
-                    }
-                    _ => {
-                        // Optimization
-                        let current_line = self.find_line_number(idx).ok().unwrap();
-                        self.line_lengths[current_line - 1] += 1;
-                    }
-                }
-                Ok(c)
-            }
-            0 => Err("Cannot push empty string."),
-            _ => Err("Cannot push multiple chars."),
+            1 => self.insert_str(idx, c).map(|_| c),
+            0 => Err(TextError::EmptyInput),
+            _ => Err(TextError::MultipleChars),
+        }
+    }
+
+    pub fn remove_at(&mut self, idx : CharIdx) -> Result<char, TextError> {
+        self.check_modifiable()?;
+        if idx.0 >= self.size() {
+            return Err(TextError::IndexOutOfRange { idx, size : self.size() });
+        }
+        let removed = self.splice(idx.0, 1, "");
+        Ok(removed.chars().next().unwrap_or_default())
+    }
+
+    /// Insert `s` before char index `idx`, which may be the end of the text.
+    pub fn insert_str(&mut self, idx : CharIdx, s : &str) -> Result<(), TextError> {
+        self.replace_range(Range::new(idx, idx), s).map(|_| ())
+    }
+
+    /// Delete the chars in `range` and return them.
+    pub fn delete_range(&mut self, range : Range) -> Result<String, TextError> {
+        self.replace_range(range, "")
+    }
+
+    /// Replace the chars in `range` with `s` as a single edit, and return
+    /// the chars replaced.
+    pub fn replace_range(&mut self, range : Range, s : &str) -> Result<String, TextError> {
+        self.check_modifiable()?;
+        if range.end.0 > self.size() || range.start > range.end {
+            return Err(TextError::IndexOutOfRange { idx : range.end.max(range.start), size : self.size() });
+        }
+        if range.is_empty() && s.is_empty() {
+            return Ok(String::new());
+        }
+        Ok(self.splice(range.start.0, range.len(), s))
+    }
+
+    // Replace `len` chars at `idx` with `s` without recording it for undo
+    fn splice_raw(&mut self, idx : usize, len : usize, s : &str) -> String {
+        let start = self.byte_idx(CharIdx(idx)).map_or(self.text.len(), |b| b.0);
+        let end = self.byte_idx(CharIdx(idx + len)).map_or(self.text.len(), |b| b.0);
+        let (first, last) = (self.position(CharIdx(idx)), self.position(CharIdx(idx + len)));
+        let removed = self.text[start..end].to_string();
+        self.text.replace_range(start..end, s);
+        self.changedtick += 1;
+        self.moved(idx, len, s.chars().count());
+
+        // Only the lines the edit was on change length: the text before it
+        // on the first one and after it on the last one are kept
+        let after = self.get_line_length(last.line) - last.col;
+        let mut lengths : Vec<usize> = s.split('\n').map(|l| l.chars().count()).collect();
+        lengths[0] += first.col;
+        *lengths.last_mut().unwrap() += after;
+        let change = LineChange { line : first.line.0, removed : last.line.0 - first.line.0, added : lengths.len() - 1 };
+        self.line_lengths.splice(first.line.0..=last.line.0, lengths);
+        self.line_changes.push(change);
+        removed
+    }
+
+    fn splice(&mut self, idx : usize, len : usize, s : &str) -> String {
+        let removed = self.splice_raw(idx, len, s);
+        self.record(idx, &removed, s);
+        removed
+    }
+
+    // Keep an edit for undo, and set the `.`, `[` and `]` marks to it
+    fn record(&mut self, idx : usize, removed : &str, inserted : &str) {
+        let idx = CharIdx(idx);
+        let last = idx + inserted.chars().count().max(1) - 1;
+        let (first, last) = match (self.current.is_empty(), self.marks.get('['), self.marks.get(']')) {
+            (false, Some(f), Some(l)) => (f.min(idx), l.max(last)),
+            _ => (idx, last),
+        };
+        self.marks.set('[', first);
+        self.marks.set(']', last);
+        self.marks.set('.', idx);
+        self.current.push(Edit { idx : idx.0, removed : String::from(removed), inserted : String::from(inserted) });
+        self.redo.clear();
+    }
+
+    // Keep marks and anchors on their text after an edit
+    fn moved(&mut self, idx : usize, removed : usize, inserted : usize) {
+        self.marks.adjust(idx, removed, inserted);
+        self.anchors.adjust(idx, removed, inserted);
+    }
+
+    /// Anchor a position at char index `idx`, to be moved along by edits,
+    /// undo and redo until it is removed.
+    pub fn anchor(&mut self, idx : CharIdx, gravity : Gravity) -> Anchor {
+        self.anchors.add(idx.min(self.end()), gravity)
+    }
+
+    pub fn anchor_index(&self, anchor : Anchor) -> Option<CharIdx> {
+        self.anchors.get(anchor)
+    }
+
+    pub fn anchor_position(&self, anchor : Anchor) -> Option<Position> {
+        self.anchor_index(anchor).map(|idx| self.position(idx))
+    }
+
+    pub fn remove_anchor(&mut self, anchor : Anchor) {
+        self.anchors.remove(anchor);
+    }
+
+    pub fn marks(&self) -> &Marks {
+        &self.marks
+    }
+
+    pub fn marks_mut(&mut self) -> &mut Marks {
+        &mut self.marks
+    }
+
+    /// Lines `first` to `last`, inclusive.
+    pub fn get_lines(&self, first : LineNo, last : LineNo) -> Vec<String> {
+        (first.0..=last.0).map(|n| String::from(self.get_line(LineNo(n)))).collect()
+    }
+
+    /// Insert `lines` before line `at`, or after the last line when `at`
+    /// is the line count.
+    pub fn insert_lines(&mut self, at : LineNo, lines : &[String]) -> Result<(), TextError> {
+        self.check_modifiable()?;
+        if at.0 > self.line_count() {
+            return Err(TextError::LineOutOfRange { line : at, count : self.line_count() });
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let joined = lines.join("\n");
+        if at.0 >= self.line_count() {
+            let end = self.size();
+            self.splice(end, 0, &format!("\n{}", joined));
+        }
+        else {
+            let idx = self.line_start(at);
+            self.splice(idx.0, 0, &format!("{}\n", joined));
         }
+        Ok(())
     }
-    pub fn remove_at(&mut self, idx : usize) -> Result<char,&str> {
-        if idx >= self.size() {
-            return Err("cannot remove element not in string.");
+
+    /// Delete lines `first` to `last`, inclusive, and return them. Deleting
+    /// every line leaves one empty line.
+    pub fn delete_lines(&mut self, first : LineNo, last : LineNo) -> Result<Vec<String>, TextError> {
+        self.check_modifiable()?;
+        self.check_line(last)?;
+        let lines = self.get_lines(first, last);
+        let end = self.line_start(last) + self.get_line_length(last);
+        if last.0 + 1 < self.line_count() {
+            let start = self.line_start(first);
+            self.splice(start.0, end - start + 1, "");
         }
-        let current_line = self.find_line_number(idx).ok().unwrap();
-        let offset : usize = self.index_to_byteoffset(idx)
-            .expect("Failed to find offset at write-char");
-        let pop_char = self.text.remove(offset);
-        if pop_char == '\n' {
-            // Optimize this later
-            self.refresh_line_lengths();
+        else if first.0 > 0 {
+            let start = self.line_start(first - 1) + self.get_line_length(first - 1);
+            self.splice(start.0, end - start, "");
         }
         else {
-            self.line_lengths[current_line - 1] -= 1;
+            self.splice(0, end.0, "");
+        }
+        Ok(lines)
+    }
+
+    /// Replace lines `first` to `last`, inclusive, with `lines`.
+    pub fn replace_lines(&mut self, first : LineNo, last : LineNo, lines : &[String]) -> Result<(), TextError> {
+        if lines.is_empty() {
+            return self.delete_lines(first, last).map(|_| ());
         }
-        Ok(pop_char)
+        self.check_modifiable()?;
+        self.check_line(last)?;
+        let start = self.line_start(first);
+        let end = self.line_start(last) + self.get_line_length(last);
+        self.splice(start.0, end - start, &lines.join("\n"));
+        Ok(())
+    }
+
+    /// The lines changed since the last call, in the order they changed.
+    pub fn take_line_changes(&mut self) -> Vec<LineChange> {
+        std::mem::take(&mut self.line_changes)
+    }
+
+    /// End the change being made, so the next edit starts a new one.
+    pub fn close_change(&mut self) {
+        if !self.current.is_empty() {
+            let change = std::mem::take(&mut self.current);
+            self.undo.push(change);
+            if let Some(dot) = self.marks.get('.') {
+                let line = self.line_of(dot);
+                let same_line = self.marks.last_change().is_some_and(|last| self.line_of(last) == line);
+                self.marks.push_change(dot, same_line);
+            }
+        }
+    }
+
+    /// Undo the last change. Returns where it started, or None when there is
+    /// nothing to undo.
+    pub fn undo(&mut self) -> Result<Option<CharIdx>, TextError> {
+        self.check_modifiable()?;
+        self.close_change();
+        let change = match self.undo.pop() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        for edit in change.iter().rev() {
+            self.splice_raw(edit.idx, edit.inserted.chars().count(), &edit.removed);
+        }
+        let start = change.iter().map(|e| CharIdx(e.idx)).min();
+        self.redo.push(change);
+        Ok(start)
+    }
+
+    /// Redo the last undone change. Returns where it started, or None when
+    /// there is nothing to redo.
+    pub fn redo(&mut self) -> Result<Option<CharIdx>, TextError> {
+        self.check_modifiable()?;
+        self.close_change();
+        let change = match self.redo.pop() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        for edit in &change {
+            self.splice_raw(edit.idx, edit.removed.chars().count(), &edit.inserted);
+        }
+        let start = change.iter().map(|e| CharIdx(e.idx)).min();
+        self.undo.push(change);
+        Ok(start)
     }
 }
 
@@ -230,107 +505,107 @@ mod tests {
     fn test_append_character() {
         let mut t : Text = Text::new("Some text");
 
-        match t.write_char(".", 9) {
+        match t.write_char(".", CharIdx(9)) {
             Ok(_) => print!(""),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Some text.");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
     }
 
     #[test]
     fn test_insert_characters() {
         let mut t : Text = Text::new("ometxt.");
 
-        match t.write_char("S", 0) {
+        match t.write_char("S", CharIdx(0)) {
             Ok(_) => print!(""),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Sometxt.");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
 
-        match t.write_char(" ", 4) {
+        match t.write_char(" ", CharIdx(4)) {
             Ok(_) => print!(""),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Some txt.");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
 
-        match t.write_char("e", 6) {
+        match t.write_char("e", CharIdx(6)) {
             Ok(_) => print!(""),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Some text.");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
     }
 
     #[test]
     fn test_insert_æ() {
         let mut t : Text = Text::new(", ");
 
-        match t.write_char("æ", 1) {
+        match t.write_char("æ", CharIdx(1)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), ",æ ");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
         }
 
     #[test]
     fn test_insert_after_æ() {
         let mut t : Text = Text::new("æ, ");
 
-        match t.write_char("v",1) {
+        match t.write_char("v", CharIdx(1)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "æv, ");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
     }
 
     #[test]
     fn test_insert_æøå() {
         let mut t : Text = Text::new("Æ, ");
 
-        match t.write_char("v", 1) {
+        match t.write_char("v", CharIdx(1)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Æv, ");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
 
-        match t.write_char("å", 4) {
+        match t.write_char("å", CharIdx(4)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Æv, å");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
 
-        match t.write_char("ø", 5) {
+        match t.write_char("ø", CharIdx(5)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Æv, åø");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
 
-        match t.write_char("æ", 6) {
+        match t.write_char("æ", CharIdx(6)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Æv, åøæ");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
 
-        match t.write_char("v", 1) {
+        match t.write_char("v", CharIdx(1)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
@@ -341,164 +616,480 @@ mod tests {
     fn test_pop_character() {
         let mut t : Text = Text::new("Some text.");
 
-        match t.remove_at(9) {
+        match t.remove_at(CharIdx(9)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Some text");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
     }
 
     #[test]
     fn test_remove_characters() {
         let mut t : Text = Text::new("Some text.");
 
-        match t.remove_at(0) {
+        match t.remove_at(CharIdx(0)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "ome text.");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
 
-        match t.remove_at(2) {
+        match t.remove_at(CharIdx(2)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "om text.");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
 
-        match t.remove_at(4) {
+        match t.remove_at(CharIdx(4)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "om txt.");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
     }
     #[test]
     fn test_pop_newline() {
         let mut t : Text = Text::new("Some\ntext.");
 
-        match t.remove_at(4) {
+        match t.remove_at(CharIdx(4)) {
             Ok(_) => (),
             Err(e) => eprintln!("{}", e),
         }
 
         assert_eq!(format!("{}", t), "Sometext.");
-        assert_eq!(t.line_lengths[0], t.text.chars().count().try_into().unwrap());
-        assert_eq!(t.line_lengths[1], 0);
+        assert_eq!(t.line_lengths[0], t.text.chars().count());
+        assert_eq!(t.get_line_length(LineNo(1)), 0);
+    }
+
+    #[test]
+    fn test_pop_newline_drops_line() {
+        let mut t : Text = Text::new("Some\ntext.\nmore");
+        t.remove_at(CharIdx(4)).unwrap();
+        assert_eq!(t.line_count(), 2);
+        assert_eq!(t.line_lengths, vec![9, 4]);
     }
 
     #[test]
     fn test_line_length_getter() {
         let t : Text = Text::new("This\nIs\nSome\nText.");
 
-        assert_eq!(t.get_line_length(0),4);
-        assert_eq!(t.get_line_length(1),2);
-        assert_eq!(t.get_line_length(2),4);
-        assert_eq!(t.get_line_length(3),5);
-        assert_eq!(t.get_line_length(4),0);
+        assert_eq!(t.get_line_length(LineNo(0)),4);
+        assert_eq!(t.get_line_length(LineNo(1)),2);
+        assert_eq!(t.get_line_length(LineNo(2)),4);
+        assert_eq!(t.get_line_length(LineNo(3)),5);
+        assert_eq!(t.get_line_length(LineNo(4)),0);
     }
 
     #[test]
     fn test_line_length_getter_æøå() {
         let t : Text = Text::new("Han bærer\nto bøker\npå føllen\nsin hjemover.");
 
-        assert_eq!(t.get_line_length(0),9);
-        assert_eq!(t.get_line_length(1),8);
-        assert_eq!(t.get_line_length(2),9);
-        assert_eq!(t.get_line_length(3),13);
-        assert_eq!(t.get_line_length(4),0);
+        assert_eq!(t.get_line_length(LineNo(0)),9);
+        assert_eq!(t.get_line_length(LineNo(1)),8);
+        assert_eq!(t.get_line_length(LineNo(2)),9);
+        assert_eq!(t.get_line_length(LineNo(3)),13);
+        assert_eq!(t.get_line_length(LineNo(4)),0);
     }
 
     #[test]
     fn test_check_line_count() {
         let t : Text = Text::new("This\nIs\nSome\nText.");
 
-        assert_eq!(t.find_line_number(0),Ok(1));
-        assert_eq!(t.find_line_number(1),Ok(1));
-        assert_eq!(t.find_line_number(2),Ok(1));
-        assert_eq!(t.find_line_number(3),Ok(1));
-        assert_eq!(t.find_line_number(4),Ok(1));
-        assert_eq!(t.find_line_number(5),Ok(2));
-        assert_eq!(t.find_line_number(6),Ok(2));
-        assert_eq!(t.find_line_number(7),Ok(2));
-        assert_eq!(t.find_line_number(8),Ok(3));
-        assert_eq!(t.find_line_number(9),Ok(3));
-        assert_eq!(t.find_line_number(10),Ok(3));
-        assert_eq!(t.find_line_number(11),Ok(3));
-        assert_eq!(t.find_line_number(12),Ok(3));
-        assert_eq!(t.find_line_number(13),Ok(4));
-        assert_eq!(t.find_line_number(14),Ok(4));
-        assert_eq!(t.find_line_number(15),Ok(4));
+        assert_eq!(t.line_of(CharIdx(0)), LineNo(0));
+        assert_eq!(t.line_of(CharIdx(1)), LineNo(0));
+        assert_eq!(t.line_of(CharIdx(2)), LineNo(0));
+        assert_eq!(t.line_of(CharIdx(3)), LineNo(0));
+        assert_eq!(t.line_of(CharIdx(4)), LineNo(0));
+        assert_eq!(t.line_of(CharIdx(5)), LineNo(1));
+        assert_eq!(t.line_of(CharIdx(6)), LineNo(1));
+        assert_eq!(t.line_of(CharIdx(7)), LineNo(1));
+        assert_eq!(t.line_of(CharIdx(8)), LineNo(2));
+        assert_eq!(t.line_of(CharIdx(9)), LineNo(2));
+        assert_eq!(t.line_of(CharIdx(10)), LineNo(2));
+        assert_eq!(t.line_of(CharIdx(11)), LineNo(2));
+        assert_eq!(t.line_of(CharIdx(12)), LineNo(2));
+        assert_eq!(t.line_of(CharIdx(13)), LineNo(3));
+        assert_eq!(t.line_of(CharIdx(14)), LineNo(3));
+        assert_eq!(t.line_of(CharIdx(15)), LineNo(3));
     }
     #[test]
     fn test_get_index_start_of_line() {
         let t : Text = Text::new("This\nIs\nSome\nText.");
 
-        assert_eq!(t.get_string_index(0,0),0);
-        assert_eq!(t.get_string_index(1,0),5);
-        assert_eq!(t.get_string_index(2,0),8);
-        assert_eq!(t.get_string_index(3,0),13);
+        assert_eq!(t.char_idx(Position::new(0, 0)), CharIdx(0));
+        assert_eq!(t.char_idx(Position::new(1, 0)), CharIdx(5));
+        assert_eq!(t.char_idx(Position::new(2, 0)), CharIdx(8));
+        assert_eq!(t.char_idx(Position::new(3, 0)), CharIdx(13));
     }
     #[test]
     fn test_get_index_middle_of_line() {
         let t : Text = Text::new("This\nIs\nSome\nText.");
 
-        assert_eq!(t.get_string_index(0,1),1);
-        assert_eq!(t.get_string_index(1,1),6);
-        assert_eq!(t.get_string_index(2,2),10);
-        assert_eq!(t.get_string_index(3,3),16);
+        assert_eq!(t.char_idx(Position::new(0, 1)), CharIdx(1));
+        assert_eq!(t.char_idx(Position::new(1, 1)), CharIdx(6));
+        assert_eq!(t.char_idx(Position::new(2, 2)), CharIdx(10));
+        assert_eq!(t.char_idx(Position::new(3, 3)), CharIdx(16));
     }
     #[test]
     fn test_get_index_end_of_line() {
         let t : Text = Text::new("This\nIs\nSome\nText.");
 
-        assert_eq!(t.get_string_index(0,4),4);
-        assert_eq!(t.get_string_index(1,2),7);
-        assert_eq!(t.get_string_index(2,4),12);
-        assert_eq!(t.get_string_index(3,5),18);
+        assert_eq!(t.char_idx(Position::new(0, 4)), CharIdx(4));
+        assert_eq!(t.char_idx(Position::new(1, 2)), CharIdx(7));
+        assert_eq!(t.char_idx(Position::new(2, 4)), CharIdx(12));
+        assert_eq!(t.char_idx(Position::new(3, 5)), CharIdx(18));
     }
 
     #[test]
     fn test_get_index_start_of_line_æøå() {
         let t : Text = Text::new("rårr\nbø\nbørs\nbønde");
 
-        assert_eq!(t.get_string_index(0,0),0);
-        assert_eq!(t.get_string_index(1,0),5);
-        assert_eq!(t.get_string_index(2,0),8);
-        assert_eq!(t.get_string_index(3,0),13);
+        assert_eq!(t.char_idx(Position::new(0, 0)), CharIdx(0));
+        assert_eq!(t.char_idx(Position::new(1, 0)), CharIdx(5));
+        assert_eq!(t.char_idx(Position::new(2, 0)), CharIdx(8));
+        assert_eq!(t.char_idx(Position::new(3, 0)), CharIdx(13));
     }
     #[test]
     fn test_get_index_middle_of_line_æøå() {
         let t : Text = Text::new("rårr\nbø\nbørs\nbønde");
 
-        assert_eq!(t.get_string_index(0,1),1);
-        assert_eq!(t.get_string_index(1,1),6);
-        assert_eq!(t.get_string_index(2,2),10);
-        assert_eq!(t.get_string_index(3,3),16);
+        assert_eq!(t.char_idx(Position::new(0, 1)), CharIdx(1));
+        assert_eq!(t.char_idx(Position::new(1, 1)), CharIdx(6));
+        assert_eq!(t.char_idx(Position::new(2, 2)), CharIdx(10));
+        assert_eq!(t.char_idx(Position::new(3, 3)), CharIdx(16));
     }
     #[test]
     fn test_get_index_end_of_line_æøå() {
         let t : Text = Text::new("rårr\nbø\nbørs\nbønde");
 
-        assert_eq!(t.get_string_index(0,4),4);
-        assert_eq!(t.get_string_index(1,2),7);
-        assert_eq!(t.get_string_index(2,4),12);
-        assert_eq!(t.get_string_index(3,5),18);
+        assert_eq!(t.char_idx(Position::new(0, 4)), CharIdx(4));
+        assert_eq!(t.char_idx(Position::new(1, 2)), CharIdx(7));
+        assert_eq!(t.char_idx(Position::new(2, 4)), CharIdx(12));
+        assert_eq!(t.char_idx(Position::new(3, 5)), CharIdx(18));
     }
 
     #[test]
     fn test_get_line() {
         let t : Text = Text::new("This\nIs\nSome\nText.");
         
-        assert_eq!(t.get_line(0), "This");
-        assert_eq!(t.get_line(1), "Is");
-        assert_eq!(t.get_line(2), "Some");
-        assert_eq!(t.get_line(3), "Text.");
+        assert_eq!(t.get_line(LineNo(0)), "This");
+        assert_eq!(t.get_line(LineNo(1)), "Is");
+        assert_eq!(t.get_line(LineNo(2)), "Some");
+        assert_eq!(t.get_line(LineNo(3)), "Text.");
+    }
+
+    fn lines(v : &[&str]) -> Vec<String> {
+        v.iter().map(|s| String::from(*s)).collect()
+    }
+
+    #[test]
+    fn test_insert_lines() {
+        let mut t : Text = Text::new("one\ntwo");
+        t.insert_lines(LineNo(1), &lines(&["a", "b"])).unwrap();
+        assert_eq!(t.get_text(), "one\na\nb\ntwo");
+        t.insert_lines(LineNo(4), &lines(&["end"])).unwrap();
+        assert_eq!(t.get_text(), "one\na\nb\ntwo\nend");
+        t.insert_lines(LineNo(0), &lines(&["top"])).unwrap();
+        assert_eq!(t.get_line(LineNo(0)), "top");
+        assert_eq!(t.line_count(), 6);
+    }
+
+    #[test]
+    fn test_delete_lines() {
+        let mut t : Text = Text::new("one\ntwo\nthree");
+        assert_eq!(t.delete_lines(LineNo(1), LineNo(1)), Ok(lines(&["two"])));
+        assert_eq!(t.get_text(), "one\nthree");
+        t.delete_lines(LineNo(1), LineNo(1)).unwrap();
+        assert_eq!(t.get_text(), "one");
+        t.delete_lines(LineNo(0), LineNo(0)).unwrap();
+        assert_eq!(t.get_text(), "");
+        assert_eq!(t.line_count(), 1);
+    }
+
+    #[test]
+    fn test_replace_lines() {
+        let mut t : Text = Text::new("a\nb\nc");
+        t.replace_lines(LineNo(0), LineNo(1), &lines(&["ab"])).unwrap();
+        assert_eq!(t.get_text(), "ab\nc");
+        t.replace_lines(LineNo(1), LineNo(1), &lines(&["x", "y"])).unwrap();
+        assert_eq!(t.get_text(), "ab\nx\ny");
+        assert_eq!(t.line_count(), 3);
+    }
+
+    #[test]
+    fn test_position() {
+        let t : Text = Text::new("ab\nc\n\nd");
+        assert_eq!(t.position(CharIdx(0)), Position::new(0, 0));
+        assert_eq!(t.position(CharIdx(2)), Position::new(0, 2));
+        assert_eq!(t.position(CharIdx(3)), Position::new(1, 0));
+        assert_eq!(t.position(CharIdx(5)), Position::new(2, 0));
+        assert_eq!(t.position(CharIdx(6)), Position::new(3, 0));
+    }
+
+    #[test]
+    fn test_conversions_round_trip() {
+        let t : Text = Text::new("æø\n\nå€x\n");
+        for i in 0..=t.size() {
+            let idx = CharIdx(i);
+            assert_eq!(t.char_idx(t.position(idx)), idx);
+            assert_eq!(t.char_idx_at(t.byte_idx(idx).unwrap()), Ok(idx));
+        }
+        assert_eq!(t.byte_idx(CharIdx(4)), Some(ByteIdx(6)));
+        // Not a char boundary, inside the '€'
+        assert_eq!(t.char_idx_at(ByteIdx(9)), Err(TextError::NotCharBoundary(ByteIdx(9))));
+        assert_eq!(t.byte_idx(t.end() + 1), None);
+    }
+
+    #[test]
+    fn test_many_lines() {
+        let t : Text = Text::new(&"x\n".repeat(69_999));
+        assert_eq!(t.line_count(), 70_000);
+        assert_eq!(t.get_line(LineNo(69_998)), "x");
+        assert_eq!(t.line_start(LineNo(69_999)), CharIdx(139_998));
+        assert_eq!(t.position(t.end()), Position::new(69_999, 0));
+    }
+
+    #[test]
+    fn test_undo_redo_change() {
+        let mut t : Text = Text::new("one\ntwo");
+        t.write_char("x", CharIdx(0)).unwrap();
+        t.write_char("y", CharIdx(1)).unwrap();
+        t.close_change();
+        t.replace_lines(LineNo(0), LineNo(1), &lines(&["joined"])).unwrap();
+        t.insert_lines(LineNo(1), &lines(&["more"])).unwrap();
+        assert_eq!(t.get_text(), "joined\nmore");
+
+        // Both line edits are one change
+        assert_eq!(t.undo(), Ok(Some(CharIdx(0))));
+        assert_eq!(t.get_text(), "xyone\ntwo");
+        assert_eq!(t.undo(), Ok(Some(CharIdx(0))));
+        assert_eq!(t.get_text(), "one\ntwo");
+        assert_eq!(t.undo(), Ok(None));
+        assert_eq!(t.redo(), Ok(Some(CharIdx(0))));
+        assert_eq!(t.get_text(), "xyone\ntwo");
+
+        // A new change forgets what was undone
+        t.remove_at(CharIdx(0)).unwrap();
+        assert_eq!(t.redo(), Ok(None));
+        assert_eq!(t.get_text(), "yone\ntwo");
+    }
+
+    #[test]
+    fn test_line_changes() {
+        let mut t : Text = Text::new("one\ntwo\nthree");
+        t.write_char("x", CharIdx(4)).unwrap();
+        t.insert_str(CharIdx(0), "a\nb\n").unwrap();
+        t.delete_lines(LineNo(3), LineNo(4)).unwrap();
+        assert_eq!(t.take_line_changes(), vec![
+            LineChange { line : 1, removed : 0, added : 0 },
+            LineChange { line : 0, removed : 0, added : 2 },
+            LineChange { line : 2, removed : 2, added : 0 },
+        ]);
+        assert_eq!(t.take_line_changes(), vec![]);
+        t.undo().unwrap();
+        assert_eq!(t.take_line_changes().len(), 3);
     }
-}
 
+    #[test]
+    fn test_marks_follow_text() {
+        let mut t : Text = Text::new("one\ntwo");
+        t.marks_mut().set('a', CharIdx(4));
+        t.write_char("x", CharIdx(0)).unwrap();
+        assert_eq!(t.marks().get('a'), Some(CharIdx(5)));
+        t.remove_at(CharIdx(1)).unwrap();
+        t.remove_at(CharIdx(1)).unwrap();
+        assert_eq!(t.marks().get('a'), Some(CharIdx(3)));
+        assert_eq!(t.get_text().chars().nth(3), Some('t'));
+        t.close_change();
+        t.insert_lines(LineNo(0), &[String::from("new")]).unwrap();
+        assert_eq!(t.marks().get('a'), Some(CharIdx(7)));
+        // Undo puts it back where its text goes
+        t.undo().unwrap();
+        assert_eq!(t.marks().get('a'), Some(CharIdx(3)));
+        assert_eq!(t.marks().get('.'), Some(CharIdx(0)));
+    }
+
+    #[test]
+    fn test_insert_delete_replace_range() {
+        let mut t : Text = Text::new("æb\ncd");
+        t.insert_str(CharIdx(1), "x\nyø").unwrap();
+        assert_eq!(t.get_text(), "æx\nyøb\ncd");
+        assert_eq!(t.line_count(), 3);
+        assert_eq!(t.get_line_length(LineNo(1)), 3);
+        assert_eq!(t.delete_range(Range::new(CharIdx(2), CharIdx(8))), Ok(String::from("\nyøb\nc")));
+        assert_eq!(t.get_text(), "æxd");
+        assert_eq!(t.line_count(), 1);
+        assert_eq!(t.replace_range(Range::new(CharIdx(0), CharIdx(2)), "1\n2\n"), Ok(String::from("æx")));
+        assert_eq!(t.get_text(), "1\n2\nd");
+        assert_eq!(t.get_line(LineNo(2)), "d");
+        // At the end, and nothing at all
+        t.insert_str(t.end(), "\n").unwrap();
+        assert_eq!(t.line_count(), 4);
+        assert_eq!(t.delete_range(Range::new(CharIdx(2), CharIdx(2))), Ok(String::new()));
+        assert_eq!(t.delete_range(Range::new(CharIdx(2), CharIdx(9))),
+            Err(TextError::IndexOutOfRange { idx : CharIdx(9), size : 6 }));
+    }
+
+    #[test]
+    fn test_range_edit_is_one_change() {
+        let mut t : Text = Text::new("one\ntwo");
+        let anchor = t.anchor(CharIdx(5), Gravity::Right);
+        let tick = t.changedtick();
+        t.replace_range(Range::new(CharIdx(1), CharIdx(6)), "N\nE\nT").unwrap();
+        assert_eq!(t.get_text(), "oN\nE\nTo");
+        assert_eq!(t.changedtick(), tick + 1);
+        // The anchor was in the replaced text, so it goes after the new text
+        assert_eq!(t.anchor_index(anchor), Some(CharIdx(6)));
+        assert_eq!((t.marks().get('['), t.marks().get(']')), (Some(CharIdx(1)), Some(CharIdx(5))));
+        t.close_change();
+        assert_eq!(t.undo(), Ok(Some(CharIdx(1))));
+        assert_eq!(t.get_text(), "one\ntwo");
+        assert_eq!(t.line_count(), 2);
+        assert_eq!(t.undo(), Ok(None));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut t : Text = Text::new("ab\nc");
+        assert_eq!(t.write_char("x", CharIdx(5)), Err(TextError::IndexOutOfRange { idx : CharIdx(5), size : 4 }));
+        assert_eq!(t.remove_at(CharIdx(4)), Err(TextError::IndexOutOfRange { idx : CharIdx(4), size : 4 }));
+        assert_eq!(t.write_char("", CharIdx(0)), Err(TextError::EmptyInput));
+        assert_eq!(t.write_char("xy", CharIdx(0)), Err(TextError::MultipleChars));
+        assert_eq!(t.delete_lines(LineNo(0), LineNo(2)), Err(TextError::LineOutOfRange { line : LineNo(2), count : 2 }));
+        assert_eq!(t.insert_lines(LineNo(3), &lines(&["x"])), Err(TextError::LineOutOfRange { line : LineNo(3), count : 2 }));
+        assert_eq!(t.get_text(), "ab\nc");
+        assert_eq!(t.changedtick(), 0);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mut t : Text = Text::new("ab\nc");
+        t.write_char("x", CharIdx(0)).unwrap();
+        t.set_modifiable(false);
+        assert_eq!(t.write_char("y", CharIdx(0)), Err(TextError::ReadOnly));
+        assert_eq!(t.remove_at(CharIdx(0)), Err(TextError::ReadOnly));
+        assert_eq!(t.replace_lines(LineNo(0), LineNo(0), &lines(&["z"])), Err(TextError::ReadOnly));
+        assert_eq!(t.undo(), Err(TextError::ReadOnly));
+        assert_eq!(t.get_text(), "xab\nc");
+        assert_eq!(TextError::ReadOnly.to_string(), "E21: Cannot make changes, 'modifiable' is off");
+        t.set_modifiable(true);
+        assert_eq!(t.undo(), Ok(Some(CharIdx(0))));
+    }
+
+    #[test]
+    fn test_anchors() {
+        let mut t : Text = Text::new("one\ntwo");
+        let left = t.anchor(CharIdx(4), Gravity::Left);
+        let right = t.anchor(CharIdx(4), Gravity::Right);
+        t.write_char("x", CharIdx(4)).unwrap();
+        assert_eq!(t.anchor_position(left), Some(Position::new(1, 0)));
+        assert_eq!(t.anchor_position(right), Some(Position::new(1, 1)));
+        t.delete_lines(LineNo(0), LineNo(0)).unwrap();
+        assert_eq!((t.anchor_index(left), t.anchor_index(right)), (Some(CharIdx(0)), Some(CharIdx(1))));
+        // Undo inserts the line again before the anchor with left gravity
+        t.undo().unwrap();
+        assert_eq!(t.get_text(), "one\ntwo");
+        assert_eq!((t.anchor_index(left), t.anchor_index(right)), (Some(CharIdx(0)), Some(CharIdx(4))));
+        t.remove_anchor(left);
+        assert_eq!(t.anchor_index(left), None);
+        // Positions past the end are kept at the end
+        let end = t.anchor(CharIdx(100), Gravity::Right);
+        assert_eq!(t.anchor_index(end), Some(CharIdx(7)));
+    }
+
+    #[test]
+    fn test_anchors_match_model() {
+        use crate::anchor::tests::{Model, Rng};
+
+        for seed in 1..=30 {
+            let mut rng = Rng(seed);
+            let mut t = Text::new("abc\ndef\nghi");
+            let mut model = Model::new(t.size());
+            let mut anchors = Vec::new();
+            // Edits of each change as (idx, removed, inserted), like Text keeps
+            let mut undo : Vec<Vec<(usize, usize, usize)>> = Vec::new();
+            let mut redo : Vec<Vec<(usize, usize, usize)>> = Vec::new();
+            let mut current = Vec::new();
+            for step in 0..300 {
+                let size = t.size();
+                match rng.below(10) {
+                    0 => {
+                        let idx = rng.below(size + 1);
+                        let gravity = if rng.below(2) == 0 { Gravity::Left } else { Gravity::Right };
+                        model.add(anchors.len(), idx, gravity);
+                        anchors.push(t.anchor(CharIdx(idx), gravity));
+                    }
+                    1 | 2 => {
+                        t.close_change();
+                        if !current.is_empty() {
+                            undo.push(std::mem::take(&mut current));
+                            redo.clear();
+                        }
+                        if rng.below(3) == 0 {
+                            let Some(group) = redo.pop() else { continue };
+                            t.redo().unwrap();
+                            for (idx, removed, inserted) in &group {
+                                model.edit(*idx, *removed, *inserted);
+                            }
+                            undo.push(group);
+                        }
+                        else {
+                            let Some(group) = undo.pop() else { continue };
+                            t.undo().unwrap();
+                            for (idx, removed, inserted) in group.iter().rev() {
+                                model.edit(*idx, *inserted, *removed);
+                            }
+                            redo.push(group);
+                        }
+                    }
+                    3 => {
+                        t.close_change();
+                        if !current.is_empty() {
+                            undo.push(std::mem::take(&mut current));
+                            redo.clear();
+                        }
+                    }
+                    4 | 5 if size > 0 => {
+                        let idx = rng.below(size);
+                        t.remove_at(CharIdx(idx)).unwrap();
+                        model.edit(idx, 1, 0);
+                        current.push((idx, 1, 0));
+                    }
+                    6 | 7 => {
+                        let idx = rng.below(size + 1);
+                        let removed = rng.below((size - idx).min(4) + 1);
+                        let inserted : String = (0..rng.below(4)).map(|_| if rng.below(3) == 0 { '\n' } else { 'é' }).collect();
+                        let n = inserted.chars().count();
+                        t.replace_range(Range::new(CharIdx(idx), CharIdx(idx + removed)), &inserted).unwrap();
+                        if removed > 0 || n > 0 {
+                            model.edit(idx, removed, n);
+                            current.push((idx, removed, n));
+                        }
+                    }
+                    _ => {
+                        let idx = rng.below(size + 1);
+                        t.write_char(if rng.below(4) == 0 { "\n" } else { "x" }, CharIdx(idx)).unwrap();
+                        model.edit(idx, 0, 1);
+                        current.push((idx, 0, 1));
+                    }
+                }
+                assert_eq!(t.size(), model.len());
+                // The line index kept up to date matches one built from scratch
+                let fresh = Text::new(t.get_text());
+                assert_eq!(t.line_count(), fresh.line_count(), "seed {} step {}", seed, step);
+                for line in 0..t.line_count() {
+                    assert_eq!(t.get_line_length(LineNo(line)), fresh.get_line_length(LineNo(line)));
+                }
+                for (id, anchor) in anchors.iter().enumerate() {
+                    assert_eq!(t.anchor_index(*anchor), model.get(id), "seed {} step {} anchor {}", seed, step, id);
+                }
+            }
+        }
+    }
+}
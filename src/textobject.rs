@@ -0,0 +1,493 @@
+use std::ops::Range;
+
+use crate::pos::{self, CharIdx};
+use crate::text::Text;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Blank,
+    Word,
+    Punct,
+    Newline,
+}
+
+// Class of `c` for word motions. Every non-blank is part of a WORD.
+fn class(c : char, big : bool) -> Class {
+    match c {
+        '\n' => Class::Newline,
+        ' ' | '\t' => Class::Blank,
+        _ if big => Class::Word,
+        c if c.is_alphanumeric() || c == '_' => Class::Word,
+        _ => Class::Punct,
+    }
+}
+
+fn is_blank(c : char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+// Start and end of the line holding `idx`, not counting the newline
+fn line_bounds(chars : &[char], idx : usize) -> (usize, usize) {
+    let start = chars[..idx].iter().rposition(|c| *c == '\n').map_or(0, |i| i + 1);
+    let end = chars[idx..].iter().position(|c| *c == '\n').map_or(chars.len(), |i| idx + i);
+    (start, end)
+}
+
+// iw, aw, iW and aW. Words do not reach past the end of the line.
+fn word_object(chars : &[char], idx : usize, around : bool, count : usize, big : bool) -> Option<Range<usize>> {
+    let (line_start, line_end) = line_bounds(chars, idx);
+    if idx >= line_end {
+        return None;
+    }
+    let run_end = |pos : usize| {
+        let c = class(chars[pos], big);
+        (pos..line_end).find(|i| class(chars[*i], big) != c).unwrap_or(line_end)
+    };
+    let blank_at = |pos : usize| pos < line_end && is_blank(chars[pos]);
+    let c = class(chars[idx], big);
+    let mut start = (line_start..idx).rev().find(|i| class(chars[*i], big) != c).map_or(line_start, |i| i + 1);
+
+    let mut end = idx;
+    if !around {
+        for _ in 0..count {
+            if end < line_end {
+                end = run_end(end);
+            }
+        }
+        return Some(start..end);
+    }
+    if blank_at(idx) {
+        // White space and the words after it
+        end = run_end(idx);
+        for i in 0..count {
+            if i > 0 && blank_at(end) {
+                end = run_end(end);
+            }
+            if end < line_end {
+                end = run_end(end);
+            }
+        }
+        return Some(start..end);
+    }
+    for i in 0..count {
+        if i > 0 && blank_at(end) {
+            end = run_end(end);
+        }
+        if end < line_end {
+            end = run_end(end);
+        }
+    }
+    if blank_at(end) {
+        end = run_end(end);
+    }
+    else {
+        // No white space after the words, so take the white space before
+        while start > line_start && is_blank(chars[start - 1]) {
+            start -= 1;
+        }
+    }
+    Some(start..end)
+}
+
+// Lines as (start, end) char ranges, not counting newlines
+fn lines_of(chars : &[char]) -> Vec<(usize, usize)> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '\n' {
+            lines.push((start, i));
+            start = i + 1;
+        }
+    }
+    lines.push((start, chars.len()));
+    lines
+}
+
+fn blank_line(chars : &[char], line : (usize, usize)) -> bool {
+    chars[line.0..line.1].iter().all(|c| is_blank(*c))
+}
+
+// is and as, within the paragraph holding `idx`. A sentence ends at `.`,
+// `!` or `?`, followed by any closing `)`, `]`, `"` or `'`, and then white
+// space or the end of the paragraph.
+fn sentence_object(chars : &[char], idx : usize, around : bool, count : usize) -> Option<Range<usize>> {
+    let lines = lines_of(chars);
+    let line = lines.iter().position(|l| idx <= l.1)?;
+    if blank_line(chars, lines[line]) {
+        return None;
+    }
+    let first = (0..line).rev().find(|l| blank_line(chars, lines[*l])).map_or(0, |l| l + 1);
+    let last = (line..lines.len()).find(|l| blank_line(chars, lines[*l])).map_or(lines.len() - 1, |l| l - 1);
+    let (par_start, par_end) = (lines[first].0, lines[last].1);
+
+    // Alternating sentences and white space, as (start, end, white)
+    let white = |c : char| is_blank(c) || c == '\n';
+    let mut spans : Vec<(usize, usize, bool)> = Vec::new();
+    let mut pos = par_start;
+    while pos < par_end {
+        let start = pos;
+        if white(chars[pos]) {
+            while pos < par_end && white(chars[pos]) {
+                pos += 1;
+            }
+            spans.push((start, pos, true));
+            continue;
+        }
+        while pos < par_end {
+            let c = chars[pos];
+            pos += 1;
+            if matches!(c, '.' | '!' | '?') {
+                while pos < par_end && matches!(chars[pos], ')' | ']' | '"' | '\'') {
+                    pos += 1;
+                }
+                if pos == par_end || white(chars[pos]) {
+                    break;
+                }
+            }
+        }
+        spans.push((start, pos, false));
+    }
+    let k = spans.iter().position(|s| idx < s.1).unwrap_or(spans.len() - 1);
+    let last_span = |n : usize| spans[(k + n).min(spans.len() - 1)];
+
+    if !around {
+        return Some(spans[k].0..last_span(count - 1).1);
+    }
+    if spans[k].2 {
+        // White space and the sentences after it
+        let end = last_span(2 * count - 1).1;
+        return Some(spans[k].0..end);
+    }
+    let end_span = (k + 2 * count - 1).min(spans.len() - 1);
+    let mut start = spans[k].0;
+    if !spans[end_span].2 && k > 0 {
+        start = spans[k - 1].0;
+    }
+    Some(start..spans[end_span].1)
+}
+
+// ip and ap: runs of blank and non-blank lines. The range covers whole
+// lines, without the newline of the last one.
+fn paragraph_object(chars : &[char], idx : usize, around : bool, count : usize) -> Option<Range<usize>> {
+    let lines = lines_of(chars);
+    let line = lines.iter().position(|l| idx <= l.1)?;
+    let blank = |l : usize| blank_line(chars, lines[l]);
+    let run_end = |l : usize| (l..lines.len()).find(|n| blank(*n) != blank(l)).unwrap_or(lines.len());
+
+    let mut first = (0..line).rev().find(|l| blank(*l) != blank(line)).map_or(0, |l| l + 1);
+    let mut end = line;
+    let runs = if around { 2 * count } else { count };
+    let mut taken = 0;
+    while taken < runs && end < lines.len() {
+        end = run_end(end);
+        taken += 1;
+    }
+    // A paragraph without blank lines after it takes those before it
+    if around && !blank(line) && taken % 2 == 1 {
+        while first > 0 && blank(first - 1) {
+            first -= 1;
+        }
+    }
+    Some(lines[first].0..lines[end - 1].1)
+}
+
+// i", a" and the other quotes, within the line
+fn quote_object(chars : &[char], idx : usize, quote : char, around : bool, count : usize) -> Option<Range<usize>> {
+    let (line_start, line_end) = line_bounds(chars, idx);
+    let escaped = |i : usize| (line_start..i).rev().take_while(|j| chars[*j] == '\\').count() % 2 == 1;
+    let quotes : Vec<usize> = (line_start..line_end)
+        .filter(|i| chars[*i] == quote && !escaped(*i))
+        .collect();
+
+    let (open, close) = match quotes.iter().position(|q| *q == idx) {
+        // On a quote: counting from the start of the line tells whether it
+        // opens or closes
+        Some(i) if i % 2 == 0 => (idx, *quotes.get(i + 1)?),
+        Some(i) => (quotes[i - 1], idx),
+        None => {
+            let pair = quotes.chunks(2).find(|p| p.len() == 2 && p[0] < idx && idx < p[1]);
+            match pair {
+                Some(p) => (p[0], p[1]),
+                None => {
+                    // The first quoted string after the cursor
+                    let i = quotes.iter().position(|q| *q > idx)?;
+                    (quotes[i], *quotes.get(i + 1)?)
+                }
+            }
+        }
+    };
+
+    if !around {
+        return Some(if count > 1 { open..close + 1 } else { open + 1..close });
+    }
+    let mut start = open;
+    let mut end = close + 1;
+    if end < line_end && is_blank(chars[end]) {
+        while end < line_end && is_blank(chars[end]) {
+            end += 1;
+        }
+    }
+    else {
+        while start > line_start && is_blank(chars[start - 1]) {
+            start -= 1;
+        }
+    }
+    Some(start..end)
+}
+
+// Index of the `open` bracket not closed before `from`, searching back
+// from `from` itself
+fn unmatched_open(chars : &[char], from : usize, open : char, close : char) -> Option<usize> {
+    let mut depth = 0;
+    for i in (0..=from).rev() {
+        if chars[i] == close {
+            depth += 1;
+        }
+        else if chars[i] == open {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+fn matching_close(chars : &[char], open_idx : usize, open : char, close : char) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in chars.iter().enumerate().skip(open_idx + 1) {
+        if *c == open {
+            depth += 1;
+        }
+        else if *c == close {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// i( and a( and the other brackets. A count selects outer blocks.
+fn bracket_object(chars : &[char], idx : usize, open : char, close : char, around : bool, count : usize) -> Option<Range<usize>> {
+    // On a closing bracket its own block counts
+    let from = if chars[idx] == close { idx.checked_sub(1)? } else { idx };
+    let mut o = unmatched_open(chars, from, open, close)?;
+    for _ in 1..count {
+        o = unmatched_open(chars, o.checked_sub(1)?, open, close)?;
+    }
+    let c = matching_close(chars, o, open, close)?;
+
+    if around {
+        return Some(o..c + 1);
+    }
+    // Inside a block spread over lines, keep the lines of the brackets
+    let mut start = o + 1;
+    let mut end = c;
+    if chars.get(start) == Some(&'\n') {
+        start += 1;
+        if let Some(nl) = chars[start.min(c)..c].iter().rposition(|ch| *ch == '\n')
+            && chars[start + nl + 1..c].iter().all(|ch| is_blank(*ch)) {
+            end = start + nl + 1;
+        }
+    }
+    Some(start.min(end)..end)
+}
+
+struct Tag {
+    start : usize,
+    end : usize,
+    name : String,
+    closing : bool,
+}
+
+// Every <tag> and </tag> in `chars`. Self-closing tags, comments and
+// declarations are left out.
+fn tags(chars : &[char]) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '<' {
+            i += 1;
+            continue;
+        }
+        let closing = chars.get(i + 1) == Some(&'/');
+        let name_start = i + 1 + closing as usize;
+        let name : String = chars[name_start.min(chars.len())..].iter()
+            .take_while(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+            .collect();
+        let gt = match chars[i..].iter().position(|c| *c == '>') {
+            Some(g) => i + g,
+            None => break,
+        };
+        if !name.is_empty() && chars[gt - 1] != '/' {
+            tags.push(Tag { start : i, end : gt + 1, name, closing });
+        }
+        i = gt + 1;
+    }
+    tags
+}
+
+// it and at. A count selects outer elements.
+fn tag_object(chars : &[char], idx : usize, around : bool, count : usize) -> Option<Range<usize>> {
+    let mut stack : Vec<&Tag> = Vec::new();
+    let mut pairs : Vec<(&Tag, &Tag)> = Vec::new();
+    let all = tags(chars);
+    for tag in &all {
+        if !tag.closing {
+            stack.push(tag);
+        }
+        else if let Some(pos) = stack.iter().rposition(|t| t.name == tag.name) {
+            pairs.push((stack[pos], tag));
+            stack.truncate(pos);
+        }
+    }
+    let mut around_idx : Vec<&(&Tag, &Tag)> = pairs.iter()
+        .filter(|(o, c)| o.start <= idx && idx < c.end)
+        .collect();
+    around_idx.sort_by_key(|(o, c)| c.end - o.start);
+    let (open, close) = around_idx.get(count - 1)?;
+    if around {
+        Some(open.start..close.end)
+    }
+    else {
+        Some(open.end..close.start)
+    }
+}
+
+impl Text {
+    /// Chars covered by the text object typed as `obj` after `i`, or after
+    /// `a` when `around` is set, at char index `idx`. A count takes in more
+    /// words, sentences and paragraphs, or outer blocks. Paragraphs cover
+    /// whole lines.
+    pub fn text_object(&self, idx : CharIdx, obj : char, around : bool, count : usize) -> Option<pos::Range> {
+        let chars : Vec<char> = self.get_text().chars().collect();
+        let idx = idx.0;
+        if idx >= chars.len() && obj != 'p' {
+            return None;
+        }
+        let count = count.max(1);
+        let range = match obj {
+            'w' | 'W' => word_object(&chars, idx, around, count, obj == 'W'),
+            's' => sentence_object(&chars, idx, around, count),
+            'p' => paragraph_object(&chars, idx, around, count),
+            '"' | '\'' | '`' => quote_object(&chars, idx, obj, around, count),
+            '(' | ')' | 'b' => bracket_object(&chars, idx, '(', ')', around, count),
+            '[' | ']' => bracket_object(&chars, idx, '[', ']', around, count),
+            '{' | '}' | 'B' => bracket_object(&chars, idx, '{', '}', around, count),
+            '<' | '>' => bracket_object(&chars, idx, '<', '>', around, count),
+            't' => tag_object(&chars, idx, around, count),
+            _ => None,
+        };
+        range.map(|r| pos::Range::new(CharIdx(r.start), CharIdx(r.end)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Text covered by `obj` with the cursor on the first `^` in `s`, which
+    // is removed first
+    fn object(s : &str, obj : char, around : bool, count : usize) -> Option<String> {
+        let idx = s.chars().position(|c| c == '^').unwrap();
+        let text : String = s.chars().filter(|c| *c != '^').collect();
+        let t = Text::new(&text);
+        let r = t.text_object(CharIdx(idx), obj, around, count)?;
+        Some(t.slice(r))
+    }
+
+    fn some(s : &str) -> Option<String> {
+        Some(String::from(s))
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(object("foo b^ar baz", 'w', false, 1), some("bar"));
+        assert_eq!(object("foo b^ar baz", 'w', true, 1), some("bar "));
+        assert_eq!(object("foo b^ar", 'w', true, 1), some(" bar"));
+        assert_eq!(object("foo b^ar baz", 'w', false, 3), some("bar baz"));
+        assert_eq!(object("foo b^ar baz qux", 'w', true, 2), some("bar baz "));
+        assert_eq!(object("fo^o  bar", 'w', true, 1), some("foo  "));
+        assert_eq!(object("foo ^ bar", 'w', true, 1), some("  bar"));
+        assert_eq!(object("a.b^ar(x)", 'w', false, 1), some("bar"));
+        assert_eq!(object("a.b^ar(x) y", 'W', false, 1), some("a.bar(x)"));
+        assert_eq!(object("a.b^ar(x) y", 'W', true, 1), some("a.bar(x) "));
+    }
+
+    #[test]
+    fn test_words_multibyte() {
+        assert_eq!(object("så bl^åbær er", 'w', false, 1), some("blåbær"));
+        assert_eq!(object("så bl^åbær er", 'w', true, 1), some("blåbær "));
+        assert_eq!(object("æ ^ø å", 'W', false, 1), some("ø"));
+    }
+
+    #[test]
+    fn test_sentences() {
+        let s = "One here. Tw^o there! Three.";
+        assert_eq!(object(s, 's', false, 1), some("Two there!"));
+        assert_eq!(object(s, 's', true, 1), some("Two there! "));
+        assert_eq!(object("One. T^wo.", 's', true, 1), some(" Two."));
+        assert_eq!(object("One. Two.\nThr^ee (really.) Four", 's', false, 1), some("Three (really.)"));
+        assert_eq!(object("A. B^.\n\nC.", 's', false, 1), some("B."));
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let s = "a\nb\n\n\nc^\nd\n\ne";
+        assert_eq!(object(s, 'p', false, 1), some("c\nd"));
+        assert_eq!(object(s, 'p', true, 1), some("c\nd\n"));
+        assert_eq!(object("a\n\nb^", 'p', true, 1), some("\nb"));
+        assert_eq!(object("a^\n\nb\n", 'p', false, 3), some("a\n\nb"));
+        assert_eq!(object("a\n^\n\nb", 'p', true, 1), some("\n\nb"));
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(object("x = \"a^b c\";", '"', false, 1), some("ab c"));
+        assert_eq!(object("x = \"a^b c\";", '"', true, 1), some(" \"ab c\""));
+        assert_eq!(object("f(\"a^\", \"b\")", '"', true, 1), some("\"a\""));
+        assert_eq!(object("x ^= 'one' 'two'", '\'', false, 1), some("one"));
+        assert_eq!(object("'a\\'^b'", '\'', false, 1), some("a\\'b"));
+        assert_eq!(object("s = `c^md`", '`', false, 2), some("`cmd`"));
+        assert_eq!(object("\"ab\" \"c^d\"", '"', false, 1), some("cd"));
+        assert_eq!(object("\"ab\" ^\"cd\"", '"', false, 1), some("cd"));
+        assert_eq!(object("no q^uotes", '"', false, 1), None);
+        assert_eq!(object("\"bl^åbær\"", '"', false, 1), some("blåbær"));
+    }
+
+    #[test]
+    fn test_brackets_nesting() {
+        let s = "f(a, g(b^, c), d)";
+        assert_eq!(object(s, '(', false, 1), some("b, c"));
+        assert_eq!(object(s, ')', true, 1), some("(b, c)"));
+        assert_eq!(object(s, 'b', false, 2), some("a, g(b, c), d"));
+        assert_eq!(object(s, '(', false, 3), None);
+        assert_eq!(object("f(a, g(b, c^), d)", '(', true, 1), some("(b, c)"));
+        assert_eq!(object("f^(a)", '(', false, 1), some("a"));
+        assert_eq!(object("x[1][^2]", '[', true, 1), some("[2]"));
+        assert_eq!(object("Vec<Opt^ion<u8>>", '<', false, 1), some("Option<u8>"));
+        assert_eq!(object("f(^)", '(', false, 1), some(""));
+        assert_eq!(object("(ø, [^æ, å])", '[', false, 1), some("æ, å"));
+    }
+
+    #[test]
+    fn test_block_over_lines() {
+        let s = "if x {\n    a^;\n    b;\n}";
+        assert_eq!(object(s, '{', false, 1), some("    a;\n    b;\n"));
+        assert_eq!(object(s, 'B', true, 1), some("{\n    a;\n    b;\n}"));
+    }
+
+    #[test]
+    fn test_tags() {
+        let s = "<div><p>one <b>tw^o</b></p><br/></div>";
+        assert_eq!(object(s, 't', false, 1), some("two"));
+        assert_eq!(object(s, 't', true, 1), some("<b>two</b>"));
+        assert_eq!(object(s, 't', false, 2), some("one <b>two</b>"));
+        assert_eq!(object(s, 't', true, 3), some(s.replace('^', "").as_str()));
+        assert_eq!(object("<a href=\"x\">l^ink</a>", 't', false, 1), some("link"));
+        assert_eq!(object("<^p>x</p>", 't', false, 1), some("x"));
+        assert_eq!(object("<p>bl^åbær</p>", 't', true, 1), some("<p>blåbær</p>"));
+    }
+}
@@ -0,0 +1,122 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Screen cells taken by the grapheme cluster `g`: two for wide East Asian
+/// chars and emoji, and for control chars shown as `^X`, none for
+/// zero-width chars on their own, one for the rest. Combining marks take
+/// no cells of their own.
+pub fn grapheme_width(g : &str) -> usize {
+    match g.chars().next() {
+        Some(c) if c.is_ascii_control() => 2,
+        _ => g.width(),
+    }
+}
+
+/// Cell after grapheme `g` shown from cell `start`, with tabs going to the
+/// next multiple of `ts`, or shown as `^I` when `ts` is 0.
+pub fn next_cell(g : &str, start : usize, ts : usize) -> usize {
+    match g {
+        "\t" if ts > 0 => (start / ts + 1) * ts,
+        g => start + grapheme_width(g),
+    }
+}
+
+// Grapheme clusters of `line` with the char column each starts at
+fn graphemes(line : &str) -> impl Iterator<Item = (usize, &str)> {
+    line.graphemes(true).scan(0, |col, g| {
+        let start = *col;
+        *col += g.chars().count();
+        Some((start, g))
+    })
+}
+
+/// Screen cell where char `col` of `line` starts.
+pub fn cell_of_col(line : &str, col : usize, ts : usize) -> usize {
+    graphemes(line).take_while(|(start, _)| *start < col).fold(0, |cell, (_, g)| next_cell(g, cell, ts))
+}
+
+/// Char column of `line` where the grapheme shown at screen `cell` starts.
+/// Cells past the end of the line give its length.
+pub fn col_at_cell(line : &str, cell : usize, ts : usize) -> usize {
+    let mut start = 0;
+    for (col, g) in graphemes(line) {
+        let end = next_cell(g, start, ts);
+        if cell < end {
+            return col;
+        }
+        start = end;
+    }
+    line.chars().count()
+}
+
+/// Char column of the grapheme after the one at `col`, or the length of
+/// `line` at its last one.
+pub fn next_col(line : &str, col : usize) -> usize {
+    graphemes(line).map(|(start, _)| start).find(|start| *start > col).unwrap_or(line.chars().count())
+}
+
+/// Char column of the grapheme before `col`, or 0 at the first one.
+pub fn prev_col(line : &str, col : usize) -> usize {
+    graphemes(line).map(|(start, _)| start).take_while(|start| *start < col).last().unwrap_or(0)
+}
+
+/// Char column where the grapheme holding char `col` starts.
+pub fn snap_col(line : &str, col : usize) -> usize {
+    graphemes(line).map(|(start, _)| start).take_while(|start| *start <= col).last().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grapheme_width() {
+        assert_eq!(grapheme_width("a"), 1);
+        assert_eq!(grapheme_width("日"), 2);
+        assert_eq!(grapheme_width("e\u{301}"), 1);
+        assert_eq!(grapheme_width("\u{200b}"), 0);
+        assert_eq!(grapheme_width("\x1b"), 2);
+        // Family emoji joined with ZWJ
+        assert_eq!(grapheme_width("👨\u{200d}👩\u{200d}👧"), 2);
+    }
+
+    #[test]
+    fn test_col_at_cell() {
+        assert_eq!(col_at_cell("abc", 1, 8), 1);
+        assert_eq!(col_at_cell("abc", 10, 8), 3);
+        // The tab covers cells 1 to 7
+        assert_eq!(col_at_cell("a\tb", 5, 8), 1);
+        assert_eq!(col_at_cell("a\tb", 8, 8), 2);
+        // Wide chars take two cells, combining marks none
+        assert_eq!(col_at_cell("日本x", 3, 8), 1);
+        assert_eq!(col_at_cell("日本x", 4, 8), 2);
+        assert_eq!(col_at_cell("e\u{301}x", 1, 8), 2);
+    }
+
+    #[test]
+    fn test_cell_of_col() {
+        assert_eq!(cell_of_col("a\tb", 2, 8), 8);
+        assert_eq!(cell_of_col("a\tb", 2, 4), 4);
+        assert_eq!(cell_of_col("a\tb", 2, 0), 3);
+        assert_eq!(cell_of_col("日本x", 2, 8), 4);
+        assert_eq!(cell_of_col("e\u{301}x", 2, 8), 1);
+        for col in 0..=4 {
+            assert_eq!(col_at_cell("a\t日b", cell_of_col("a\t日b", col, 8), 8), col);
+        }
+    }
+
+    #[test]
+    fn test_grapheme_cols() {
+        let line = "ae\u{301}👨\u{200d}👩x";
+        assert_eq!(next_col(line, 0), 1);
+        assert_eq!(next_col(line, 1), 3);
+        assert_eq!(next_col(line, 3), 6);
+        assert_eq!(next_col(line, 6), 7);
+        assert_eq!(prev_col(line, 6), 3);
+        assert_eq!(prev_col(line, 3), 1);
+        assert_eq!(prev_col(line, 0), 0);
+        assert_eq!(snap_col(line, 2), 1);
+        assert_eq!(snap_col(line, 5), 3);
+        assert_eq!(snap_col(line, 6), 6);
+    }
+}
@@ -0,0 +1,136 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::indent;
+use crate::width::{cell_of_col, col_at_cell, next_cell, prev_col};
+
+/// How long lines are split into screen rows with 'wrap' set.
+pub struct Wrap<'a> {
+    pub width : usize,          // Cells in a screen row
+    pub ts : usize,             // Cells from one tab stop to the next
+    pub linebreak : bool,       // Break after a char in `breakat` instead of anywhere
+    pub breakat : &'a str,
+    pub breakindent : bool,     // Indent rows after the first like the line
+    pub showbreak : &'a str,    // Shown at the start of rows after the first
+}
+
+/// One screen row of a line: chars `start` to `end`, shown after `lead`
+/// cells of indent and 'showbreak'.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Row {
+    pub start : usize,
+    pub end : usize,
+    pub lead : usize,
+}
+
+impl Wrap<'_> {
+    /// Cells before the text of the rows after the first: the indent of
+    /// `line` with 'breakindent' and 'showbreak', at most half a row.
+    fn lead(&self, line : &str) -> usize {
+        let indent = match self.breakindent {
+            true => indent::indent_width(line, self.ts.max(1)),
+            false => 0,
+        };
+        (indent + self.showbreak.width()).min(self.width / 2)
+    }
+
+    /// The screen rows `line` takes. An empty line takes one.
+    pub fn rows(&self, line : &str) -> Vec<Row> {
+        let lead = self.lead(line);
+        let mut rows = Vec::new();
+        let mut row = Row { start : 0, end : 0, lead : 0 };
+        let mut row_cell = 0;       // Cell of the line where the row starts
+        let mut brk = None;         // Where the row can end with 'linebreak'
+        let (mut col, mut cell) = (0, 0);
+        for g in line.graphemes(true) {
+            let next = next_cell(g, cell, self.ts);
+            if col > row.start && next - row_cell > self.width - row.lead {
+                let end = match brk {
+                    Some(end) if self.linebreak => end,
+                    _ => col,
+                };
+                rows.push(Row { end, ..row });
+                row = Row { start : end, end, lead };
+                row_cell = cell_of_col(line, end, self.ts);
+                brk = None;
+            }
+            col += g.chars().count();
+            cell = next;
+            if self.breakat.contains(g) {
+                brk = Some(col);
+            }
+        }
+        rows.push(Row { end : col, ..row });
+        rows
+    }
+
+    /// Index in `rows` of the row holding char `col`. The end of the line
+    /// is on the last row.
+    pub fn row_of_col(rows : &[Row], col : usize) -> usize {
+        rows.iter().position(|r| col < r.end).unwrap_or(rows.len() - 1)
+    }
+
+    /// Cell in `row` where char `col` of `line` is shown.
+    pub fn cell_in_row(&self, line : &str, row : Row, col : usize) -> usize {
+        row.lead + cell_of_col(line, col, self.ts) - cell_of_col(line, row.start, self.ts)
+    }
+
+    /// Char column of `line` shown at `cell` of `row`, staying on the row.
+    /// With `past_end` it may be the end of the row, as at the end of the
+    /// line in insert mode.
+    pub fn col_in_row(&self, line : &str, row : Row, cell : usize, past_end : bool) -> usize {
+        let start = cell_of_col(line, row.start, self.ts);
+        let col = col_at_cell(line, start + cell.saturating_sub(row.lead), self.ts);
+        match past_end {
+            true => col.min(row.end),
+            false => col.min(prev_col(line, row.end)).max(row.start),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap(width : usize) -> Wrap<'static> {
+        Wrap { width, ts : 8, linebreak : false, breakat : " \t!@*-+;:,./?", breakindent : false, showbreak : "" }
+    }
+
+    fn texts<'a>(line : &'a str, rows : &[Row]) -> Vec<&'a str> {
+        let starts : Vec<usize> = line.char_indices().map(|(i, _)| i).chain([line.len()]).collect();
+        rows.iter().map(|r| &line[starts[r.start]..starts[r.end]]).collect()
+    }
+
+    #[test]
+    fn test_rows() {
+        let w = wrap(5);
+        assert_eq!(w.rows(""), vec![Row { start : 0, end : 0, lead : 0 }]);
+        assert_eq!(texts("abcdefghijk", &w.rows("abcdefghijk")), vec!["abcde", "fghij", "k"]);
+        assert_eq!(texts("abcde", &w.rows("abcde")), vec!["abcde"]);
+        // A wide char that does not fit goes to the next row
+        assert_eq!(texts("abcd日x", &w.rows("abcd日x")), vec!["abcd", "日x"]);
+    }
+
+    #[test]
+    fn test_linebreak() {
+        let w = Wrap { linebreak : true, ..wrap(10) };
+        let line = "one two three fours";
+        assert_eq!(texts(line, &w.rows(line)), vec!["one two ", "three ", "fours"]);
+        // A word longer than the row is still split
+        let line = "abcdefghijklmn";
+        assert_eq!(texts(line, &w.rows(line)), vec!["abcdefghij", "klmn"]);
+    }
+
+    #[test]
+    fn test_lead() {
+        let w = Wrap { breakindent : true, showbreak : "> ", ..wrap(10) };
+        let rows = w.rows("  abcdefghijkl");
+        assert_eq!(texts("  abcdefghijkl", &rows), vec!["  abcdefgh", "ijkl"]);
+        assert_eq!(rows[1].lead, 4);
+        assert_eq!(w.cell_in_row("  abcdefghijkl", rows[1], 11), 5);
+        assert_eq!(w.col_in_row("  abcdefghijkl", rows[1], 5, false), 11);
+        assert_eq!(w.col_in_row("  abcdefghijkl", rows[1], 0, false), 10);
+        assert_eq!(w.col_in_row("  abcdefghijkl", rows[0], 20, false), 9);
+        assert_eq!(Wrap::row_of_col(&rows, 14), 1);
+    }
+}